@@ -0,0 +1,73 @@
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Produces an asciicast v2 recording (https://docs.asciinema.org/manual/asciicast/v2/)
+/// of the highlighted output, one "o" event per output line.
+#[derive(Debug)]
+pub struct AsciicastWriter {
+    width: usize,
+    height: usize,
+    start: Instant,
+}
+
+fn escape_json(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len() + 2);
+    ret.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\r' => ret.push_str("\\r"),
+            '\t' => ret.push_str("\\t"),
+            c if c < ' ' => ret.push_str(&format!("\\u{:04x}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+#[test]
+fn test_escape_json() {
+    assert_eq!("\"a\\\"b\\\\c\\r\\n\\u001b[0m\"",
+               escape_json("a\"b\\c\r\n\x1b[0m"));
+}
+
+impl AsciicastWriter {
+    pub fn new(width: usize, height: usize) -> AsciicastWriter {
+        AsciicastWriter {
+            width: width,
+            height: height,
+            start: Instant::now(),
+        }
+    }
+
+    /// The header line, which must come first.
+    pub fn header(&self) -> String {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        format!("{{\"version\": 2, \"width\": {}, \"height\": {}, \"timestamp\": {}}}",
+                self.width,
+                self.height,
+                timestamp)
+    }
+
+    /// An output event for an (ANSI-colored) line, timed relative to the
+    /// creation of the writer.
+    pub fn event(&self, line: &str) -> String {
+        let elapsed = self.start.elapsed();
+        let time = elapsed.as_secs() as f64 + (elapsed.subsec_nanos() as f64) / 1e9;
+        format!("[{:.6}, \"o\", {}]", time, escape_json(&format!("{}\r\n", line)))
+    }
+}
+
+#[test]
+fn test_asciicast_writer() {
+    let w = AsciicastWriter::new(120, 24);
+    assert!(w.header().starts_with("{\"version\": 2, \"width\": 120, \"height\": 24, "));
+    let e = w.event("\x1b[31mabc\x1b[0m");
+    assert!(e.starts_with("[0."));
+    assert!(e.ends_with(", \"o\", \"\\u001b[31mabc\\u001b[0m\\r\\n\"]"));
+}
@@ -0,0 +1,144 @@
+use std::io;
+use std::io::{BufRead, BufReader, Read};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Child, ExitStatus, Stdio};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender};
+use std::thread;
+use std::time::Duration;
+use libc;
+
+use rule::Stream;
+use signal;
+
+const POLL_INTERVAL_MS: u64 = 100;
+
+/// How many lines may be read ahead of the output.  Past that, the command
+/// blocks on its full pipes, like in a shell pipeline.
+const READ_AHEAD_LINES: usize = 256;
+
+/// Signals that are passed to the child.  Those from the terminal, e.g. ^C,
+/// already go to the child too, so only the ones sent with kill() are.
+/// SIGHUP isn't, as it reloads the rules.
+const FORWARDED_SIGNALS: [libc::c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGQUIT];
+
+/// Runs a command, and reads the lines of its stdout and stderr.
+#[derive(Debug)]
+pub struct Command {
+    child: Child,
+    rx: Receiver<(Stream, io::Result<String>)>,
+    closed: bool,
+}
+
+fn read_lines<R: Read + Send + 'static>(reader: R,
+                                        stream: Stream,
+                                        tx: SyncSender<(Stream, io::Result<String>)>) {
+    thread::spawn(move || for line in BufReader::new(reader).lines() {
+        let stop = match line {
+            Err(ref e) => e.kind() != io::ErrorKind::InvalidData,
+            _ => false,
+        };
+        if tx.send((stream, line)).is_err() || stop {
+            return;
+        }
+    });
+}
+
+/// The exit code for a status, with the shell convention for signals.
+pub fn exit_code(status: &ExitStatus) -> i32 {
+    match status.code() {
+        Some(code) => code,
+        None => 128 + status.signal().unwrap_or(0),
+    }
+}
+
+impl Command {
+    /// Start `args[0]` with the rest of the arguments.  The standard input is
+    /// inherited.
+    pub fn spawn(args: &[String]) -> io::Result<Command> {
+        let mut child = ::std::process::Command::new(&args[0]).args(&args[1..])
+            .stdin(Stdio::inherit())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        for sig in FORWARDED_SIGNALS.iter() {
+            signal::catch(*sig);
+        }
+
+        let (tx, rx) = sync_channel(READ_AHEAD_LINES);
+        read_lines(child.stdout.take().unwrap(), Stream::Stdout, tx.clone());
+        read_lines(child.stderr.take().unwrap(), Stream::Stderr, tx);
+        Ok(Command {
+            child: child,
+            rx: rx,
+            closed: false,
+        })
+    }
+
+    fn forward_signals(&self) {
+        for sig in FORWARDED_SIGNALS.iter() {
+            if let Some(true) = signal::take_with_origin(*sig) {
+                debug!("Forwarding signal {}", sig);
+                unsafe {
+                    libc::kill(self.child.id() as libc::pid_t, *sig);
+                }
+            }
+        }
+    }
+
+    /// The next line from either stream, or `None` when both are closed.
+    pub fn next_line(&mut self) -> Option<(Stream, io::Result<String>)> {
+        self.next_line_or_idle(|| ())
+    }
+
+    /// Same as `next_line()`, but calls `idle` every so often while waiting,
+    /// e.g. to reload the rules.
+    pub fn next_line_or_idle<F: FnMut()>(&mut self,
+                                         mut idle: F)
+                                         -> Option<(Stream, io::Result<String>)> {
+        while !self.closed {
+            self.forward_signals();
+            match self.rx.recv_timeout(Duration::from_millis(POLL_INTERVAL_MS)) {
+                Ok(line) => return Some(line),
+                Err(RecvTimeoutError::Timeout) => idle(),
+                Err(RecvTimeoutError::Disconnected) => self.closed = true,
+            }
+        }
+        None
+    }
+
+    /// Wait for the command to finish, still forwarding signals.
+    pub fn wait(mut self) -> io::Result<ExitStatus> {
+        loop {
+            if let Some(status) = self.child.try_wait()? {
+                return Ok(status);
+            }
+            self.forward_signals();
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+        }
+    }
+
+    /// Stop the command, e.g. when the output is closed.
+    pub fn terminate(mut self) -> io::Result<ExitStatus> {
+        unsafe {
+            libc::kill(self.child.id() as libc::pid_t, libc::SIGTERM);
+        }
+        self.child.wait()
+    }
+}
+
+#[test]
+fn test_command() {
+    let mut c = Command::spawn(&["sh".to_string(),
+                                 "-c".to_string(),
+                                 "echo out; echo err >&2; exit 3".to_string()])
+        .unwrap();
+    let mut lines = vec![];
+    while let Some((stream, line)) = c.next_line() {
+        lines.push((stream, line.unwrap()));
+    }
+    lines.sort_by_key(|&(s, _)| s == Stream::Stderr);
+    assert_eq!(vec![(Stream::Stdout, "out".to_string()), (Stream::Stderr, "err".to_string())],
+               lines);
+    assert_eq!(3, exit_code(&c.wait().unwrap()));
+}
@@ -0,0 +1,365 @@
+use toml::Value;
+
+use super::*;
+use rule::*;
+use rule_parser::*;
+use rule_test::*;
+use rule_format::Format;
+
+/// The first lines of rule files, which make them runnable as scripts.
+const TOML_HEADER: &'static str = "///bin/true; exec hl -r \"$0\" \"${@}\"";
+const LEGACY_HEADER: &'static str = "///bin/true; exec hl -c \"$0\" \"${@}\"";
+
+/// Read the rules and the tests of a file.
+pub fn read_file(parser: &RuleParser,
+                 filename: &str,
+                 format: Format)
+                 -> Result<(Vec<Rule>, Vec<RuleTest>), RuleError> {
+    let mut rules = vec![];
+    let tests = parser.parse_file_with_tests(filename, format, &mut rules)?;
+    Ok((rules, tests))
+}
+
+fn string(s: &str) -> Value {
+    Value::String(s.to_string())
+}
+
+fn push_line_keys(ret: &mut Vec<(&'static str, Value)>,
+                  keys: [&'static str; 3],
+                  line: Option<&DecorativeLine>) {
+    let line = match line {
+        Some(l) => l,
+        None => return,
+    };
+    // A line with neither a mark nor colors still needs a key to exist.
+    if line.spec().len() > 0 || line.colors().is_none() {
+        ret.push((keys[0], string(line.spec())));
+    }
+    if let Some(c) = line.colors() {
+        ret.push((keys[1], string(&c.spec())));
+    }
+    if let Some(a) = line.align_spec() {
+        ret.push((keys[2], string(a.name())));
+    }
+}
+
+/// The keys of a rule in the TOML names, in the order they're written.
+fn rule_keys(rule: &Rule) -> Vec<(&'static str, Value)> {
+    let mut ret = vec![];
+    if let Some(name) = rule.name() {
+        ret.push(("name", string(name)));
+    }
+    if let Some(description) = rule.description() {
+        ret.push(("description", string(description)));
+    }
+    if let Some(tag) = rule.tag() {
+        ret.push(("tag", string(tag)));
+    }
+    ret.push(("pattern", string(rule.pattern())));
+    if let Some(engine) = rule.engine() {
+        ret.push(("engine", string(engine.name())));
+    }
+    let options = rule.options();
+    for &(key, on) in &[("ignore_case", options.ignore_case),
+                        ("whole_word", options.whole_word),
+                        ("literal", options.literal)] {
+        if on {
+            ret.push((key, Value::Boolean(true)));
+        }
+    }
+    if let Some(unicode) = options.unicode {
+        ret.push(("unicode", Value::Boolean(unicode)));
+    }
+    if let Some(when) = rule.when() {
+        ret.push(("when", string(when)));
+    }
+    for &(key, ref patterns) in &[("when_all", rule.when_all()),
+                                  ("when_any", rule.when_any()),
+                                  ("unless", rule.unless())] {
+        if patterns.len() > 0 {
+            ret.push((key, Value::Array(patterns.iter().map(|p| string(p)).collect())));
+        }
+    }
+    if rule.states().len() > 0 {
+        ret.push(("states", Value::Array(rule.states().iter().map(|s| string(s)).collect())));
+    }
+    if let Some(state) = rule.next_state() {
+        ret.push(("next_state", string(state)));
+    }
+    if let Some(stream) = rule.stream() {
+        ret.push(("stream", string(stream.name())));
+    }
+    if let Some(c) = rule.match_colors() {
+        ret.push(("color", string(&c.spec())));
+    }
+    if let Some(c) = rule.line_colors() {
+        ret.push(("line_color", string(&c.spec())));
+    }
+    push_line_keys(&mut ret,
+                   ["pre_line", "pre_line_color", "pre_line_align"],
+                   rule.pre_line());
+    push_line_keys(&mut ret,
+                   ["post_line", "post_line_color", "post_line_align"],
+                   rule.post_line());
+    if rule.stop() {
+        ret.push(("stop", Value::Boolean(true)));
+    }
+    ret
+}
+
+/// Quote a string for TOML.  Literal strings are preferred, so that patterns
+/// don't need their backslashes doubled.
+fn toml_string(s: &str) -> String {
+    let plain = !s.chars().any(|c| c.is_control() && c != '\t');
+    if plain && !s.contains('\'') {
+        return format!("'{}'", s);
+    }
+    if plain && !s.contains("'''") && !s.starts_with('\'') && !s.ends_with('\'') {
+        return format!("'''{}'''", s);
+    }
+    let mut ret = "\"".to_string();
+    for ch in s.chars() {
+        match ch {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\r' => ret.push_str("\\r"),
+            '\t' => ret.push_str("\\t"),
+            c if c.is_control() => ret.push_str(&format!("\\u{:04X}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+#[test]
+fn test_toml_string() {
+    assert_eq!(r"'\bfoo\b'", toml_string(r"\bfoo\b"));
+    assert_eq!(r"'''it's'''", toml_string("it's"));
+    assert_eq!(r#""\\'a\\'""#, toml_string(r"\'a\'"));
+    assert_eq!(r#""a\n\"b\"""#, toml_string("a\n\"b\""));
+}
+
+fn toml_value(value: &Value) -> String {
+    match *value {
+        Value::String(ref s) => toml_string(s),
+        Value::Array(ref a) => {
+            format!("[{}]", a.iter().map(toml_value).collect::<Vec<String>>().join(", "))
+        }
+        ref v => v.to_string(),
+    }
+}
+
+/// Write the input of a test as a multi-line string when possible.
+fn toml_input(input: &[String]) -> String {
+    let multi_line = input.iter()
+        .all(|l| !l.contains("'''") && !l.chars().any(|c| c.is_control() && c != '\t'));
+    if !multi_line {
+        return toml_value(&Value::Array(input.iter().map(|l| string(l)).collect()));
+    }
+    let mut ret = "'''\n".to_string();
+    for line in input {
+        ret.push_str(line);
+        ret.push('\n');
+    }
+    ret.push_str("'''");
+    ret
+}
+
+/// Write rules and tests as a TOML rule file.
+pub fn to_toml(rules: &[Rule], tests: &[RuleTest]) -> String {
+    let mut ret = TOML_HEADER.to_string();
+    ret.push('\n');
+    for rule in rules {
+        ret.push_str("\n[[rule]]\n");
+        for (key, value) in rule_keys(rule) {
+            ret.push_str(&format!("{} = {}\n", key, toml_value(&value)));
+        }
+    }
+    for test in tests {
+        ret.push_str("\n[[test]]\n");
+        if let Some(ref name) = test.name {
+            ret.push_str(&format!("name = {}\n", toml_string(name)));
+        }
+        ret.push_str(&format!("input = {}\n", toml_input(&test.input)));
+        for e in &test.expect {
+            ret.push_str("\n[[test.expect]]\n");
+            if let Some(ref rules) = e.rules {
+                let rules = Value::Array(rules.iter().map(|r| string(r)).collect());
+                ret.push_str(&format!("rules = {}\n", toml_value(&rules)));
+            }
+            if let Some(ref state) = e.state {
+                ret.push_str(&format!("state = {}\n", toml_string(state)));
+            }
+            if let Some(ref markup) = e.markup {
+                ret.push_str(&format!("markup = {}\n", toml_string(markup)));
+            }
+        }
+    }
+    ret
+}
+
+/// A state in the legacy syntax, where the initial state is "INIT".
+fn legacy_state(rule: &Rule, state: &str) -> Result<String, RuleError> {
+    if state == "INIT" || state.contains(',') || state.trim() != state {
+        return Err(RuleError::new(&format!("Rule '{}': state '{}' can't be written in the \
+                                            legacy format.",
+                                           rule.label(),
+                                           state)));
+    }
+    Ok(if state.len() == 0 { "INIT".to_string() } else { state.to_string() })
+}
+
+/// Write rules as a legacy rule file.  Fails if a value can't be written,
+/// e.g. because it spans lines.
+pub fn to_legacy(rules: &[Rule]) -> Result<String, RuleError> {
+    let mut ret = LEGACY_HEADER.to_string();
+    ret.push('\n');
+    for rule in rules {
+        ret.push('\n');
+        // A rule starts with its pattern.
+        let mut keys = rule_keys(rule);
+        keys.sort_by_key(|&(key, _)| key != "pattern");
+        for (key, value) in keys {
+            let values = match value {
+                Value::Boolean(true) => {
+                    ret.push_str(&format!(".{}\n", key));
+                    continue;
+                }
+                // The patterns of a condition have a line each.
+                Value::Array(patterns) if key != "states" => {
+                    patterns.iter().map(|p| p.as_str().unwrap_or("").to_string()).collect()
+                }
+                v => vec![legacy_value(rule, key, v)?],
+            };
+            for value in values {
+                // Legacy values are trimmed, and end at the end of the line.
+                if value.trim() != value || value.contains('\n') || value.contains('\r') {
+                    return Err(RuleError::new(&format!("Rule '{}': {} {:?} can't be written \
+                                                        in the legacy format.",
+                                                       rule.label(),
+                                                       key,
+                                                       value)));
+                }
+                let key = if key == "pattern" { key.to_string() } else { format!(".{}", key) };
+                ret.push_str(format!("{} = {}", key, value).trim_right());
+                ret.push('\n');
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// A value of a key in the legacy syntax.
+fn legacy_value(rule: &Rule, key: &str, value: Value) -> Result<String, RuleError> {
+    Ok(match value {
+        Value::Array(states) => {
+            let mut vals = vec![];
+            for s in &states {
+                vals.push(legacy_state(rule, s.as_str().unwrap_or(""))?);
+            }
+            vals.join(",")
+        }
+        Value::String(ref s) if key == "next_state" => legacy_state(rule, s)?,
+        Value::String(s) => s,
+        v => v.to_string(),
+    })
+}
+
+#[cfg(test)]
+fn parse_str(parser: &RuleParser,
+             format: Format,
+             content: &str)
+             -> Result<(Vec<Rule>, Vec<RuleTest>), RuleError> {
+    let mut rules = vec![];
+    let tests = parser.parse_str_with_tests("x", content, format, &mut rules)?;
+    Ok((rules, tests))
+}
+
+/// Convert the rules back and forth, and check nothing is lost on the way.
+#[cfg(test)]
+fn check_round_trip(format: Format, content: &str) {
+    let parser = RuleParser::new(Term::Xterm, 80);
+    let (rules, tests) = parse_str(&parser, format, content).unwrap();
+    assert!(rules.len() > 0);
+
+    let toml = to_toml(&rules, &tests);
+    let legacy = to_legacy(&rules).unwrap();
+    let (toml_rules, toml_tests) = parse_str(&parser, Format::Toml, &toml).unwrap();
+    let (legacy_rules, _) = parse_str(&parser, Format::Legacy, &legacy).unwrap();
+
+    // The tests move to other lines, but are otherwise the same.
+    assert_eq!(toml, to_toml(&toml_rules, &toml_tests));
+    assert_eq!(toml, to_toml(&legacy_rules, &tests));
+    assert_eq!(legacy, to_legacy(&toml_rules).unwrap());
+    assert_eq!(legacy, to_legacy(&legacy_rules).unwrap());
+}
+
+#[test]
+fn test_round_trip_legacy() {
+    check_round_trip(Format::Legacy, include_str!("../samples/highlighter-logcat"));
+}
+
+#[test]
+fn test_round_trip_toml() {
+    let content = include_str!("../samples/highlighter-logcat.toml");
+    check_round_trip(Format::Toml, content);
+
+    // The specs are kept as written.
+    let (rules, tests) = parse_str(&RuleParser::new(Term::Xterm, 80), Format::Toml, content)
+        .unwrap();
+    assert_eq!(2, tests.len());
+    let toml = to_toml(&rules, &tests);
+    assert!(toml.contains("\n[[rule]]\nname = 'fatal-start'\n\
+                           pattern = '(?:\\d F |\\bF[\\/\\(])'\n\
+                           states = ['']\nnext_state = 'in_fatal'\npre_line = '#'\n\
+                           pre_line_color = 'bred'\n"));
+    assert!(toml.contains("\nline_color = '444444'\n"));
+}
+
+#[test]
+fn test_round_trip_conditions() {
+    check_round_trip(Format::Toml,
+                     "[[rule]]\n\
+                      pattern = 'pid=\\d+'\n\
+                      when_all = ['ActivityManager', '!DEBUG']\n\
+                      when_any = ['Start', 'a,b']\n\
+                      unless = ['StrictMode']\n");
+    let mut rule = Rule::new("a").unwrap();
+    rule.set_unless(vec!["b".to_string(), "!c".to_string()]).unwrap();
+    assert_eq!(format!("{}\n\npattern = a\n.unless = b\n.unless = !c\n", LEGACY_HEADER),
+               to_legacy(&[rule]).unwrap());
+}
+
+#[test]
+fn test_to_legacy() {
+    let mut rule = Rule::new("a").unwrap();
+    rule.set_states(vec!["".to_string(), "x".to_string()])
+        .set_next_state("".to_string())
+        .set_stop(true);
+    assert_eq!(format!("{}\n\npattern = a\n.states = INIT,x\n.next_state = INIT\n.stop\n",
+                       LEGACY_HEADER),
+               to_legacy(&[rule.clone()]).unwrap());
+
+    // The engine and the options come right after the pattern, before the
+    // other patterns.
+    let mut with_engine = Rule::new("a").unwrap();
+    let options = matcher::Options { literal: true, unicode: Some(false), ..Default::default() };
+    with_engine.set_when("b".to_string())
+        .unwrap()
+        .set_engine(matcher::Engine::Regex)
+        .unwrap()
+        .set_options(options)
+        .unwrap();
+    assert_eq!(format!("{}\n\npattern = a\n.engine = regex\n.literal\n.unicode = false\n\
+                        .when = b\n",
+                       LEGACY_HEADER),
+               to_legacy(&[with_engine.clone()]).unwrap());
+    assert!(to_toml(&[with_engine], &[])
+        .ends_with("pattern = 'a'\nengine = 'regex'\nliteral = true\nunicode = false\n\
+                    when = 'b'\n"));
+
+    rule.set_description("two\nlines".to_string());
+    assert!(to_legacy(&[rule]).is_err());
+}
@@ -0,0 +1,177 @@
+use std::io;
+use std::io::{Cursor, Read};
+
+#[cfg(feature = "gzip")]
+use flate2::read::MultiGzDecoder;
+#[cfg(feature = "xz")]
+use xz2::read::XzDecoder;
+#[cfg(feature = "bzip2")]
+use bzip2::read::MultiBzDecoder;
+
+/// Stands for the block size of bzip2 in a magic number, from '1' to '9'.
+const BLOCK_SIZE: u8 = b'?';
+
+/// bzip2 has "BZh", the block size, then the magic of the first block, or of
+/// the end of the stream when it's empty.
+const MAGIC_NUMBERS: [(&'static [u8], Compression); 5] =
+    [(&[0x1f, 0x8b], Compression::Gzip),
+     (&[0xfd, b'7', b'z', b'X', b'Z', 0x00], Compression::Xz),
+     (&[0x28, 0xb5, 0x2f, 0xfd], Compression::Zstd),
+     (b"BZh?1AY&SY", Compression::Bzip2),
+     (b"BZh?\x17\x72\x45\x38\x50\x90", Compression::Bzip2)];
+
+/// Whether `data` starts like `magic`, if `data` is shorter.
+fn starts_like(data: &[u8], magic: &[u8]) -> bool {
+    data.iter().zip(magic).all(|(&d, &m)| {
+        if m == BLOCK_SIZE {
+            d >= b'1' && d <= b'9'
+        } else {
+            d == m
+        }
+    })
+}
+
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Zstd,
+    Bzip2,
+}
+
+impl Compression {
+    /// Tell the compression from the first bytes of a stream.
+    pub fn detect(magic: &[u8]) -> Compression {
+        for &(m, c) in MAGIC_NUMBERS.iter() {
+            if magic.len() >= m.len() && starts_like(magic, m) {
+                return c;
+            }
+        }
+        Compression::None
+    }
+
+    /// Whether more bytes are needed to tell the compression.
+    fn needs_more(magic: &[u8]) -> bool {
+        magic.len() == 0 ||
+        MAGIC_NUMBERS.iter().any(|&(m, _)| m.len() > magic.len() && starts_like(magic, m))
+    }
+
+    /// The name of the cargo feature for the codec.
+    pub fn feature(&self) -> &'static str {
+        match *self {
+            Compression::None => "",
+            Compression::Gzip => "gzip",
+            Compression::Xz => "xz",
+            Compression::Zstd => "zstd",
+            Compression::Bzip2 => "bzip2",
+        }
+    }
+}
+
+#[test]
+fn test_detect() {
+    assert_eq!(Compression::Gzip, Compression::detect(&[0x1f, 0x8b, 0x08, 0x00]));
+    assert_eq!(Compression::Xz,
+               Compression::detect(&[0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00]));
+    assert_eq!(Compression::Zstd, Compression::detect(&[0x28, 0xb5, 0x2f, 0xfd, 0x04]));
+    assert_eq!(Compression::Bzip2, Compression::detect(b"BZh91AY&SY\x00"));
+    assert_eq!(Compression::Bzip2,
+               Compression::detect(b"BZh9\x17\x72\x45\x38\x50\x90\x00\x00\x00\x00"));
+    assert_eq!(Compression::None, Compression::detect(b"BZh91AY"));
+    assert_eq!(Compression::None, Compression::detect(b"BZh01AY&SY"));
+    assert_eq!(Compression::None, Compression::detect(b"BZhello, world"));
+    assert_eq!(Compression::None, Compression::detect(b"BZ"));
+    assert_eq!(Compression::None, Compression::detect(b""));
+    assert_eq!(Compression::None, Compression::detect(b"hello"));
+
+    assert!(Compression::needs_more(b""));
+    assert!(Compression::needs_more(b"BZ"));
+    assert!(Compression::needs_more(&[0xfd, b'7']));
+    assert!(Compression::needs_more(b"BZh9"));
+    assert!(!Compression::needs_more(b"BZhello"));
+    assert!(!Compression::needs_more(b"ok\n"));
+}
+
+fn not_supported(c: Compression) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput,
+                   format!("The input is compressed with {}, but hl was built without the \
+                            '{}' feature",
+                           c.feature(),
+                           c.feature()))
+}
+
+/// Wrap `reader` with a decoder if the data is compressed, which is told by
+/// the magic number, not by the file name.  Concatenated streams, like from
+/// `cat a.gz b.gz`, are read through.
+///
+/// Reads only as much as needed to tell the compression, so a short line from
+/// a pipe isn't held back.
+pub fn open<R: Read + Send + 'static>(mut reader: R) -> io::Result<Box<Read + Send>> {
+    let mut magic = vec![];
+    let mut buf = [0u8; 16];
+    while Compression::needs_more(&magic) {
+        let want = MAGIC_NUMBERS.iter().map(|&(m, _)| m.len()).max().unwrap() - magic.len();
+        match reader.read(&mut buf[..want]) {
+            Ok(0) => break,
+            Ok(n) => magic.extend_from_slice(&buf[..n]),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    let compression = Compression::detect(&magic);
+    debug!("Detected compression: {:?}", compression);
+
+    let stream = Cursor::new(magic).chain(reader);
+    match compression {
+        Compression::None => Ok(Box::new(stream)),
+        #[cfg(feature = "gzip")]
+        Compression::Gzip => Ok(Box::new(MultiGzDecoder::new(stream))),
+        #[cfg(feature = "xz")]
+        Compression::Xz => Ok(Box::new(XzDecoder::new_multi_decoder(stream))),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => Ok(Box::new(::zstd::stream::read::Decoder::new(stream)?)),
+        #[cfg(feature = "bzip2")]
+        Compression::Bzip2 => Ok(Box::new(MultiBzDecoder::new(stream))),
+        #[allow(unreachable_patterns)]
+        c => Err(not_supported(c)),
+    }
+}
+
+#[cfg(test)]
+fn read_all(r: Box<Read + Send>) -> io::Result<String> {
+    let mut r = r;
+    let mut s = String::new();
+    r.read_to_string(&mut s)?;
+    Ok(s)
+}
+
+#[test]
+fn test_open_plain() {
+    assert_eq!("ab\ncd\n", read_all(open(Cursor::new(b"ab\ncd\n".to_vec())).unwrap()).unwrap());
+    assert_eq!("a", read_all(open(Cursor::new(b"a".to_vec())).unwrap()).unwrap());
+    assert_eq!("", read_all(open(io::empty()).unwrap()).unwrap());
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn test_open_gzip() {
+    use std::io::Write;
+    use flate2::write::GzEncoder;
+
+    let mut data = vec![];
+    for text in &["line 1\n", "line 2\n"] {
+        let mut e = GzEncoder::new(vec![], ::flate2::Compression::default());
+        e.write_all(text.as_bytes()).unwrap();
+        data.extend(e.finish().unwrap());
+    }
+    assert_eq!("line 1\nline 2\n", read_all(open(Cursor::new(data)).unwrap()).unwrap());
+}
+
+#[cfg(not(feature = "xz"))]
+#[test]
+fn test_open_unsupported() {
+    let data = vec![0xfd, b'7', b'z', b'X', b'Z', 0x00, 0x00];
+    let e = open(Cursor::new(data)).err().unwrap();
+    assert!(e.to_string().contains("'xz' feature"));
+}
@@ -1,48 +1,70 @@
 extern crate env_logger;
 
-use std::rc::Rc;
+use std::io;
+use std::io::prelude::*;
+use std::mem;
+use std::sync::Arc;
 
 use super::*;
 use term_color::*;
 use rule::*;
+use render::write_ansi_runs;
 
 #[derive(Debug,Clone)]
 struct LayeredColors {
-    colors: Rc<Colors>,
+    colors: Arc<Colors>,
+    /// The index of the rule the colors came from.
+    source: Option<usize>,
     back: Option<Box<LayeredColors>>,
 }
 
 impl LayeredColors {
     fn new() -> LayeredColors {
         LayeredColors {
-            colors: Rc::new(NO_COLORS.clone()),
+            colors: Arc::new(NO_COLORS.clone()),
+            source: None,
             back: None,
         }
     }
 
-    fn with_colors(colors: Rc<Colors>, back: LayeredColors) -> LayeredColors {
+    fn with_colors(colors: Arc<Colors>, back: LayeredColors) -> LayeredColors {
+        LayeredColors::with_source(colors, None, back)
+    }
+
+    fn with_source(colors: Arc<Colors>, source: Option<usize>, back: LayeredColors) -> LayeredColors {
         LayeredColors {
             colors: colors,
+            source: source,
             back: Some(Box::new(back)),
         }
     }
 
     fn fg_colors(&self) -> &Colors {
-        self.colors_inner(true)
+        &self.layer(true).colors
     }
 
     fn bg_colors(&self) -> &Colors {
-        self.colors_inner(false)
+        &self.layer(false).colors
+    }
+
+    /// The rule whose foreground color is in effect.
+    fn fg_source(&self) -> Option<usize> {
+        self.layer(true).source
     }
 
-    fn colors_inner(&self, fg: bool) -> &Colors {
+    /// The rule whose background color is in effect.
+    fn bg_source(&self) -> Option<usize> {
+        self.layer(false).source
+    }
+
+    fn layer(&self, fg: bool) -> &LayeredColors {
         let mut cur: &LayeredColors = self;
         while (cur.colors.get_color(fg) ==  Color::None) && cur.back.is_some() {
             let back_ref: &Option<Box<LayeredColors>> = &cur.back;
             let back_some: &Box<LayeredColors> = back_ref.as_ref().unwrap();
             cur = back_some;
         }
-        &cur.colors
+        cur
     }
 }
 
@@ -54,15 +76,15 @@ fn test_layered_color() {
     let cb3 = Colors::with_colors(Color::None, Color::Console(3), ATTR_NONE, Term::Xterm);
     let cb4 = Colors::with_colors(Color::None, Color::Console(4), ATTR_NONE, Term::Xterm);
 
-    let l1 = LayeredColors::with_colors(Rc::new(cf1), empty.clone());
+    let l1 = LayeredColors::with_colors(Arc::new(cf1), empty.clone());
     assert_eq!(Color::Console(1), l1.fg_colors().fg());
     assert_eq!(Color::None, l1.bg_colors().bg());
 
-    let l2 = LayeredColors::with_colors(Rc::new(cf2), l1.clone());
+    let l2 = LayeredColors::with_colors(Arc::new(cf2), l1.clone());
     assert_eq!(Color::Console(2), l2.fg_colors().fg());
     assert_eq!(Color::None, l1.bg_colors().bg());
 
-    let l3 = LayeredColors::with_colors(Rc::new(cb3), l2.clone());
+    let l3 = LayeredColors::with_colors(Arc::new(cb3), l2.clone());
     assert_eq!(Color::Console(2), l3.fg_colors().fg());
     assert_eq!(Color::Console(3), l3.bg_colors().bg());
 }
@@ -70,134 +92,964 @@ fn test_layered_color() {
 
 #[derive(Debug)]
 struct Matches<'a> {
+    index: usize,
     rule: &'a Rule,
     ranges: Vec<(usize, usize)>,
 }
 
-#[derive(Debug)]
-pub struct Filter {
-    term: Term,
-    rules: Vec<Rule>,
-    state: String,
+/// A run of text with fully resolved colors.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct StyledSpan {
+    pub text: String,
+    pub fg: Color,
+    pub bg: Color,
+    pub attrs: Attribute,
 }
 
-impl Filter {
-    pub fn new(term: Term, rules: Vec<Rule>) -> Filter {
-        Filter {
-            term: term,
-            rules: rules,
-            state: String::new(),
+/// A pre or post line of a matched rule, with the captures put in.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Decoration {
+    /// The index of the rule.
+    pub rule: usize,
+    pub text: String,
+    pub colors: Option<Colors>,
+}
+
+impl Decoration {
+    /// The line as a span, like the spans of the highlighted text.
+    pub fn span(&self) -> StyledSpan {
+        let (fg, bg, attrs) = match self.colors {
+            Some(ref c) => (c.fg(), c.bg(), c.attrs()),
+            None => (Color::None, Color::None, ATTR_NONE),
+        };
+        StyledSpan {
+            text: self.text.clone(),
+            fg: fg,
+            bg: bg,
+            attrs: attrs,
         }
     }
+}
 
-    pub fn process<F>(&mut self, line: &str, out: F)
-        where F: Fn(&str)
-    {
-        debug!("line={}", line);
+/// A line after the rules are matched and the colors are put together, before
+/// it's rendered; see `Filter::highlight()` and `render::Renderer`.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct HighlightedLine {
+    /// The input line.
+    pub text: String,
+    /// The input line in runs of the same style.
+    pub spans: Vec<StyledSpan>,
+    /// The lines to show before and after it.
+    pub pre_lines: Vec<Decoration>,
+    pub post_lines: Vec<Decoration>,
+    /// The indexes of the matched rules, in order.
+    pub rules: Vec<usize>,
+}
+
+/// A single output line, produced by `Filter::process_styled()`.
+#[derive(Debug)]
+pub enum StyledLine<'a> {
+    /// A pre or post line of a matched rule, and its text with the captures
+    /// put in.
+    Decorative(&'a DecorativeLine, String),
+    /// The highlighted input line.
+    Text(Vec<StyledSpan>),
+}
 
-        // Find matches.
-        let mut matches: Vec<Matches> = vec![];
-        for r in &self.rules {
-            if r.states().len() > 0 {
-                if !r.states().contains(&self.state) {
+/// A run of bytes of a line, with the rules whose colors won for the
+/// foreground and the background.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct ExplainedSpan {
+    pub start: usize,
+    pub end: usize,
+    pub fg: Option<usize>,
+    pub bg: Option<usize>,
+}
+
+/// How the last line was processed; see `Filter::set_explain()`.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Explanation {
+    pub state_before: String,
+    pub state_after: String,
+    /// The indexes of the matched rules, in order, with their byte ranges.
+    pub matches: Vec<(usize, Vec<(usize, usize)>)>,
+    /// The `stop` rule that ended the evaluation, if any.
+    pub stopped_by: Option<usize>,
+    /// The parts of the line colored by any rule.
+    pub spans: Vec<ExplainedSpan>,
+}
+
+impl Explanation {
+    /// `matches` must be in the reverse order.
+    fn new(state_before: String,
+           state_after: &String,
+           matches: &Vec<Matches>,
+           ca: &Vec<LayeredColors>)
+           -> Explanation {
+        let mut spans: Vec<ExplainedSpan> = vec![];
+        for (i, c) in ca.iter().enumerate() {
+            let (fg, bg) = (c.fg_source(), c.bg_source());
+            if let Some(last) = spans.last_mut() {
+                if last.end == i && last.fg == fg && last.bg == bg {
+                    last.end = i + 1;
                     continue;
                 }
             }
-            let m = r.matches(line);
-            if m.len() == 0 {
-                continue;
-            }
-            debug!("  found={}, {:?}", r.pattern(), m);
-            debug!("    states='{:?}'", r.states());
-            if let Some(s) = r.next_state() {
-                self.state = s.clone();
-                debug!("    next_state='{}'", self.state);
+            if fg.is_some() || bg.is_some() {
+                spans.push(ExplainedSpan {
+                    start: i,
+                    end: i + 1,
+                    fg: fg,
+                    bg: bg,
+                });
             }
-            matches.push(Matches {
-                rule: &r,
-                ranges: m,
-            });
-            if r.stop() {
-                break;
+        }
+        let stopped_by = match matches.first() {
+            Some(m) if m.rule.stop() => Some(m.index),
+            _ => None,
+        };
+        Explanation {
+            state_before: state_before,
+            state_after: state_after.clone(),
+            matches: matches.iter().rev().map(|m| (m.index, m.ranges.clone())).collect(),
+            stopped_by: stopped_by,
+            spans: spans,
+        }
+    }
+
+    /// Describe it in a few lines of text, with the names of the rules.
+    pub fn describe(&self, rules: &[Rule]) -> Vec<String> {
+        let rule = |i: usize| {
+            match rules[i].description() {
+                Some(d) => format!("#{} {} ({})", i, rules[i].label(), d),
+                None => format!("#{} {}", i, rules[i].label()),
             }
+        };
+        let source = |s: Option<usize>| s.map(|i| format!("#{}", i)).unwrap_or("-".to_string());
+
+        let mut ret = vec![format!("state: '{}' -> '{}'", self.state_before, self.state_after)];
+        for &(index, ref ranges) in &self.matches {
+            let ranges: Vec<String> = ranges.iter().map(|r| format!("{}..{}", r.0, r.1)).collect();
+            let stop = if self.stopped_by == Some(index) { ", stop" } else { "" };
+            ret.push(format!("{}: {}{}", rule(index), ranges.join(" "), stop));
+        }
+        if self.spans.len() > 0 {
+            let spans: Vec<String> = self.spans
+                .iter()
+                .map(|s| format!("{}..{} fg={} bg={}", s.start, s.end, source(s.fg), source(s.bg)))
+                .collect();
+            ret.push(format!("colors: {}", spans.join(", ")));
         }
+        ret
+    }
+}
 
-        // Show pre lines.
-        for m in &matches {
-            if let Some(ref l) = m.rule.pre_line() {
-                out(&l.computed_line());
+/// What a filter changes as it processes lines.  Filters sharing a `RuleSet`
+/// each have their own.
+#[derive(Debug,Clone)]
+pub struct FilterState {
+    /// The current state, for rules with `states`.
+    state: String,
+    enabled: Vec<bool>,
+
+    /// The stream of the lines being processed.
+    stream: Stream,
+
+    /// Indexes of the rules that matched the last line.
+    last_matches: Vec<usize>,
+
+    /// Whether to keep the explanation of each line.
+    explain: bool,
+    explanation: Option<Explanation>,
+
+    /// The output of the line, kept to reuse its memory.
+    buf: Vec<u8>,
+}
+
+impl FilterState {
+    /// The initial state, with all the rules enabled.
+    pub fn new(rules: &RuleSet) -> FilterState {
+        FilterState {
+            state: String::new(),
+            enabled: vec![true; rules.len()],
+            stream: Stream::Stdout,
+            last_matches: vec![],
+            explain: false,
+            explanation: None,
+            buf: vec![],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Filter {
+    term: Term,
+    rules: Arc<RuleSet>,
+
+    /// The colors under all the rules for stderr lines.
+    stderr_colors: Option<Arc<Colors>>,
+
+    fs: FilterState,
+}
+
+fn find_matches<'a>(rules: &'a Vec<Rule>,
+                    enabled: &Vec<bool>,
+                    state: &mut String,
+                    stream: Stream,
+                    line: &str)
+                    -> Vec<Matches<'a>> {
+    let mut matches: Vec<Matches> = vec![];
+    for (index, r) in rules.iter().enumerate() {
+        if !enabled[index] {
+            continue;
+        }
+        if r.stream().map(|s| s != stream).unwrap_or(false) {
+            continue;
+        }
+        if r.states().len() > 0 {
+            if !r.states().contains(state) {
+                continue;
             }
         }
+        let m = r.matches(line);
+        if m.len() == 0 {
+            continue;
+        }
+        debug!("  found={}, {:?}", r.pattern(), m);
+        debug!("    states='{:?}'", r.states());
+        if let Some(s) = r.next_state() {
+            *state = s.clone();
+            debug!("    next_state='{}'", state);
+        }
+        matches.push(Matches {
+            index: index,
+            rule: &r,
+            ranges: m,
+        });
+        if r.stop() {
+            break;
+        }
+    }
+    matches
+}
 
-        let mut ca = Vec::with_capacity(line.len());
-        ca.resize(line.len(), LayeredColors::new());
+/// Render a decorative line of a matched rule, filling in the captures if needed.
+/// Returns the line without and with colors.
+fn render_decorative(rule: &Rule, dl: &DecorativeLine, line: &str) -> (String, String) {
+    if dl.has_captures() {
+        dl.render(&rule.captures(line))
+    } else {
+        (dl.plain_line().clone(), dl.computed_line().clone())
+    }
+}
 
-        // From here, we apply in the reverse order.
-        matches.reverse();
+/// Same as `render_decorative()`, but writes the line with colors and a
+/// newline.  Only the lines with captures are rendered again.
+fn write_decorative(rule: &Rule, dl: &DecorativeLine, line: &str, out: &mut Vec<u8>) {
+    if dl.has_captures() {
+        out.extend_from_slice(dl.render(&rule.captures(line)).1.as_bytes());
+    } else {
+        out.extend_from_slice(dl.computed_line().as_bytes());
+    }
+    out.push(b'\n');
+}
 
-        // First, apply the line colors.
-        for m in &matches {
-            for r in &m.ranges {
-                if let Some(c) = m.rule.line_colors() {
-                    for i in 0..line.len() {
-                        ca[i] = LayeredColors::with_colors(c.clone(), ca[i].clone());
-                    }
+fn decoration(m: &Matches, dl: &DecorativeLine, line: &str) -> Decoration {
+    Decoration {
+        rule: m.index,
+        text: render_decorative(m.rule, dl, line).0,
+        colors: dl.colors().cloned(),
+    }
+}
+
+/// The runs of the bytes of a line with the same colors, with the colors of
+/// the foreground and of the background.
+struct ColorRuns<'a> {
+    line: &'a [u8],
+    ca: &'a [LayeredColors],
+    start: usize,
+}
+
+impl<'a> ColorRuns<'a> {
+    fn new(line: &'a str, ca: &'a [LayeredColors]) -> ColorRuns<'a> {
+        ColorRuns {
+            line: line.as_bytes(),
+            ca: ca,
+            start: 0,
+        }
+    }
+}
+
+impl<'a> Iterator for ColorRuns<'a> {
+    type Item = (&'a Colors, &'a Colors, &'a [u8]);
+
+    fn next(&mut self) -> Option<(&'a Colors, &'a Colors, &'a [u8])> {
+        if self.start >= self.line.len() {
+            return None;
+        }
+        let ca = self.ca;
+        let (fg, bg) = (ca[self.start].fg_colors(), ca[self.start].bg_colors());
+        let mut end = self.start + 1;
+        while end < self.line.len() && ca[end].fg_colors().fg_eq(fg) &&
+              ca[end].bg_colors().bg_eq(bg) {
+            end += 1;
+        }
+        let text = &self.line[self.start..end];
+        self.start = end;
+        Some((fg, bg, text))
+    }
+}
+
+/// Split a line into spans with the same colors.
+fn styled_spans(line: &str, ca: &[LayeredColors]) -> Vec<StyledSpan> {
+    ColorRuns::new(line, ca)
+        .map(|(fg, bg, text)| {
+            StyledSpan {
+                text: String::from_utf8_lossy(text).into_owned(),
+                fg: fg.fg(),
+                bg: bg.bg(),
+                attrs: fg.attrs(),
+            }
+        })
+        .collect()
+}
+
+/// Write a line with the escape sequences the rules computed for their
+/// colors.
+fn write_colored(term: Term, line: &str, ca: &[LayeredColors], out: &mut Vec<u8>) {
+    let runs = ColorRuns::new(line, ca)
+        .map(|(fg, bg, text)| (fg.fg_code().as_str(), bg.bg_code().as_str(), text));
+    // Writing to a Vec doesn't fail.
+    write_ansi_runs(term, runs, out).unwrap();
+}
+
+/// Match the rules, in the state of `fs`, and put the colors together.  The
+/// matches are given in the reverse order.
+fn match_line<'a>(rules: &'a RuleSet,
+                  fs: &mut FilterState,
+                  base: Option<&Arc<Colors>>,
+                  line: &str)
+                  -> (Vec<Matches<'a>>, Vec<LayeredColors>) {
+    debug!("line={}", line);
+    let state_before = fs.state.clone();
+
+    let mut matches = find_matches(rules.rules(), &fs.enabled, &mut fs.state, fs.stream, line);
+    fs.last_matches = matches.iter().map(|m| m.index).collect();
+
+    // From here, we apply in the reverse order.
+    matches.reverse();
+
+    let ca = layer_colors(line, &matches, base);
+    if fs.explain {
+        fs.explanation = Some(Explanation::new(state_before, &fs.state, &matches, &ca));
+    }
+    (matches, ca)
+}
+
+/// Build the color layers of each byte, on top of `base`. `matches` must be in
+/// the reverse order.
+fn layer_colors(line: &str, matches: &Vec<Matches>, base: Option<&Arc<Colors>>) -> Vec<LayeredColors> {
+    let bottom = match base {
+        Some(c) => LayeredColors::with_colors(c.clone(), LayeredColors::new()),
+        None => LayeredColors::new(),
+    };
+    let mut ca = Vec::with_capacity(line.len());
+    ca.resize(line.len(), bottom);
+
+    // First, apply the line colors.
+    for m in matches {
+        for r in &m.ranges {
+            if let Some(c) = m.rule.line_colors() {
+                for i in 0..line.len() {
+                    ca[i] = LayeredColors::with_source(c.clone(), Some(m.index), ca[i].clone());
                 }
             }
         }
+    }
 
-        // Apply match colors (in the reverse order).
-        for m in &matches {
-            for r in &m.ranges {
-                for i in (r.0)..(r.1) {
-                    if let Some(c) = m.rule.match_colors() {
-                        ca[i] = LayeredColors::with_colors(c.clone(), ca[i].clone());
-                    }
+    // Apply match colors (in the reverse order).
+    for m in matches {
+        for r in &m.ranges {
+            for i in (r.0)..(r.1) {
+                if let Some(c) = m.rule.match_colors() {
+                    ca[i] = LayeredColors::with_source(c.clone(), Some(m.index), ca[i].clone());
                 }
             }
         }
+    }
+    ca
+}
+
+impl Filter {
+    pub fn new(term: Term, rules: Vec<Rule>) -> Filter {
+        Filter::with_rule_set(term, Arc::new(RuleSet::new(rules)))
+    }
+
+    /// A filter using shared rules, e.g. the same ones as a filter of another
+    /// thread.
+    pub fn with_rule_set(term: Term, rules: Arc<RuleSet>) -> Filter {
+        let fs = FilterState::new(&rules);
+        Filter {
+            term: term,
+            rules: rules,
+            stderr_colors: None,
+            fs: fs,
+        }
+    }
+
+    /// A filter sharing the rules and the settings, in the initial state.
+    pub fn fork(&self) -> Filter {
+        let mut fs = FilterState::new(&self.rules);
+        fs.enabled = self.fs.enabled.clone();
+        fs.stream = self.fs.stream;
+        fs.explain = self.fs.explain;
+        Filter {
+            term: self.term,
+            rules: self.rules.clone(),
+            stderr_colors: self.stderr_colors.clone(),
+            fs: fs,
+        }
+    }
+
+    /// Replace the rules.  The current state is carried over, and so is
+    /// whether each rule is enabled: by the name for named rules, or else by
+    /// the index if the number of the rules is the same.  New rules are
+    /// enabled.
+    pub fn set_rules(&mut self, rules: Vec<Rule>) {
+        self.set_rule_set(Arc::new(RuleSet::new(rules)));
+    }
 
-        // Now build the result, char by char.
-        let mut res = String::new();
+    /// Same as `set_rules()`, with shared rules.
+    pub fn set_rule_set(&mut self, rules: Arc<RuleSet>) {
+        let same_count = rules.len() == self.rules.len();
+        let enabled = rules.rules()
+            .iter()
+            .enumerate()
+            .map(|(i, rule)| {
+                let old = match rule.name() {
+                    Some(name) => self.rules.rules().iter().position(|r| r.name() == Some(name)),
+                    None if same_count => Some(i),
+                    None => None,
+                };
+                old.map_or(true, |j| self.fs.enabled[j])
+            })
+            .collect();
+        self.fs.enabled = enabled;
+        self.rules = rules;
+    }
 
-        let line_bytes = line.as_bytes();
+    /// Update the width of the decorative lines of all the rules.  Other
+    /// filters sharing the rules keep the old width.
+    pub fn set_width(&mut self, console_width: usize) {
+        self.rules = Arc::new(self.rules.with_width(console_width));
+    }
 
-        let mut last_fg: &Colors = &NO_COLORS;
-        let mut last_bg: &Colors = &NO_COLORS;
+    pub fn rules(&self) -> &Vec<Rule> {
+        self.rules.rules()
+    }
 
-        let mut in_color = false;
+    pub fn rule_set(&self) -> &Arc<RuleSet> {
+        &self.rules
+    }
 
-        for i in 0..line.len() {
-            let fg = ca[i].fg_colors();
-            let bg = ca[i].bg_colors();
+    pub fn term(&self) -> Term {
+        self.term
+    }
 
-            if !fg.fg_eq(last_fg) || !bg.bg_eq(last_bg) {
-                if in_color {
-                    res.push_str(self.term.csi_reset());
-                    in_color = false;
-                }
-                res.push_str(fg.fg_code());
-                res.push_str(bg.bg_code());
+    pub fn state(&self) -> &String {
+        &self.fs.state
+    }
 
-                last_fg = fg;
-                last_bg = bg;
-                in_color = true;
+    /// Go back to the initial state.
+    pub fn reset_state(&mut self) {
+        self.fs.state = String::new();
+    }
+
+    pub fn is_rule_enabled(&self, index: usize) -> bool {
+        self.fs.enabled[index]
+    }
+
+    /// Enable or disable a rule.  Disabled rules are skipped as if they didn't exist.
+    pub fn set_rule_enabled(&mut self, index: usize, enabled: bool) {
+        self.fs.enabled[index] = enabled;
+    }
+
+    /// Enable or disable all the rules with a tag.  Returns the number of the rules.
+    pub fn set_tag_enabled(&mut self, tag: &str, enabled: bool) -> usize {
+        let mut count = 0;
+        for (i, r) in self.rules.rules().iter().enumerate() {
+            if r.tag().map_or(false, |t| t == tag) {
+                self.fs.enabled[i] = enabled;
+                count += 1;
             }
+        }
+        count
+    }
+
+    /// Indexes of the rules that matched the last processed line.
+    pub fn last_matches(&self) -> &Vec<usize> {
+        &self.fs.last_matches
+    }
+
+    /// Keep how each line is processed, for `explanation()`.
+    pub fn set_explain(&mut self, explain: bool) {
+        self.fs.explain = explain;
+        self.fs.explanation = None;
+    }
+
+    /// How the last line was processed, if `set_explain()` is on.
+    pub fn explanation(&self) -> Option<&Explanation> {
+        self.fs.explanation.as_ref()
+    }
+
+    /// Set the stream of the following lines, for rules with `stream`.
+    pub fn set_stream(&mut self, stream: Stream) {
+        self.fs.stream = stream;
+    }
+
+    pub fn stream(&self) -> Stream {
+        self.fs.stream
+    }
+
+    pub fn set_stderr_colors(&mut self, colors: Colors) {
+        self.stderr_colors = Some(Arc::new(colors));
+    }
 
-            res.push(line_bytes[i] as char);
+    fn base_colors(&self) -> Option<&Arc<Colors>> {
+        match self.fs.stream {
+            Stream::Stderr => self.stderr_colors.as_ref(),
+            Stream::Stdout => None,
         }
-        if in_color {
-            res.push_str(self.term.csi_reset());
-            in_color = false;
+    }
+
+    pub fn process<F>(&mut self, line: &str, mut out: F)
+        where F: FnMut(&str)
+    {
+        let mut res = vec![];
+        // Writing to a Vec doesn't fail.
+        self.process_to(line, &mut res).unwrap();
+        // The output is UTF-8, as the line is.
+        let res = String::from_utf8(res).unwrap();
+        let mut lines: Vec<&str> = res.split('\n').collect();
+        lines.pop(); // After the last newline.
+        for l in lines {
+            out(l);
         }
+    }
 
-        out(&res);
+    /// Same as `process()`, but writes the output lines to `out`, each with a
+    /// newline.  They're written at once, from a buffer that's reused for the
+    /// next line.  The output is the same as `render::AnsiRenderer` gives.
+    pub fn process_to(&mut self, line: &str, out: &mut Write) -> io::Result<()> {
+        let rules = self.rules.clone();
+        let base = self.base_colors().cloned();
+        let (matches, ca) = match_line(&rules, &mut self.fs, base.as_ref(), line);
 
-        // Show post lines.
+        let mut buf = mem::replace(&mut self.fs.buf, vec![]);
+        buf.clear();
+        for m in matches.iter().rev() {
+            if let Some(l) = m.rule.pre_line() {
+                write_decorative(m.rule, l, line, &mut buf);
+            }
+        }
+        write_colored(self.term, line, &ca, &mut buf);
+        buf.push(b'\n');
         for m in &matches {
-            if let Some(ref l) = m.rule.post_line() {
-                out(&l.computed_line());
+            if let Some(l) = m.rule.post_line() {
+                write_decorative(m.rule, l, line, &mut buf);
+            }
+        }
+        let res = out.write_all(&buf);
+        self.fs.buf = buf;
+        res
+    }
+
+    /// Highlight an incomplete line, e.g. a prompt, without changing the state
+    /// or showing the decorative lines; the complete line is to be given to
+    /// `process()` later.
+    pub fn process_partial(&self, line: &str) -> String {
+        let mut state = self.fs.state.clone();
+        let mut matches =
+            find_matches(self.rules.rules(), &self.fs.enabled, &mut state, self.fs.stream, line);
+        matches.reverse();
+        let ca = layer_colors(line, &matches, self.base_colors());
+        let mut res = vec![];
+        write_colored(self.term, line, &ca, &mut res);
+        String::from_utf8(res).unwrap()
+    }
+
+    /// Match the rules and put the colors together, without rendering the
+    /// line.  `render::AnsiRenderer` renders it as `process()` does.
+    pub fn highlight(&mut self, line: &str) -> HighlightedLine {
+        let rules = self.rules.clone();
+        let base = self.base_colors().cloned();
+        let (matches, ca) = match_line(&rules, &mut self.fs, base.as_ref(), line);
+
+        HighlightedLine {
+            text: line.to_string(),
+            spans: styled_spans(line, &ca),
+            pre_lines: matches.iter()
+                .rev()
+                .filter_map(|m| m.rule.pre_line().map(|l| decoration(m, l, line)))
+                .collect(),
+            post_lines: matches.iter()
+                .filter_map(|m| m.rule.post_line().map(|l| decoration(m, l, line)))
+                .collect(),
+            rules: self.fs.last_matches.clone(),
+        }
+    }
+
+    /// Same as `process()`, but instead of ANSI strings, gives the output lines
+    /// as spans with resolved colors, for non-terminal output formats.
+    pub fn process_styled<F>(&mut self, line: &str, mut out: F)
+        where F: FnMut(StyledLine)
+    {
+        let HighlightedLine { spans, pre_lines, post_lines, .. } = self.highlight(line);
+        for d in pre_lines {
+            out(StyledLine::Decorative(self.rules.rules()[d.rule].pre_line().unwrap(), d.text));
+        }
+        out(StyledLine::Text(spans));
+        for d in post_lines {
+            out(StyledLine::Decorative(self.rules.rules()[d.rule].post_line().unwrap(), d.text));
+        }
+    }
+}
+
+/// Highlights what's written to it a line at a time, and writes the result to
+/// the inner writer.  An incomplete line is kept until the rest of it comes,
+/// or until `finish()` or the writer is dropped.
+pub struct HighlightWriter<W: Write> {
+    filter: Filter,
+    /// Only `None` once `finish()` has taken it.
+    inner: Option<W>,
+    /// The incomplete line.
+    pending: Vec<u8>,
+}
+
+impl<W: Write> HighlightWriter<W> {
+    pub fn new(filter: Filter, inner: W) -> HighlightWriter<W> {
+        HighlightWriter {
+            filter: filter,
+            inner: Some(inner),
+            pending: vec![],
+        }
+    }
+
+    pub fn filter(&self) -> &Filter {
+        &self.filter
+    }
+
+    pub fn filter_mut(&mut self) -> &mut Filter {
+        &mut self.filter
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.inner.as_ref().unwrap()
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        self.inner.as_mut().unwrap()
+    }
+
+    /// Highlight the incomplete line if there's one, flush, and give the inner
+    /// writer back.
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.pending.len() > 0 {
+            try!(self.write_pending());
+        }
+        try!(self.get_mut().flush());
+        Ok(self.inner.take().unwrap())
+    }
+
+    /// Highlight the pending line, without the CR of a CRLF.  The line is gone
+    /// even if it can't be written, so it doesn't come back with the next one.
+    fn write_pending(&mut self) -> io::Result<()> {
+        let res = {
+            let mut line: &[u8] = &self.pending;
+            if line.last() == Some(&b'\r') {
+                line = &line[..line.len() - 1];
+            }
+            self.filter.process_to(&String::from_utf8_lossy(line), self.inner.as_mut().unwrap())
+        };
+        self.pending.clear();
+        res
+    }
+}
+
+impl<W: Write> Write for HighlightWriter<W> {
+    /// Takes the bytes up to the first line that can't be written.  The error
+    /// is only given when it's the first line, as the bytes before it are
+    /// taken.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut written = 0;
+        while let Some(nl) = buf[written..].iter().position(|&b| b == b'\n') {
+            self.pending.extend_from_slice(&buf[written..written + nl]);
+            if let Err(e) = self.write_pending() {
+                return if written == 0 { Err(e) } else { Ok(written) };
             }
+            written += nl + 1;
         }
+        self.pending.extend_from_slice(&buf[written..]);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.get_mut().flush()
+    }
+}
+
+impl<W: Write> Drop for HighlightWriter<W> {
+    /// Same as `finish()`, but the errors are lost; call `finish()` to get
+    /// them.
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            if self.pending.len() > 0 {
+                let _ = self.write_pending();
+            }
+            let _ = self.get_mut().flush();
+        }
+    }
+}
+
+#[test]
+fn test_process_to() {
+    let mut rule = Rule::new("b").unwrap();
+    rule.set_match_colors(Colors::with_colors(Color::Console(1), Color::None, ATTR_NONE, Term::Xterm))
+        .set_pre_line(DecorativeLine::new("-", None, Term::Xterm, 3));
+    let mut filter = Filter::new(Term::Xterm, vec![rule]);
+
+    let mut out = vec![];
+    filter.process_to("ab", &mut out).unwrap();
+    filter.process_to("\u{e9}b", &mut out).unwrap();
+    assert_eq!("---\na\x1b[31mb\x1b[0m\n---\n\u{e9}\x1b[31mb\x1b[0m\n",
+               String::from_utf8(out).unwrap());
+
+    // Errors come back to the caller.
+    let mut full = io::Cursor::new([0u8; 5]);
+    assert!(filter.process_to("ab", &mut full).is_err());
+}
+
+#[test]
+fn test_highlight_writer() {
+    let mut rule = Rule::new("b").unwrap();
+    rule.set_match_colors(Colors::with_colors(Color::Console(1), Color::None, ATTR_NONE, Term::Xterm));
+    let mut w = HighlightWriter::new(Filter::new(Term::Xterm, vec![rule]), vec![]);
+
+    w.write_all(b"ab\r\nx").unwrap();
+    assert_eq!(b"a\x1b[31mb\x1b[0m\n", &w.get_ref()[..]);
+    w.write_all(b"y\nb").unwrap();
+    assert_eq!(b"a\x1b[31mb\x1b[0m\nxy\n", &w.get_ref()[..]);
+    let out = w.finish().unwrap();
+    assert_eq!(b"a\x1b[31mb\x1b[0m\nxy\n\x1b[31mb\x1b[0m\n", &out[..]);
+
+    // The incomplete line is written on drop.
+    let mut out = vec![];
+    HighlightWriter::new(Filter::new(Term::Xterm, vec![]), &mut out).write_all(b"a\nb").unwrap();
+    assert_eq!(b"a\nb\n", &out[..]);
+}
+
+#[test]
+fn test_highlight_writer_errors() {
+    let mut w = HighlightWriter::new(Filter::new(Term::Xterm, vec![]),
+                                     io::Cursor::new([0u8; 4]));
+    // "ab\n" is written, and "cd\n" doesn't fit.
+    assert_eq!(3, w.write(b"ab\ncd\nef").unwrap());
+    assert!(w.write(b"cd\nef").is_err());
+    assert_eq!(0, w.pending.len());
+    assert_eq!(2, w.write(b"ef").unwrap());
+}
+
+#[test]
+fn test_process_styled() {
+    let mut rule = Rule::new("b+").unwrap();
+    rule.set_match_colors(Colors::with_colors(Color::Console(1), Color::None, ATTR_NONE, Term::Xterm));
+    let mut filter = Filter::new(Term::Xterm, vec![rule]);
+
+    let mut lines = vec![];
+    filter.process_styled("abbc", |l| {
+        if let StyledLine::Text(spans) = l {
+            lines.push(spans);
+        }
+    });
+    assert_eq!(1, lines.len());
+    assert_eq!(vec!["a", "bb", "c"],
+               lines[0].iter().map(|s| s.text.as_str()).collect::<Vec<&str>>());
+    assert_eq!(Color::None, lines[0][0].fg);
+    assert_eq!(Color::Console(1), lines[0][1].fg);
+    assert_eq!(Color::None, lines[0][2].fg);
+    assert_eq!(vec![0], *filter.last_matches());
+
+    filter.set_rule_enabled(0, false);
+    filter.process_styled("abbc", |_| {});
+    assert_eq!(0, filter.last_matches().len());
+}
+
+#[test]
+fn test_highlight() {
+    let mut rule = Rule::new(r"F (\w+)").unwrap();
+    rule.set_match_colors(Colors::with_colors(Color::Console(1), Color::None, ATTR_NONE, Term::Xterm))
+        .set_post_line(DecorativeLine::new("= $1 =", None, Term::Xterm, 10));
+    let mut filter = Filter::new(Term::Xterm, vec![Rule::new("x").unwrap(), rule]);
+
+    let line = filter.highlight("x F main");
+    assert_eq!("x F main", line.text);
+    assert_eq!(vec!["x F ", "main"],
+               line.spans.iter().map(|s| s.text.as_str()).collect::<Vec<&str>>());
+    assert_eq!(Color::Console(1), line.spans[1].fg);
+    assert_eq!(0, line.pre_lines.len());
+    assert_eq!(vec![Decoration {
+                        rule: 1,
+                        text: "== main ==".to_string(),
+                        colors: None,
+                    }],
+               line.post_lines);
+    assert_eq!(vec![0, 1], line.rules);
+}
+
+#[test]
+fn test_decorative_line_captures() {
+    let mut rule = Rule::new(r"F (\w+)").unwrap();
+    rule.set_pre_line(DecorativeLine::new("= $1 =", None, Term::Xterm, 10));
+    let mut filter = Filter::new(Term::Xterm, vec![rule]);
+
+    let mut lines = vec![];
+    filter.process("x F main", |l| lines.push(l.to_string()));
+    assert_eq!(vec!["== main ==", "x F main"], lines);
+}
+
+#[test]
+fn test_stream() {
+    let mut rule = Rule::new("b").unwrap();
+    rule.set_match_colors(Colors::with_colors(Color::Console(1), Color::None, ATTR_NONE, Term::Xterm))
+        .set_stream(Stream::Stderr);
+    let mut filter = Filter::new(Term::Xterm, vec![rule]);
+    filter.set_stderr_colors(Colors::with_colors(Color::None, Color::Console(4), ATTR_NONE, Term::Xterm));
+
+    let mut lines = vec![];
+    filter.process_styled("ab", |l| {
+        if let StyledLine::Text(spans) = l {
+            lines.push(spans);
+        }
+    });
+    assert_eq!(0, filter.last_matches().len());
+    assert_eq!(Color::None, lines[0][0].bg);
+
+    filter.set_stream(Stream::Stderr);
+    lines.clear();
+    filter.process_styled("ab", |l| {
+        if let StyledLine::Text(spans) = l {
+            lines.push(spans);
+        }
+    });
+    assert_eq!(vec![0], *filter.last_matches());
+    assert_eq!(vec!["a", "b"],
+               lines[0].iter().map(|s| s.text.as_str()).collect::<Vec<&str>>());
+    assert_eq!((Color::None, Color::Console(4)), (lines[0][0].fg, lines[0][0].bg));
+    assert_eq!((Color::Console(1), Color::Console(4)), (lines[0][1].fg, lines[0][1].bg));
+}
+
+#[test]
+fn test_process_partial() {
+    let mut rule = Rule::new("b").unwrap();
+    rule.set_match_colors(Colors::with_colors(Color::Console(1), Color::None, ATTR_NONE, Term::Xterm))
+        .set_next_state("next".to_string());
+    let mut filter = Filter::new(Term::Xterm, vec![rule]);
+
+    assert_eq!("a\x1b[31mb\x1b[0m", filter.process_partial("ab"));
+    assert_eq!("", filter.state());
+
+    let mut lines = vec![];
+    filter.process("abc", |l| lines.push(l.to_string()));
+    assert_eq!(vec!["a\x1b[31mb\x1b[0mc\x1b[0m"], lines);
+    assert_eq!("next", filter.state());
+}
+
+#[test]
+fn test_explain() {
+    let mut error = Rule::new("E").unwrap();
+    error.set_name("error".to_string())
+        .set_description("Errors".to_string())
+        .set_match_colors(Colors::with_colors(Color::Console(1), Color::None, ATTR_NONE, Term::Xterm))
+        .set_next_state("error".to_string())
+        .set_stop(true);
+    let mut line = Rule::new("x").unwrap();
+    line.set_line_colors(Colors::with_colors(Color::None, Color::Console(4), ATTR_NONE, Term::Xterm));
+    let mut filter = Filter::new(Term::Xterm, vec![line, error, Rule::new("y").unwrap()]);
+    filter.set_explain(true);
+
+    filter.process("xyEy", |_| ());
+    let e = filter.explanation().unwrap().clone();
+    assert_eq!(vec![(0, vec![(0, 1)]), (1, vec![(2, 3)])], e.matches);
+    assert_eq!(Some(1), e.stopped_by);
+    assert_eq!(vec![ExplainedSpan { start: 0, end: 2, fg: None, bg: Some(0) },
+                    ExplainedSpan { start: 2, end: 3, fg: Some(1), bg: Some(0) },
+                    ExplainedSpan { start: 3, end: 4, fg: None, bg: Some(0) }],
+               e.spans);
+    assert_eq!(vec!["state: '' -> 'error'",
+                    "#0 x: 0..1",
+                    "#1 error (Errors): 2..3, stop",
+                    "colors: 0..2 fg=- bg=#0, 2..3 fg=#1 bg=#0, 3..4 fg=- bg=#0"],
+               e.describe(filter.rules()));
+}
+
+#[cfg(test)]
+fn assert_send_sync<T: Send + Sync>() {}
+
+#[test]
+fn test_set_rules_keeps_enabled() {
+    let named = |name: &str| {
+        let mut rule = Rule::new(name).unwrap();
+        rule.set_name(name.to_string());
+        rule
+    };
+    let enabled = |filter: &Filter| {
+        (0..filter.rules().len()).map(|i| filter.is_rule_enabled(i)).collect::<Vec<_>>()
+    };
+    let mut filter = Filter::new(Term::Dumb, vec![named("a"), named("b"), Rule::new("c").unwrap()]);
+    filter.set_rule_enabled(1, false);
+    filter.set_rule_enabled(2, false);
+
+    // Unnamed rules are kept by the index when the count is the same.
+    filter.set_rules(vec![named("b"), named("a"), Rule::new("d").unwrap()]);
+    assert_eq!(vec![false, true, false], enabled(&filter));
+
+    filter.set_rules(vec![named("x"), named("b"), Rule::new("c").unwrap(), named("a")]);
+    assert_eq!(vec![true, false, true, true], enabled(&filter));
+}
+
+#[test]
+fn test_send_sync() {
+    assert_send_sync::<Rule>();
+    assert_send_sync::<RuleSet>();
+    assert_send_sync::<FilterState>();
+    assert_send_sync::<Filter>();
+}
+
+#[test]
+fn test_shared_rules() {
+    use std::thread;
+
+    let mut rule = Rule::new("E").unwrap();
+    rule.set_match_colors(Colors::with_colors(Color::Console(1), Color::None, ATTR_NONE, Term::Xterm))
+        .set_next_state("error".to_string());
+    let mut filter = Filter::new(Term::Xterm, vec![rule]);
+    filter.process("E", |_| ());
+    assert_eq!("error", filter.state());
+
+    // Forks share the rules, but not the state.
+    let threads: Vec<_> = (0..2)
+        .map(|_| {
+            let mut fork = filter.fork();
+            thread::spawn(move || {
+                assert_eq!("", fork.state());
+                let mut out = vec![];
+                fork.process_to("xE", &mut out).unwrap();
+                String::from_utf8(out).unwrap()
+            })
+        })
+        .collect();
+    for t in threads {
+        assert_eq!("x\x1b[31mE\x1b[0m\n", t.join().unwrap());
     }
+    assert!(Arc::ptr_eq(filter.rule_set(), filter.fork().rule_set()));
 }
@@ -0,0 +1,181 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+const POLL_INTERVAL_MS: u64 = 200;
+const TAIL_CHUNK_SIZE: u64 = 8192;
+
+/// Reads a file like "tail -F": once it reaches the end, it waits for more data
+/// to be appended.  If the file is truncated, it'll start over from the
+/// beginning, and if the file is replaced (i.e. rotated) with a new one,
+/// it'll switch to the new file.
+#[derive(Debug)]
+pub struct FollowReader {
+    path: PathBuf,
+    file: File,
+    dev: u64,
+    inode: u64,
+    pos: u64,
+}
+
+/// Find the start offset of the last `lines` lines.
+pub fn find_tail_start<T: Read + Seek>(file: &mut T, lines: usize) -> io::Result<u64> {
+    let len = file.seek(SeekFrom::End(0))?;
+    if lines == 0 {
+        return Ok(len);
+    }
+    let mut buf = vec![0u8; TAIL_CHUNK_SIZE as usize];
+    let mut end = len;
+    let mut found = 0;
+    let mut first = true;
+    while end > 0 {
+        let start = if end > TAIL_CHUNK_SIZE { end - TAIL_CHUNK_SIZE } else { 0 };
+        let size = (end - start) as usize;
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut buf[..size])?;
+
+        for i in (0..size).rev() {
+            if buf[i] != b'\n' {
+                continue;
+            }
+            // The LF at the very end doesn't start a new line.
+            if first && start + (i as u64) == len - 1 {
+                continue;
+            }
+            found += 1;
+            if found == lines {
+                return Ok(start + (i as u64) + 1);
+            }
+        }
+        first = false;
+        end = start;
+    }
+    Ok(0)
+}
+
+#[test]
+fn test_find_tail_start() {
+    let mut c = io::Cursor::new(b"a\nbb\nccc\n".to_vec());
+    assert_eq!(9, find_tail_start(&mut c, 0).unwrap());
+    assert_eq!(5, find_tail_start(&mut c, 1).unwrap());
+    assert_eq!(2, find_tail_start(&mut c, 2).unwrap());
+    assert_eq!(0, find_tail_start(&mut c, 3).unwrap());
+    assert_eq!(0, find_tail_start(&mut c, 10).unwrap());
+
+    // No LF at the end.
+    let mut c = io::Cursor::new(b"a\nbb\nccc".to_vec());
+    assert_eq!(5, find_tail_start(&mut c, 1).unwrap());
+
+    let mut c = io::Cursor::new(vec![]);
+    assert_eq!(0, find_tail_start(&mut c, 1).unwrap());
+}
+
+impl FollowReader {
+    /// Open a file, starting from the last `tail_lines` lines.
+    pub fn open(path: &str, tail_lines: usize) -> io::Result<FollowReader> {
+        let mut file = File::open(path)?;
+        let meta = file.metadata()?;
+        let pos = find_tail_start(&mut file, tail_lines)?;
+        file.seek(SeekFrom::Start(pos))?;
+
+        Ok(FollowReader {
+            path: PathBuf::from(path),
+            file: file,
+            dev: meta.dev(),
+            inode: meta.ino(),
+            pos: pos,
+        })
+    }
+
+    /// Called at the end of the file; switch to the new file if the file has
+    /// been rotated, or rewind if it has been truncated.
+    fn check_file(&mut self) -> io::Result<()> {
+        let meta = match fs::metadata(&self.path) {
+            Ok(m) => m,
+            Err(_) => return Ok(()), // Being rotated; wait for the new file.
+        };
+        if meta.dev() != self.dev || meta.ino() != self.inode {
+            let file = match File::open(&self.path) {
+                Ok(f) => f,
+                Err(_) => return Ok(()),
+            };
+            debug!("{} has been rotated", self.path.display());
+            self.file = file;
+            self.dev = meta.dev();
+            self.inode = meta.ino();
+            self.pos = 0;
+        } else if meta.len() < self.pos {
+            debug!("{} has been truncated", self.path.display());
+            self.file.seek(SeekFrom::Start(0))?;
+            self.pos = 0;
+        }
+        Ok(())
+    }
+}
+
+impl Read for FollowReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.file.read(buf)?;
+            if n > 0 {
+                self.pos += n as u64;
+                return Ok(n);
+            }
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+            self.check_file()?;
+        }
+    }
+}
+
+#[cfg(test)]
+fn temp_path(name: &str) -> PathBuf {
+    ::std::env::temp_dir().join(format!("hl-test-{}-{}", name, ::std::process::id()))
+}
+
+#[cfg(test)]
+fn read_string(reader: &mut FollowReader, len: usize) -> String {
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf).unwrap();
+    String::from_utf8(buf).unwrap()
+}
+
+#[test]
+fn test_follow_rotation() {
+    use std::io::Write;
+
+    let path = temp_path("follow-rotation");
+    let rotated = path.with_extension("1");
+    File::create(&path).unwrap().write_all(b"a\nb\n").unwrap();
+    let mut reader = FollowReader::open(path.to_str().unwrap(), 1).unwrap();
+    assert_eq!("b\n", read_string(&mut reader, 2));
+
+    // What's written before the rotation is read first.
+    let mut old = fs::OpenOptions::new().append(true).open(&path).unwrap();
+    fs::rename(&path, &rotated).unwrap();
+    old.write_all(b"c\n").unwrap();
+    File::create(&path).unwrap().write_all(b"d\n").unwrap();
+    assert_eq!("c\nd\n", read_string(&mut reader, 4));
+
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(&rotated).unwrap();
+}
+
+#[test]
+fn test_follow_truncation() {
+    use std::io::Write;
+
+    let path = temp_path("follow-truncation");
+    File::create(&path).unwrap().write_all(b"a\nbb\n").unwrap();
+    let mut reader = FollowReader::open(path.to_str().unwrap(), 10).unwrap();
+    assert_eq!("a\nbb\n", read_string(&mut reader, 5));
+
+    File::create(&path).unwrap().write_all(b"c\n").unwrap();
+    assert_eq!("c\n", read_string(&mut reader, 2));
+
+    fs::remove_file(&path).unwrap();
+}
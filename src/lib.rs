@@ -5,61 +5,213 @@ extern crate log;
 #[macro_use]
 extern crate lazy_static;
 extern crate env_logger;
+extern crate libc;
+extern crate regex;
+#[cfg(feature = "pcre")]
 extern crate pcre;
+#[cfg(feature = "fancy-regex")]
+extern crate fancy_regex;
 extern crate toml;
+extern crate yaml_rust;
+extern crate serde_json;
+extern crate unicode_width;
+#[cfg(feature = "gzip")]
+extern crate flate2;
+#[cfg(feature = "xz")]
+extern crate xz2;
+#[cfg(feature = "zstd")]
+extern crate zstd;
+#[cfg(feature = "bzip2")]
+extern crate bzip2;
 
 use std::env;
 use std::error::Error;
 use std::fmt;
 use std::io;
 
+pub mod matcher;
 pub mod rule;
 pub mod rule_parser;
+pub mod rule_format;
 pub mod term_color;
 pub mod filter;
+pub mod svg;
+pub mod render;
+pub mod asciicast;
+pub mod tty;
+pub mod pager;
+pub mod follow;
+pub mod signal;
+pub mod watcher;
+pub mod tui;
+pub mod merge;
+pub mod decompress;
+pub mod command;
+pub mod pty;
+pub mod lint;
+pub mod rule_test;
+pub mod convert;
 
 const CSI: &'static str = "\x1b[";
 const CSI_END: &'static str = "m";
 const CSI_RESET: &'static str = "\x1b[0m";
 const NO_CSI: &'static str = "";
 
+/// The errors in rules and rule files.
 #[derive(Debug)]
 pub enum RuleError {
-    ParseError(String), // IoError(String, std::io::Error),
+    /// A rule file can't be read.
+    Io { file: String, source: io::Error },
+    /// A rule file isn't valid TOML, or YAML or JSON, which are read the same
+    /// way.  The line and the column are from 1, or 0 if unknown.
+    Syntax {
+        file: String,
+        format: rule_format::Format,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    /// A pattern doesn't compile.  The offset is in the pattern.
+    Regex {
+        pattern: String,
+        offset: usize,
+        message: String,
+    },
+    /// A color spec can't be parsed.
+    Color { spec: String },
+    /// Rules that don't fit the schema, e.g. with an unknown key.
+    Schema(String),
+    /// More than one error, e.g. all the syntax errors in a rule file.
+    Several(Vec<RuleError>),
+    /// An error in a rule file that was loaded.  If the line isn't known, e.g.
+    /// in JSON, the table tells where it is, e.g. "rule #2".
+    Include {
+        file: String,
+        line: usize,
+        column: usize,
+        table: String,
+        source: Box<RuleError>,
+    },
 }
 
 impl RuleError {
     pub fn new(description: &str) -> RuleError {
-        RuleError::ParseError(description.to_string())
+        RuleError::Schema(description.to_string())
+    }
+
+    /// An error somewhere in a rule file.
+    pub fn in_file(file: &str, source: RuleError) -> RuleError {
+        RuleError::at(file, 0, 0, "", source)
     }
-}
 
-// impl From<io::Error> for RuleError {
-//     fn from(err: io::Error) -> RuleError {
-//         RuleError::IoError("I/O error".to_string(), err)
-//     }
-// }
+    /// An error at a place in a rule file.
+    pub fn at(file: &str, line: usize, column: usize, table: &str, source: RuleError) -> RuleError {
+        RuleError::Include {
+            file: file.to_string(),
+            line: line,
+            column: column,
+            table: table.to_string(),
+            source: Box::new(source),
+        }
+    }
+}
 
 impl Error for RuleError {
     fn description(&self) -> &str {
-        match self {
-            &RuleError::ParseError(ref desc) => desc,
-            // &RuleError::IoError(ref desc, ref err) => err.description(),
+        match *self {
+            RuleError::Io { .. } => "unable to read rule file",
+            RuleError::Syntax { .. } => "invalid rule file",
+            RuleError::Regex { .. } => "invalid regex pattern",
+            RuleError::Color { .. } => "invalid color",
+            RuleError::Schema(ref desc) => desc,
+            RuleError::Several(_) => "several errors in rule file",
+            RuleError::Include { .. } => "error in rule file",
         }
     }
 
     fn cause(&self) -> Option<&Error> {
-        match self {
-            &RuleError::ParseError(ref desc) => None,
-            // &RuleError::IoError(ref desc, ref err) => Some(err),
+        match *self {
+            RuleError::Io { ref source, .. } => Some(source),
+            RuleError::Include { ref source, .. } => Some(&**source),
+            _ => None,
         }
     }
 }
 
 impl fmt::Display for RuleError {
     fn fmt(self: &RuleError, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.description())
+        match *self {
+            RuleError::Io { ref file, .. } => write!(f, "Unable to read file '{}'", file),
+            RuleError::Syntax { ref file, format, line, column, ref message } => {
+                write!(f,
+                       "Invalid {}: {}:{}:{}: {}",
+                       format.name(),
+                       file,
+                       line,
+                       column,
+                       message)
+            }
+            RuleError::Regex { ref pattern, offset, ref message } => {
+                write!(f,
+                       "Invalid regex pattern: {}: {} at offset {}",
+                       pattern,
+                       message,
+                       offset)
+            }
+            RuleError::Color { ref spec } => write!(f, "Invalid color: {}", spec),
+            RuleError::Schema(ref desc) => write!(f, "{}", desc),
+            RuleError::Several(ref errors) => {
+                for (i, e) in errors.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "\n")?;
+                    }
+                    write!(f, "{}", error_chain(e))?;
+                }
+                Ok(())
+            }
+            RuleError::Include { ref file, line, column, ref table, .. } => {
+                write!(f, "{}", file)?;
+                if line > 0 {
+                    write!(f, ":{}", line)?;
+                }
+                if line > 0 && column > 0 {
+                    write!(f, ":{}", column)?;
+                }
+                if line == 0 && table.len() > 0 {
+                    write!(f, ": {}", table)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The message of an error, followed by the messages of its causes, e.g.
+/// "x.toml:7:9: Invalid color: bgren".
+pub fn error_chain(err: &Error) -> String {
+    let mut ret = err.to_string();
+    let mut cause = err.cause();
+    while let Some(e) = cause {
+        ret.push_str(": ");
+        ret.push_str(&e.to_string());
+        cause = e.cause();
     }
+    ret
+}
+
+#[test]
+fn test_rule_error() {
+    let color = RuleError::Color { spec: "bgren".to_string() };
+    let e = RuleError::at("x.toml", 7, 9, "rule #2", color);
+    assert_eq!("x.toml:7:9", e.to_string());
+    assert_eq!("x.toml:7:9: Invalid color: bgren", error_chain(&e));
+    let e = RuleError::at("x.json", 0, 0, "rule #2", RuleError::new("Missing key 'pattern'."));
+    assert_eq!("x.json: rule #2: Missing key 'pattern'.", error_chain(&e));
+
+    let io = io::Error::new(io::ErrorKind::NotFound, "not found");
+    let e = RuleError::in_file("x.toml", RuleError::Io { file: "y.toml".to_string(), source: io });
+    assert_eq!("x.toml: Unable to read file 'y.toml': not found", error_chain(&e));
+    assert!(e.cause().and_then(|c| c.cause()).is_some());
 }
 
 #[derive(Debug,Copy,Clone,PartialEq,Eq)]
@@ -0,0 +1,369 @@
+use std::cmp;
+use std::fmt;
+
+use super::*;
+use rule::*;
+use rule_parser::*;
+use rule_format::Format;
+
+/// Patterns that match any non-empty line.  A rule only applies when its match
+/// isn't empty, so any line it applies to is matched by these too.
+const MATCH_ALL: [&'static str; 9] = [".", ".*", ".+", "^.*", "^.+", ".*$", ".+$", "^.*$", "^.+$"];
+
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A problem found in a rule file.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Diagnostic {
+    pub file: String,
+    /// Where the problem is.  The line is 0 if it's about the whole file.
+    pub location: Location,
+    pub severity: Severity,
+    pub message: String,
+    /// A suggestion to fix it.
+    pub help: Option<String>,
+    /// More about where it is, when the location isn't known.
+    pub note: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(file: &str, location: Location, severity: Severity, message: String) -> Diagnostic {
+        Diagnostic {
+            file: file.to_string(),
+            location: location,
+            severity: severity,
+            message: message,
+            help: None,
+            note: None,
+        }
+    }
+
+    /// Where the problem is, as "file:line:column".
+    fn position(&self) -> String {
+        match (self.location.line, self.location.column) {
+            (0, _) => self.file.clone(),
+            (line, 0) => format!("{}:{}", self.file, line),
+            (line, column) => format!("{}:{}:{}", self.file, line, column),
+        }
+    }
+
+    /// Render the problem like rustc does, with the line and a caret under
+    /// the offending text.
+    pub fn render(&self) -> String {
+        let mut ret = format!("{}: {}\n", self.severity, self.message);
+        let gutter = " ".repeat(self.location.line.to_string().len());
+        ret.push_str(&format!("{}--> {}\n", gutter, self.position()));
+        if self.location.line > 0 && self.location.text.len() > 0 {
+            ret.push_str(&format!("{} |\n", gutter));
+            ret.push_str(&format!("{} | {}\n", self.location.line, self.location.text));
+            if self.location.column > 0 {
+                ret.push_str(&format!("{} | {}{}\n",
+                                      gutter,
+                                      " ".repeat(self.location.column - 1),
+                                      "^".repeat(cmp::max(self.location.len, 1))));
+            }
+        }
+        if let Some(ref note) = self.note {
+            ret.push_str(&format!("{} = note: {}\n", gutter, note));
+        }
+        if let Some(ref help) = self.help {
+            ret.push_str(&format!("{} = help: {}\n", gutter, help));
+        }
+        ret
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.position(), self.severity, self.message)
+    }
+}
+
+/// The text a pattern matches if it has no special characters, e.g. "main\.c".
+fn literal(pattern: &str) -> Option<String> {
+    let mut ret = String::new();
+    let mut chars = pattern.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => {
+                match chars.next() {
+                    Some(c) if !c.is_alphanumeric() => ret.push(c),
+                    _ => return None,
+                }
+            }
+            '.' | '^' | '$' | '|' | '?' | '*' | '+' | '(' | ')' | '[' | ']' | '{' | '}' => {
+                return None
+            }
+            '!' if ret.len() == 0 => return None, // Negated.
+            c => ret.push(c),
+        }
+    }
+    Some(ret)
+}
+
+#[test]
+fn test_literal() {
+    assert_eq!(Some("main.c".to_string()), literal(r"main\.c"));
+    assert_eq!(Some("a b".to_string()), literal("a b"));
+    assert_eq!(None, literal("a.c"));
+    assert_eq!(None, literal(r"\d"));
+    assert_eq!(None, literal("!abc"));
+}
+
+/// Whether `earlier` is a `stop` rule that matches every line `later` would
+/// highlight, so that `later` never gets a chance.
+fn shadows(earlier: &Rule, later: &Rule) -> bool {
+    if !earlier.stop() || earlier.conditional() {
+        return false;
+    }
+    if earlier.stream().is_some() && earlier.stream() != later.stream() {
+        return false;
+    }
+    if earlier.states().len() > 0 &&
+       (later.states().len() == 0 ||
+        !later.states().iter().all(|s| earlier.states().contains(s))) {
+        return false;
+    }
+    let (e, l) = (earlier.pattern(), later.pattern());
+    let (eo, lo) = (earlier.options(), later.options());
+    if e == l && eo == lo {
+        return true;
+    }
+    // A whole word only matches where the later rule does when they're the
+    // same.
+    if eo.whole_word {
+        return false;
+    }
+    if !eo.literal && MATCH_ALL.contains(&e.as_str()) {
+        return true;
+    }
+    // A later rule that ignores case matches more than an earlier one that
+    // doesn't.
+    if lo.ignore_case && !eo.ignore_case {
+        return false;
+    }
+    let text = |pattern: &String, literal_pattern: bool| {
+        if !literal_pattern {
+            literal(pattern)
+        } else if pattern.starts_with('!') {
+            None // Negated.
+        } else {
+            Some(pattern.clone())
+        }
+    };
+    match (text(e, eo.literal), text(l, lo.literal)) {
+        (Some(ref e), Some(ref l)) if eo.ignore_case => {
+            e.len() > 0 && l.to_lowercase().contains(&e.to_lowercase())
+        }
+        (Some(e), Some(l)) => e.len() > 0 && l.contains(&e),
+        _ => false,
+    }
+}
+
+/// The errors found while reading the rules of a file.
+pub fn errors(filename: &str, entries: &[RuleEntry]) -> Vec<Diagnostic> {
+    let mut ret = vec![];
+    for (index, entry) in entries.iter().enumerate() {
+        for problem in &entry.errors {
+            let mut d = Diagnostic::new(filename,
+                                        problem.location.clone(),
+                                        Severity::Error,
+                                        problem.error.to_string());
+            d.help = problem.help.clone();
+            if problem.location.line == 0 {
+                d.note = Some(match entry.rule {
+                    Some(ref rule) => format!("in rule #{} '{}'", index + 1, rule.label()),
+                    None => format!("in rule #{}", index + 1),
+                });
+            }
+            ret.push(d);
+        }
+    }
+    ret
+}
+
+/// Find the mistakes in the rules of a file, besides the errors found while
+/// reading it.
+pub fn check_entries(filename: &str, entries: &[RuleEntry]) -> Vec<Diagnostic> {
+    let mut ret = errors(filename, entries);
+    let diagnostic = |line: usize, severity: Severity, message: String| {
+        let location = Location { line: line, ..Location::default() };
+        Diagnostic::new(filename, location, severity, message)
+    };
+
+    let rules: Vec<(usize, &Rule)> = entries.iter()
+        .filter_map(|e| e.rule.as_ref().map(|r| (e.line, r)))
+        .collect();
+
+    // States.  The initial state is "".
+    let mut reported: Vec<&String> = vec![];
+    for &(line, rule) in &rules {
+        for state in rule.states() {
+            if state.len() == 0 || reported.contains(&state) {
+                continue;
+            }
+            if !rules.iter().any(|&(_, r)| r.next_state() == Some(state)) {
+                ret.push(diagnostic(line,
+                                    Severity::Warning,
+                                    format!("State '{}' is used, but no rule enters it.", state)));
+                reported.push(state);
+            }
+        }
+        if let Some(state) = rule.next_state() {
+            if state.len() == 0 || reported.contains(&state) {
+                continue;
+            }
+            if !rules.iter().any(|&(_, r)| r.states().contains(state)) {
+                ret.push(diagnostic(line,
+                                    Severity::Warning,
+                                    format!("State '{}' is entered, but no rule uses it.", state)));
+                reported.push(state);
+            }
+        }
+    }
+
+    // Shadowed rules.
+    for (i, &(line, rule)) in rules.iter().enumerate() {
+        if let Some(&(earlier_line, _)) = rules[..i].iter().find(|&&(_, r)| shadows(r, rule)) {
+            let message = format!("Rule '{}' never applies; the 'stop' rule at line {} \
+                                   matches first.",
+                                  rule.pattern(),
+                                  earlier_line);
+            ret.push(diagnostic(line, Severity::Warning, message));
+        }
+    }
+
+    ret.sort_by_key(|d| (d.location.line, d.location.column));
+    ret
+}
+
+/// The errors that keep a whole rule file from being read, e.g. all the
+/// syntax errors in it.
+pub fn file_errors(filename: &str, e: &RuleError) -> Vec<Diagnostic> {
+    // Syntax errors have a position, but no source line.  The position is
+    // shown after the message, so it's left out of it.
+    let (location, message) = match *e {
+        RuleError::Syntax { format, line, column, ref message, .. } => {
+            (Location { line: line, column: column, ..Location::default() },
+             format!("Invalid {}: {}", format.name(), message))
+        }
+        RuleError::Several(ref errors) => {
+            return errors.iter().flat_map(|e| file_errors(filename, e)).collect();
+        }
+        _ => (Location::default(), error_chain(e)),
+    };
+    vec![Diagnostic::new(filename, location, Severity::Error, message)]
+}
+
+/// Check a rule file in the given format.
+pub fn check_file(parser: &RuleParser, filename: &str, format: Format) -> Vec<Diagnostic> {
+    match parser.read_entries(filename, format) {
+        Ok(entries) => check_entries(filename, &entries),
+        Err(e) => file_errors(filename, &e),
+    }
+}
+
+#[cfg(test)]
+fn entry(line: usize, pattern: &str) -> RuleEntry {
+    RuleEntry {
+        rule: Some(Rule::new(pattern).unwrap()),
+        line: line,
+        errors: vec![],
+    }
+}
+
+#[test]
+fn test_check_entries() {
+    let mut entries = vec![entry(1, "Exception"),
+                           entry(2, "at "),
+                           entry(3, "ERROR"),
+                           entry(4, "FATAL ERROR"),
+                           entry(5, r"\d+")];
+    entries[0].rule.as_mut().unwrap().set_next_state("exception".to_string());
+    entries[1].rule.as_mut().unwrap().set_states(vec!["exceptoin".to_string()]);
+    entries[2].rule.as_mut().unwrap().set_stop(true);
+    entries[4].errors.push(Problem {
+        error: RuleError::new("Invalid color: bgren"),
+        location: Location::of_value(7, "color = 'bgren'", true),
+        help: Some("did you mean 'bgreen'?".to_string()),
+    });
+
+    let messages: Vec<String> = check_entries("x.toml", &entries)
+        .iter()
+        .map(|d| d.to_string())
+        .collect();
+    assert_eq!(vec!["x.toml:1: warning: State 'exception' is entered, but no rule uses it.",
+                    "x.toml:2: warning: State 'exceptoin' is used, but no rule enters it.",
+                    "x.toml:4: warning: Rule 'FATAL ERROR' never applies; the 'stop' rule at \
+                     line 3 matches first.",
+                    "x.toml:7:9: error: Invalid color: bgren"],
+               messages);
+    assert_eq!("error: Invalid color: bgren\n \
+                --> x.toml:7:9\n  \
+                |\n\
+                7 | color = 'bgren'\n  \
+                |         ^^^^^^^\n  \
+                = help: did you mean 'bgreen'?\n",
+               errors("x.toml", &entries)[0].render());
+
+    // A stop rule in a state only shadows rules in the same state.
+    entries[2].rule.as_mut().unwrap().set_states(vec!["exception".to_string()]);
+    assert!(!shadows(entries[2].rule.as_ref().unwrap(), entries[3].rule.as_ref().unwrap()));
+    entries[3].rule.as_mut().unwrap().set_states(vec!["exception".to_string()]);
+    assert!(shadows(entries[2].rule.as_ref().unwrap(), entries[3].rule.as_ref().unwrap()));
+
+}
+
+#[test]
+fn test_file_errors() {
+    let syntax = |line: usize, message: &str| {
+        RuleError::Syntax {
+            file: "x.toml".to_string(),
+            format: Format::Toml,
+            line: line,
+            column: 1,
+            message: message.to_string(),
+        }
+    };
+    let e = RuleError::Several(vec![syntax(3, "a"), syntax(6, "b")]);
+    let diagnostics = file_errors("x.toml", &e);
+    assert_eq!(2, diagnostics.len());
+    assert_eq!(3, diagnostics[0].location.line);
+    assert_eq!(6, diagnostics[1].location.line);
+    assert_eq!("x.toml:3:1: error: Invalid TOML: a", diagnostics[0].to_string());
+    assert_eq!(1, file_errors("x.toml", &RuleError::new("x")).len());
+}
+
+#[test]
+fn test_shadows_options() {
+    let rule = |pattern: &str, options: matcher::Options| {
+        let mut rule = Rule::with_options(pattern, options).unwrap();
+        rule.set_stop(true);
+        rule
+    };
+    let none = matcher::Options::default();
+    let ignore_case = matcher::Options { ignore_case: true, ..none };
+    let whole_word = matcher::Options { whole_word: true, ..none };
+    let literal = matcher::Options { literal: true, ..none };
+
+    assert!(shadows(&rule("error", ignore_case), &rule("FATAL ERROR", none)));
+    assert!(!shadows(&rule("ERROR", none), &rule("FATAL ERROR", ignore_case)));
+    assert!(!shadows(&rule("error", whole_word), &rule("errors", none)));
+    assert!(shadows(&rule("error", whole_word), &rule("error", whole_word)));
+    assert!(shadows(&rule("a.b", literal), &rule("xa.bx", literal)));
+    assert!(shadows(&rule("a.b", literal), &rule(r"xa\.b", none)));
+    assert!(!shadows(&rule(".*", literal), &rule("x", none)));
+}
@@ -1,22 +1,44 @@
 #[macro_use]
 extern crate clap;
 extern crate env_logger;
-extern crate fileinput;
 extern crate highlighter;
+extern crate libc;
 #[macro_use]
 extern crate log;
+extern crate unicode_width;
 
-use clap::{App, Arg, Shell, SubCommand};
-use fileinput::FileInput;
+use clap::{App, Arg, ArgMatches, Shell, SubCommand};
 use highlighter::*;
 use highlighter::filter::*;
 use highlighter::rule::*;
 use highlighter::rule_parser::*;
 use highlighter::term_color::*;
+use highlighter::svg::*;
+use highlighter::asciicast::*;
+use highlighter::pager::*;
+use highlighter::tty;
+use highlighter::follow::*;
+use highlighter::merge::*;
+use highlighter::decompress;
+use highlighter::command::{self, Command};
+use highlighter::pty::*;
+use highlighter::lint;
+use highlighter::convert;
+use highlighter::rule_format::Format;
+use highlighter::matcher::Options;
+use highlighter::signal;
+use highlighter::watcher::*;
+use highlighter::tui;
+use highlighter::tui::{TermBackend, Viewer};
+use unicode_width::UnicodeWidthStr;
 use std::env;
 use std::io::BufReader;
 use std::io::Read;
+use std::io::prelude::*;
 use std::io;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 
 fn error(message: &String) {
     writeln!(&mut std::io::stderr(),
@@ -31,7 +53,54 @@ const FLAG_SIMPLE_RULE: &'static str = "pattern";
 const FLAG_RULEFILE: &'static str = "rulefile";
 const FLAG_LEGACY_RULEFILE: &'static str = "legacyfile";
 const FLAG_WIDTH: &'static str = "width";
+const FLAG_OUTPUT_FORMAT: &'static str = "output-format";
+const FLAG_PAGING: &'static str = "paging";
+const FLAG_FOLLOW: &'static str = "follow";
+const FLAG_TAIL: &'static str = "tail";
+const FLAG_TUI: &'static str = "tui";
+const FLAG_WITH_FILENAME: &'static str = "with-filename";
+const FLAG_LINE_NUMBER: &'static str = "line-number";
+const FLAG_FILENAME_COLOR: &'static str = "filename-color";
+const FLAG_LINE_NUMBER_COLOR: &'static str = "line-number-color";
+const FLAG_FILE_HEADER: &'static str = "file-header";
+const FLAG_FILE_HEADER_COLOR: &'static str = "file-header-color";
+const FLAG_RESET_STATE: &'static str = "reset-state";
+const FLAG_MERGE: &'static str = "merge";
+const FLAG_TIME_FORMAT: &'static str = "time-format";
+const FLAG_BOOT_TIME: &'static str = "boot-time";
+const FLAG_STDERR_MARKER: &'static str = "stderr-marker";
+const FLAG_STDERR_COLOR: &'static str = "stderr-color";
+const FLAG_COMMAND: &'static str = "command";
+const FLAG_PTY: &'static str = "pty";
+const FLAG_EXPLAIN: &'static str = "explain";
 const FLAG_FILES: &'static str = "files";
+const FLAG_TO: &'static str = "to";
+const FLAG_FORMAT: &'static str = "format";
+const FLAG_IGNORE_CASE: &'static str = "ignore-case";
+const FLAG_WHOLE_WORD: &'static str = "whole-word";
+const FLAG_LITERAL: &'static str = "literal";
+
+const SUBCOMMAND_CHECK: &'static str = "check";
+const SUBCOMMAND_TEST: &'static str = "test";
+const SUBCOMMAND_CONVERT: &'static str = "convert";
+
+/// How many lines --follow starts from, without --tail.
+const DEFAULT_TAIL: usize = 10;
+
+/// How often to check the rule files and the signals while the input is quiet.
+const IDLE_INTERVAL_MS: u64 = 100;
+
+/// How many lines the reader thread may read ahead of the output.
+const READ_AHEAD_LINES: usize = 256;
+
+/// Width for pre/post lines when it's not known.
+const DEFAULT_WIDTH: usize = 80;
+
+/// Terminal height written in asciicast headers.
+const ASCIICAST_HEIGHT: usize = 24;
+
+/// How the standard input is shown in file name prefixes and headers.
+const STDIN_NAME: &'static str = "(standard input)";
 
 fn get_app<'a, 'b>() -> App<'a, 'b> {
     App::new("Hilighter")
@@ -44,16 +113,38 @@ fn get_app<'a, 'b>() -> App<'a, 'b> {
             .takes_value(true)
             .multiple(true)
             .number_of_values(1)
-            .help("Add a simple rule: RE=(colors)(@colors)\n\
+            .help("Add a simple rule: RE=(colors)(@colors)(;flags)\n\
                 e.g. '\\d+=500/222@/cyan'\n\
-                Colors can be omitted to apply default colors."))
+                Colors can be omitted to apply default colors.\n\
+                Flags: i (ignore case), w (whole word), l (literal),\n\
+                u (Unicode) and a (ASCII)"))
+        .arg(Arg::with_name(FLAG_IGNORE_CASE)
+            .short("i")
+            .long(FLAG_IGNORE_CASE)
+            .help("Ignore case in the rules without ignore_case"))
+        .arg(Arg::with_name(FLAG_WHOLE_WORD)
+            .short("W")
+            .long(FLAG_WHOLE_WORD)
+            .help("Only match whole words in the rules without whole_word\n\
+                (-W, as -w is --width)"))
+        .arg(Arg::with_name(FLAG_LITERAL)
+            .short("Q")
+            .long(FLAG_LITERAL)
+            .help("Take patterns as plain strings in the rules without literal;\n\
+                a leading '!' still negates them (-Q, as -F is --follow)"))
         .arg(Arg::with_name(FLAG_RULEFILE)
             .short("r")
             .long(FLAG_RULEFILE)
             .takes_value(true)
             .multiple(true)
             .number_of_values(1)
-            .help("Specify TOML rule file"))
+            .help("Specify TOML, YAML or JSON rule file"))
+        .arg(Arg::with_name(FLAG_FORMAT)
+            .long(FLAG_FORMAT)
+            .takes_value(true)
+            .possible_values(&["toml", "yaml", "json"])
+            .help("Format of the -r rule files [default: by the extension, \n\
+                *.yaml, *.yml and *.json, or TOML]"))
         .arg(Arg::with_name(FLAG_LEGACY_RULEFILE)
             .short("c")
             .long(FLAG_LEGACY_RULEFILE)
@@ -71,80 +162,1003 @@ fn get_app<'a, 'b>() -> App<'a, 'b> {
         .arg(Arg::with_name(FLAG_WIDTH)
             .short("w")
             .long(FLAG_WIDTH)
-            .default_value("80")
             .min_values(1)
             .takes_value(true)
-            .help("Set width for pre/post lines"))
+            .help("Set width for pre/post lines\n\
+                (default: terminal width, or 80)"))
+        .arg(Arg::with_name(FLAG_OUTPUT_FORMAT)
+            .short("o")
+            .long(FLAG_OUTPUT_FORMAT)
+            .takes_value(true)
+            .possible_values(&["ansi", "svg", "asciicast"])
+            .default_value("ansi")
+            .help("Set output format"))
+        .arg(Arg::with_name(FLAG_PAGING)
+            .long(FLAG_PAGING)
+            .takes_value(true)
+            .possible_values(&["auto", "always", "never"])
+            .default_value("auto")
+            .help("Send output to $PAGER (default: less -R)\n\
+                auto: when writing finite input to a terminal"))
+        .arg(Arg::with_name(FLAG_FOLLOW)
+            .short("F")
+            .long(FLAG_FOLLOW)
+            .help("Keep reading the file as it grows, like tail -F.\n\
+                Handles truncated and rotated files."))
+        .arg(Arg::with_name(FLAG_TAIL)
+            .long(FLAG_TAIL)
+            .takes_value(true)
+            .requires(FLAG_FOLLOW)
+            .help("With --follow, start from the last N lines (default: 10)"))
+        .arg(Arg::with_name(FLAG_TUI)
+            .long(FLAG_TUI)
+            .help("Show the output in a full screen viewer"))
+        .arg(Arg::with_name(FLAG_WITH_FILENAME)
+            .short("H")
+            .long(FLAG_WITH_FILENAME)
+            .help("Prefix each line with the file name"))
+        .arg(Arg::with_name(FLAG_LINE_NUMBER)
+            .short("n")
+            .long(FLAG_LINE_NUMBER)
+            .help("Prefix each line with the line number in the file"))
+        .arg(Arg::with_name(FLAG_FILENAME_COLOR)
+            .long(FLAG_FILENAME_COLOR)
+            .takes_value(true)
+            .default_value("magenta")
+            .help("Set colors for file names"))
+        .arg(Arg::with_name(FLAG_LINE_NUMBER_COLOR)
+            .long(FLAG_LINE_NUMBER_COLOR)
+            .takes_value(true)
+            .default_value("green")
+            .help("Set colors for line numbers"))
+        .arg(Arg::with_name(FLAG_FILE_HEADER)
+            .long(FLAG_FILE_HEADER)
+            .takes_value(true)
+            .help("Print a line before each file, like pre_line.\n\
+                $0 is replaced with the file name.\n\
+                e.g. '── $0 ──'"))
+        .arg(Arg::with_name(FLAG_FILE_HEADER_COLOR)
+            .long(FLAG_FILE_HEADER_COLOR)
+            .takes_value(true)
+            .default_value("bwhite/blue")
+            .help("Set colors for file headers"))
+        .arg(Arg::with_name(FLAG_EXPLAIN)
+            .long(FLAG_EXPLAIN)
+            .conflicts_with(FLAG_TUI)
+            .help("Show the matched rules, the states and the colors of each line on stderr"))
+        .arg(Arg::with_name(FLAG_RESET_STATE)
+            .long(FLAG_RESET_STATE)
+            .help("Reset the rule state at the start of each file"))
+        .arg(Arg::with_name(FLAG_MERGE)
+            .long(FLAG_MERGE)
+            .conflicts_with_all(&[FLAG_FOLLOW, FLAG_TUI])
+            .help("Read the input files concurrently, and interleave\n\
+                their lines by timestamp"))
+        .arg(Arg::with_name(FLAG_TIME_FORMAT)
+            .long(FLAG_TIME_FORMAT)
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .requires(FLAG_MERGE)
+            .help("Set the timestamp format of each input for --merge,\n\
+                in order; the last one applies to the rest.\n\
+                auto (default), iso, logcat, syslog, dmesg, epoch,\n\
+                or a format like '%Y/%m/%d %H:%M:%S.%f'.\n\
+                Times without an offset are local times"))
+        .arg(Arg::with_name(FLAG_BOOT_TIME)
+            .long(FLAG_BOOT_TIME)
+            .takes_value(true)
+            .requires(FLAG_MERGE)
+            .help("Set the boot time for the dmesg timestamps with --merge,\n\
+                in seconds since the epoch [default: btime in /proc/stat]"))
+        .arg(Arg::with_name(FLAG_STDERR_MARKER)
+            .long(FLAG_STDERR_MARKER)
+            .takes_value(true)
+            .help("Prefix the stderr lines of the command with this"))
+        .arg(Arg::with_name(FLAG_STDERR_COLOR)
+            .long(FLAG_STDERR_COLOR)
+            .takes_value(true)
+            .help("Set the base colors for the stderr lines of the command"))
+        .arg(Arg::with_name(FLAG_PTY)
+            .long(FLAG_PTY)
+            .requires(FLAG_COMMAND)
+            .help("Run the command on a pseudo-terminal, passing through the input"))
         .arg(Arg::with_name(FLAG_FILES)
             .index(1)
             .required(false)
             .multiple(true)
             .help("Input files"))
+        .arg(Arg::with_name(FLAG_COMMAND)
+            .index(2)
+            .last(true)
+            .multiple(true)
+            .conflicts_with_all(&[FLAG_FILES, FLAG_FOLLOW, FLAG_MERGE, FLAG_TUI])
+            .help("Run the command and highlight its stdout and stderr,\n\
+                e.g. hl -r logcat.toml -- adb logcat"))
+        .subcommand(SubCommand::with_name(SUBCOMMAND_CHECK)
+            .about("Check rule files for errors and mistakes")
+            .arg(format_arg())
+            .arg(Arg::with_name(FLAG_FILES)
+                .index(1)
+                .required(true)
+                .multiple(true)
+                .help("Rule files; *.toml, *.yaml, *.yml and *.json are read by the \n\
+                    extension, and others as legacy rule files")))
+        .subcommand(SubCommand::with_name(SUBCOMMAND_TEST)
+            .about("Run the [[test]]s in rule files")
+            .arg(format_arg())
+            .arg(Arg::with_name(FLAG_FILES)
+                .index(1)
+                .required(true)
+                .multiple(true)
+                .help("TOML, YAML or JSON rule files")))
+        .subcommand(SubCommand::with_name(SUBCOMMAND_CONVERT)
+            .about("Convert a rule file between the TOML and legacy formats")
+            .arg(format_arg())
+            .arg(Arg::with_name(FLAG_TO)
+                .long(FLAG_TO)
+                .takes_value(true)
+                .required(true)
+                .possible_values(&["toml", "legacy"])
+                .help("Format to write"))
+            .arg(Arg::with_name(FLAG_FILES)
+                .index(1)
+                .required(true)
+                .help("Rule file; *.toml, *.yaml, *.yml and *.json are read by the \n\
+                    extension, and others as a legacy rule file")))
 }
 
-fn run_single_threaded<T: Read>(reader: BufReader<T>, filter: &mut Filter, writer: &Fn(&str)) {
-    for line in reader.lines() {
-        match line {
+/// --format for the subcommands, which also read legacy rule files.
+fn format_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(FLAG_FORMAT)
+        .long(FLAG_FORMAT)
+        .takes_value(true)
+        .possible_values(&["toml", "yaml", "json", "legacy"])
+        .help("Format of the rule files [default: by the extension]")
+}
+
+/// The format of a rule file: --format if given, or else told by the
+/// extension, or else `default`.
+fn rule_file_format(matches: &ArgMatches, filename: &str, default: Format) -> Format {
+    matches.value_of(FLAG_FORMAT)
+        .and_then(Format::parse)
+        .or_else(|| Format::from_extension(filename))
+        .unwrap_or(default)
+}
+
+/// Where the highlighted lines go; either stdout or a pager.
+/// Keeps the first write error, so that the input loop can stop.
+struct Output {
+    writer: Box<Write>,
+    auto_flush: bool,
+    error: Option<io::Error>,
+}
+
+impl Output {
+    fn new(writer: Box<Write>, auto_flush: bool) -> Output {
+        Output {
+            writer: writer,
+            auto_flush: auto_flush,
+            error: None,
+        }
+    }
+
+    fn write_line(&mut self, line: &str) {
+        if self.error.is_some() {
+            return;
+        }
+        let mut res = writeln!(self.writer, "{}", line);
+        if res.is_ok() && self.auto_flush {
+            res = self.writer.flush();
+        }
+        if let Err(e) = res {
+            self.error = Some(e);
+        }
+    }
+
+    /// Write an incomplete line right away, e.g. a prompt.
+    fn write_partial(&mut self, text: &str) {
+        if self.error.is_some() {
+            return;
+        }
+        let mut res = write!(self.writer, "{}", text);
+        if res.is_ok() {
+            res = self.writer.flush();
+        }
+        if let Err(e) = res {
+            self.error = Some(e);
+        }
+    }
+
+    fn check(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.check()?;
+        self.writer.flush()
+    }
+}
+
+/// Reloads the rules when the rule files are modified, or on SIGHUP.
+/// Also follows the terminal width on SIGWINCH, unless the width is given.
+/// It's checked for each input line, and every `IDLE_INTERVAL_MS` while the
+/// input is quiet.
+struct Reloader<'a> {
+    matches: &'a ArgMatches<'a>,
+    term: Term,
+    width: usize,
+    auto_width: bool,
+    watcher: FileWatcher,
+}
+
+impl<'a> Reloader<'a> {
+    fn new(matches: &'a ArgMatches<'a>,
+           term: Term,
+           width: usize,
+           auto_width: bool)
+           -> Reloader<'a> {
+        let mut files: Vec<String> = vec![];
+        for flag in &[FLAG_RULEFILE, FLAG_LEGACY_RULEFILE] {
+            if let Some(args) = matches.values_of(flag) {
+                files.extend(args.map(|s| s.to_string()));
+            }
+        }
+        // Without rule files, SIGHUP keeps its default action.
+        if files.len() > 0 {
+            signal::catch(libc::SIGHUP);
+        }
+        if auto_width {
+            signal::catch(libc::SIGWINCH);
+        }
+        Reloader {
+            matches: matches,
+            term: term,
+            width: width,
+            auto_width: auto_width,
+            watcher: FileWatcher::new(files),
+        }
+    }
+
+    /// Swap the rules of the filter if needed, and follow the width.
+    fn check(&mut self, filter: &mut Filter) {
+        if self.auto_width && signal::take(libc::SIGWINCH) {
+            if let Some(width) = tty::terminal_width() {
+                if width != self.width {
+                    debug!("Terminal width changed to {}", width);
+                    self.width = width;
+                    filter.set_width(width);
+                }
+            }
+        }
+        if let Some(rules) = self.new_rules() {
+            filter.set_rules(rules);
+        }
+    }
+
+    /// The rules read again, if the rule files have been modified or on
+    /// SIGHUP.  If the new rules have an error, it's reported, and the old
+    /// ones should be kept.
+    fn new_rules(&mut self) -> Option<Vec<Rule>> {
+        let hup = signal::take(libc::SIGHUP);
+        let changed = self.watcher.changed();
+        if !hup && !changed {
+            return None;
+        }
+        debug!("Reloading rules");
+        match load_rules(self.matches, self.term, self.width) {
+            Ok(rules) => Some(rules),
             Err(e) => {
-                error(&format!("{}", e));
-                match e.kind() {
-                    std::io::ErrorKind::InvalidData => continue, // OK
-                    _ => return,
+                error(&format!("Unable to reload rules: {}", e));
+                None
+            }
+        }
+    }
+
+    fn width(&self) -> usize {
+        self.width
+    }
+}
+
+/// Colors of the source tags with --merge, in turn.
+const MERGE_TAG_COLORS: [&'static str; 6] = ["cyan", "yellow", "magenta", "green", "blue", "red"];
+
+/// The "FILE:LINE:" prefix of each line, and the header line before each file.
+/// With --merge, also the tag of the source of each line.
+struct FileDecorations {
+    term: Term,
+    with_filename: bool,
+    line_number: bool,
+    filename_colors: Colors,
+    line_number_colors: Colors,
+    header: Option<DecorativeLine>,
+    tags: Vec<(String, Colors)>,
+    stderr_marker: Option<(String, Colors)>,
+}
+
+impl FileDecorations {
+    fn new(matches: &ArgMatches,
+           term: Term,
+           width: usize,
+           files: &Vec<String>)
+           -> Result<FileDecorations, String> {
+        let parser = RuleParser::new(term, width);
+        let parse = |flag: &str| {
+            parser.parse_colors(matches.value_of(flag).unwrap())
+                .map_err(|e| format!("--{}: {}", flag, e))
+        };
+        let header = match matches.value_of(FLAG_FILE_HEADER) {
+            Some(mark) => {
+                Some(DecorativeLine::new(mark, Some(parse(FLAG_FILE_HEADER_COLOR)?), term, width))
+            }
+            None => None,
+        };
+
+        let mut tags = vec![];
+        if matches.is_present(FLAG_MERGE) {
+            let names: Vec<String> = files.iter()
+                .map(|f| {
+                    let path = std::path::Path::new(f);
+                    match path.file_name() {
+                        Some(n) if f != "-" => n.to_string_lossy().to_string(),
+                        _ => STDIN_NAME.to_string(),
+                    }
+                })
+                .collect();
+            let width = names.iter().map(|n| n.chars().count()).max().unwrap_or(0);
+            for (i, name) in names.into_iter().enumerate() {
+                let colors = parser.parse_colors(MERGE_TAG_COLORS[i % MERGE_TAG_COLORS.len()])
+                    .unwrap();
+                tags.push((format!("{:<1$}", name, width), colors));
+            }
+        }
+
+        let stderr_marker = match matches.value_of(FLAG_STDERR_MARKER) {
+            Some(marker) => {
+                let colors = match matches.value_of(FLAG_STDERR_COLOR) {
+                    Some(_) => parse(FLAG_STDERR_COLOR)?,
+                    None => Colors::new_empty(),
+                };
+                Some((marker.to_string(), colors))
+            }
+            None => None,
+        };
+
+        Ok(FileDecorations {
+            term: term,
+            with_filename: matches.is_present(FLAG_WITH_FILENAME),
+            line_number: matches.is_present(FLAG_LINE_NUMBER),
+            filename_colors: parse(FLAG_FILENAME_COLOR)?,
+            line_number_colors: parse(FLAG_LINE_NUMBER_COLOR)?,
+            header: header,
+            tags: tags,
+            stderr_marker: stderr_marker,
+        })
+    }
+
+    /// The header line for a file, at the given width.
+    fn header(&mut self, file: &str, width: usize) -> Option<(&DecorativeLine, (String, String))> {
+        match self.header {
+            Some(ref mut dl) => {
+                dl.set_width(width);
+                let rendered = dl.render(&[file.to_string()]);
+                Some((&*dl, rendered))
+            }
+            None => None,
+        }
+    }
+
+    /// The parts of the prefix, with their colors and the separators after them.
+    fn parts(&self,
+             source: usize,
+             stream: Stream,
+             file: &str,
+             line_no: usize)
+             -> Vec<(String, &Colors, &str)> {
+        let mut ret = vec![];
+        if let Some(&(ref marker, ref colors)) = self.stderr_marker.as_ref() {
+            if stream == Stream::Stderr {
+                ret.push((marker.clone(), colors, ""));
+            }
+        }
+        if let Some(&(ref tag, ref colors)) = self.tags.get(source) {
+            ret.push((tag.clone(), colors, " "));
+        }
+        if self.with_filename {
+            ret.push((file.to_string(), &self.filename_colors, ":"));
+        }
+        if self.line_number {
+            ret.push((line_no.to_string(), &self.line_number_colors, ":"));
+        }
+        ret
+    }
+
+    /// The display width of the prefix, for the blanks before the pre and
+    /// post lines, which aren't input lines.
+    fn prefix_width(&self, source: usize, stream: Stream, file: &str, line_no: usize) -> usize {
+        self.parts(source, stream, file, line_no)
+            .iter()
+            .map(|&(ref text, _, separator)| text.width() + separator.width())
+            .sum()
+    }
+
+    fn prefix_spans(&self,
+                    source: usize,
+                    stream: Stream,
+                    file: &str,
+                    line_no: usize)
+                    -> Vec<StyledSpan> {
+        let mut ret = vec![];
+        for (text, colors, separator) in self.parts(source, stream, file, line_no) {
+            ret.push(StyledSpan {
+                text: text,
+                fg: colors.fg(),
+                bg: colors.bg(),
+                attrs: colors.attrs(),
+            });
+            if separator.len() > 0 {
+                ret.push(StyledSpan {
+                    text: separator.to_string(),
+                    fg: Color::None,
+                    bg: Color::None,
+                    attrs: ATTR_NONE,
+                });
+            }
+        }
+        ret
+    }
+
+    /// Same as `prefix_spans()`, as an ANSI string.
+    fn prefix(&self, source: usize, stream: Stream, file: &str, line_no: usize) -> String {
+        let mut ret = String::new();
+        for (text, colors, separator) in self.parts(source, stream, file, line_no) {
+            ret.push_str(colors.fg_code());
+            ret.push_str(colors.bg_code());
+            ret.push_str(&text);
+            if colors.fg_code().len() > 0 || colors.bg_code().len() > 0 {
+                ret.push_str(self.term.csi_reset());
+            }
+            ret.push_str(separator);
+        }
+        ret
+    }
+}
+
+#[test]
+fn test_file_decorations_prefix() {
+    let files = vec![];
+    let matches = get_app()
+        .get_matches_from(vec!["hl", "-H", "-n", "--line-number-color", "/red", "-p", "x"]);
+    let d = FileDecorations::new(&matches, Term::Console, 80, &files).unwrap();
+    assert_eq!("\x1b[35ma.log\x1b[0m:\x1b[41m12\x1b[0m:", d.prefix(0, Stream::Stdout, "a.log", 12));
+    assert_eq!(9, d.prefix_width(0, Stream::Stdout, "a.log", 12));
+    let spans = d.prefix_spans(0, Stream::Stdout, "a.log", 12);
+    assert_eq!(4, spans.len());
+    assert_eq!("12", spans[2].text);
+    assert_eq!(Color::Console(1), spans[2].bg);
+
+    let matches = get_app().get_matches_from(vec!["hl", "-n", "-p", "x"]);
+    let d = FileDecorations::new(&matches, Term::Console, 80, &files).unwrap();
+    assert_eq!("\x1b[32m3\x1b[0m:", d.prefix(0, Stream::Stdout, "a.log", 3));
+
+    let matches = get_app().get_matches_from(vec!["hl", "-p", "x"]);
+    let d = FileDecorations::new(&matches, Term::Console, 80, &files).unwrap();
+    assert_eq!("", d.prefix(0, Stream::Stdout, "a.log", 3));
+
+    let files = vec!["/var/log/kern.log".to_string(), "-".to_string()];
+    let matches = get_app().get_matches_from(vec!["hl", "--merge", "-p", "x"]);
+    let d = FileDecorations::new(&matches, Term::Console, 80, &files).unwrap();
+    assert_eq!("\x1b[36mkern.log        \x1b[0m ",
+               d.prefix(0, Stream::Stdout, "/var/log/kern.log", 1));
+    assert_eq!("\x1b[33m(standard input)\x1b[0m ", d.prefix(1, Stream::Stdout, "-", 1));
+
+    let matches = get_app().get_matches_from(vec!["hl", "--stderr-marker", "E ", "-p", "x"]);
+    let d = FileDecorations::new(&matches, Term::Console, 80, &files).unwrap();
+    assert_eq!("", d.prefix(0, Stream::Stdout, "cmd", 1));
+    assert_eq!("E ", d.prefix(0, Stream::Stderr, "cmd", 1));
+    assert_eq!(1, d.prefix_spans(0, Stream::Stderr, "cmd", 1).len());
+}
+
+#[test]
+fn test_tail_requires_follow() {
+    assert!(get_app().get_matches_from_safe(vec!["hl", "--tail", "5", "x.log"]).is_err());
+    let matches = get_app().get_matches_from(vec!["hl", "-F", "x.log"]);
+    assert!(!matches.is_present(FLAG_TAIL));
+}
+
+/// An input event for `run_single_threaded()`.
+enum Input<'a> {
+    /// A new file has started, with the current output width.
+    FileStart(&'a str, usize),
+    /// A line, with the index and the name of its file, the stream it came
+    /// from, and the line number.
+    Line {
+        source: usize,
+        name: &'a str,
+        stream: Stream,
+        line_no: usize,
+        line: &'a str,
+    },
+    /// The start of a line that's been pending for a while, e.g. a prompt,
+    /// with the name of the command, the line number and the width of the
+    /// terminal.  The complete line follows as a `Line`.
+    Partial {
+        name: &'a str,
+        line_no: usize,
+        line: &'a str,
+        width: usize,
+    },
+}
+
+/// The output lines for an input line, with ANSI escape sequences.  The prefix
+/// goes before the input line, and blanks of `width` columns before the pre
+/// and post lines, so that they aren't taken for input lines.
+fn prefixed_lines(filter: &mut Filter, line: &str, prefix: &str, width: usize) -> Vec<String> {
+    let mut out = vec![];
+    // Writing to a Vec doesn't fail.
+    filter.process_to(line, &mut out).unwrap();
+    let pre_lines = filter.last_matches()
+        .iter()
+        .filter(|&&i| filter.rules()[i].pre_line().is_some())
+        .count();
+    let out = String::from_utf8(out).unwrap();
+    let blank = " ".repeat(width);
+    let mut lines: Vec<&str> = out.split('\n').collect();
+    lines.pop(); // After the last newline.
+    lines.iter()
+        .enumerate()
+        .map(|(i, l)| {
+            let prefix = if i == pre_lines { prefix } else { &blank };
+            format!("{}{}", prefix, l)
+        })
+        .collect()
+}
+
+#[test]
+fn test_prefixed_lines() {
+    let mut rule = Rule::new("x").unwrap();
+    rule.set_pre_line(DecorativeLine::new("-", None, Term::Dumb, 3))
+        .set_post_line(DecorativeLine::new("=", None, Term::Dumb, 3));
+    let mut filter = Filter::new(Term::Dumb, vec![rule]);
+    assert_eq!(vec!["    ---", "a:1:x", "    ==="],
+               prefixed_lines(&mut filter, "x", "a:1:", 4));
+    assert_eq!(vec!["a:2:y"], prefixed_lines(&mut filter, "y", "a:2:", 4));
+}
+
+/// Write how the filter processed the last line to stderr, for --explain.
+fn print_explanation(filter: &Filter) {
+    if let Some(e) = filter.explanation() {
+        for line in e.describe(filter.rules()) {
+            let _ = writeln!(&mut io::stderr(), "  | {}", line);
+        }
+    }
+}
+
+/// Open an input file, or the standard input for "-", decompressing it if needed.
+fn open_file(name: &str) -> io::Result<Box<Read + Send>> {
+    decompress::open(open_raw_file(name)?)
+}
+
+/// Same as `open_file()`, without telling the compression, which may block
+/// on a pipe.
+fn open_raw_file(name: &str) -> io::Result<Box<Read + Send>> {
+    if name == "-" {
+        Ok(Box::new(io::stdin()))
+    } else {
+        Ok(Box::new(std::fs::File::open(name)?))
+    }
+}
+
+/// The items of an iterator, read on another thread, so that the caller can
+/// do something else while waiting for them, e.g. reload the rules.  The
+/// thread stops reading when the caller falls behind.
+fn read_in_background<I>(iter: I) -> mpsc::Receiver<I::Item>
+    where I: Iterator + Send + 'static,
+          I::Item: Send + 'static
+{
+    let (tx, rx) = mpsc::sync_channel(READ_AHEAD_LINES);
+    thread::spawn(move || for item in iter {
+        if tx.send(item).is_err() {
+            return;
+        }
+    });
+    rx
+}
+
+/// The next item from `rx`, or `None` at the end.  The reloader is checked
+/// while waiting.
+fn next_or_idle<T>(rx: &mpsc::Receiver<T>,
+                   filter: &mut Filter,
+                   reloader: &mut Reloader)
+                   -> Option<T> {
+    loop {
+        match rx.recv_timeout(Duration::from_millis(IDLE_INTERVAL_MS)) {
+            Ok(item) => return Some(item),
+            Err(mpsc::RecvTimeoutError::Timeout) => reloader.check(filter),
+            Err(mpsc::RecvTimeoutError::Disconnected) => return None,
+        }
+    }
+}
+
+/// Where the input lines come from.
+struct InputSpec {
+    files: Vec<String>,
+    follow_reader: Option<FollowReader>,
+    reset_state: bool,
+    /// The timestamp format of each file, with --merge.
+    time_formats: Option<Vec<TimeFormat>>,
+    /// The command to run instead of reading files.
+    command: Option<Vec<String>>,
+    /// Whether to run the command on a pseudo-terminal.
+    pty: bool,
+}
+
+/// Read the input files one by one.  A file that can't be read is reported and
+/// skipped, and the error is returned at the end.  Returns the exit code of
+/// the command, if any, or 0.
+fn run_single_threaded<F>(input: InputSpec,
+                          filter: &mut Filter,
+                          reloader: &mut Reloader,
+                          process: F)
+                          -> io::Result<i32>
+    where F: FnMut(&mut Filter, Input) -> io::Result<()>
+{
+    let InputSpec { files, follow_reader, reset_state, time_formats, command, pty } = input;
+    if let Some(args) = command {
+        if pty {
+            return run_pty(&args, filter, reloader, process);
+        }
+        return run_command(&args, filter, reloader, process);
+    }
+    if let Some(formats) = time_formats {
+        return run_merged(&files, formats, filter, reloader, process).map(|_| 0);
+    }
+    let mut process = process;
+
+    let stdin = vec!["-".to_string()];
+    let names = if files.len() == 0 { &stdin } else { &files };
+    let mut follow_reader = follow_reader;
+    let mut failed = false;
+
+    for (source, name) in names.iter().enumerate() {
+        let input: Box<Read + Send> = match follow_reader.take() {
+            Some(r) => Box::new(r),
+            None => {
+                match open_file(name) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        error(&format!("Unable to open file '{}': {}", name, e));
+                        failed = true;
+                        continue;
+                    }
                 }
             }
+        };
+        let name = if name == "-" { STDIN_NAME } else { name };
+
+        if reset_state {
+            filter.reset_state();
+        }
+        reloader.check(filter);
+        process(filter, Input::FileStart(name, reloader.width()))?;
+
+        let mut line_no = 0;
+        let lines = read_in_background(BufReader::new(input).lines());
+        while let Some(line) = next_or_idle(&lines, filter, reloader) {
+            line_no += 1;
+            match line {
+                Err(e) => {
+                    error(&format!("{}: {}", name, e));
+                    match e.kind() {
+                        std::io::ErrorKind::InvalidData => continue, // OK
+                        _ => {
+                            failed = true;
+                            break;
+                        }
+                    }
+                }
+                Ok(s) => {
+                    reloader.check(filter);
+                    process(filter,
+                            Input::Line {
+                                source: source,
+                                name: name,
+                                stream: Stream::Stdout,
+                                line_no: line_no,
+                                line: &s,
+                            })?;
+                }
+            }
+        }
+    }
+    if failed {
+        return Err(io::Error::new(io::ErrorKind::Other, "Some input files couldn't be read."));
+    }
+    Ok(0)
+}
+
+/// Same as `run_single_threaded()`, but reads the stdout and stderr of a
+/// command.
+fn run_command<F>(args: &Vec<String>,
+                  filter: &mut Filter,
+                  reloader: &mut Reloader,
+                  mut process: F)
+                  -> io::Result<i32>
+    where F: FnMut(&mut Filter, Input) -> io::Result<()>
+{
+    let mut command = Command::spawn(args).map_err(|e| {
+            io::Error::new(e.kind(), format!("Unable to run '{}': {}", args[0], e))
+        })?;
+    let mut line_nos = [0, 0];
+    while let Some((stream, line)) = command.next_line_or_idle(|| reloader.check(filter)) {
+        let line_no = &mut line_nos[stream as usize];
+        *line_no += 1;
+        match line {
+            Err(e) => error(&format!("{}: {}", args[0], e)),
             Ok(s) => {
-                filter.process(&s, writer);
+                reloader.check(filter);
+                filter.set_stream(stream);
+                let res = process(filter,
+                                  Input::Line {
+                                      source: 0,
+                                      name: &args[0],
+                                      stream: stream,
+                                      line_no: *line_no,
+                                      line: &s,
+                                  });
+                if let Err(e) = res {
+                    command.terminate()?;
+                    return Err(e);
+                }
             }
         }
     }
+    Ok(command::exit_code(&command.wait()?))
 }
 
-fn real_main() -> Result<(), String> {
-    env_logger::init().unwrap();
+/// Same as `run_command()`, but runs the command on a pseudo-terminal, where
+/// stdout and stderr are the same.
+fn run_pty<F>(args: &Vec<String>,
+              filter: &mut Filter,
+              reloader: &mut Reloader,
+              mut process: F)
+              -> io::Result<i32>
+    where F: FnMut(&mut Filter, Input) -> io::Result<()>
+{
+    let mut pty = Pty::spawn(args).map_err(|e| {
+            io::Error::new(e.kind(), format!("Unable to run '{}': {}", args[0], e))
+        })?;
+    let mut line_no = 0;
+    while let Some(output) = pty.next_output_or_idle(|| reloader.check(filter)) {
+        reloader.check(filter);
+        let res = match output {
+            Err(e) => {
+                error(&format!("{}: {}", args[0], e));
+                continue;
+            }
+            Ok(PtyOutput::Partial(s)) => {
+                process(filter,
+                        Input::Partial {
+                            name: &args[0],
+                            line_no: line_no + 1,
+                            line: &s,
+                            width: reloader.width(),
+                        })
+            }
+            Ok(PtyOutput::Line(s)) => {
+                line_no += 1;
+                process(filter,
+                        Input::Line {
+                            source: 0,
+                            name: &args[0],
+                            stream: Stream::Stdout,
+                            line_no: line_no,
+                            line: &s,
+                        })
+            }
+        };
+        if let Err(e) = res {
+            pty.terminate()?;
+            return Err(e);
+        }
+    }
+    Ok(command::exit_code(&pty.wait()?))
+}
 
-    let matches = get_app().get_matches();
-    if matches.is_present(FLAG_BASHCOMP) {
-        get_app().gen_completions_to("hl", Shell::Bash, &mut io::stdout());
-        return Ok(());
+/// Same as `run_single_threaded()`, but interleaves the lines of all the files
+/// by their timestamps.
+fn run_merged<F>(files: &Vec<String>,
+                 formats: Vec<TimeFormat>,
+                 filter: &mut Filter,
+                 reloader: &mut Reloader,
+                 mut process: F)
+                 -> io::Result<()>
+    where F: FnMut(&mut Filter, Input) -> io::Result<()>
+{
+    let mut merger = Merger::new();
+    let mut sources = vec![];
+    let mut failed = false;
+    for (source, (name, format)) in files.iter().zip(formats).enumerate() {
+        match open_file(name) {
+            Ok(r) => {
+                merger.add(r, format);
+                sources.push((source, if name == "-" { STDIN_NAME } else { name }, 0));
+            }
+            Err(e) => {
+                error(&format!("Unable to open file '{}': {}", name, e));
+                failed = true;
+            }
+        }
     }
 
-    let args: Vec<String> = env::args().collect();
-    let program = &args[0];
+    let lines = read_in_background(merger);
+    while let Some((i, line)) = next_or_idle(&lines, filter, reloader) {
+        let &mut (source, name, ref mut line_no) = &mut sources[i];
+        *line_no += 1;
+        match line {
+            Err(e) => {
+                error(&format!("{}: {}", name, e));
+                if e.kind() != io::ErrorKind::InvalidData {
+                    failed = true;
+                }
+            }
+            Ok(s) => {
+                reloader.check(filter);
+                process(filter,
+                        Input::Line {
+                            source: source,
+                            name: name,
+                            stream: Stream::Stdout,
+                            line_no: *line_no,
+                            line: &s,
+                        })?;
+            }
+        }
+    }
+    if failed {
+        return Err(io::Error::new(io::ErrorKind::Other, "Some input files couldn't be read."));
+    }
+    Ok(())
+}
 
-    let width = value_t!(matches, FLAG_WIDTH, usize).unwrap();
-    let auto_flush = matches.is_present("autoflush");
-    let mut files: Vec<String> = vec![];
-    if let Some(args) = matches.values_of(FLAG_FILES) {
-        for arg in args {
-            files.push(arg.to_string());
+/// The timestamp formats of the files for --merge.
+fn load_time_formats(matches: &ArgMatches, files: &Vec<String>) -> Result<Vec<TimeFormat>, String> {
+    let specs: Vec<&str> = match matches.values_of(FLAG_TIME_FORMAT) {
+        Some(values) => values.collect(),
+        None => vec!["auto"],
+    };
+    if specs.len() > files.len() {
+        return Err("More --time-format values than input files.".to_string());
+    }
+    let boot = if matches.is_present(FLAG_BOOT_TIME) {
+        Some(value_t!(matches, FLAG_BOOT_TIME, f64).map_err(|e| e.to_string())?)
+    } else {
+        boot_time()
+    };
+    let mut ret = vec![];
+    for i in 0..files.len() {
+        let spec = specs.get(i).unwrap_or(specs.last().unwrap());
+        ret.push(TimeFormat::parse(spec, boot)
+            .map_err(|e| format!("--{}: {}", FLAG_TIME_FORMAT, e))?);
+    }
+    Ok(ret)
+}
+
+/// Show the input in the full screen viewer.  The input is read on a separate
+/// thread, so the viewer can respond to keys while waiting for the input.
+fn run_tui(filter: Filter,
+           files: Vec<String>,
+           follow_reader: Option<FollowReader>,
+           reloader: &mut Reloader)
+           -> io::Result<()> {
+    // The files are opened before the viewer starts, so that a missing one
+    // is reported as usual, but their compression is told on the thread.
+    let stdin = vec!["-".to_string()];
+    let names = if files.len() == 0 { stdin } else { files };
+    let mut raw_files = vec![];
+    if follow_reader.is_none() {
+        for name in names {
+            let r = open_raw_file(&name).map_err(|e| {
+                    io::Error::new(e.kind(), format!("Unable to open file '{}': {}", name, e))
+                })?;
+            raw_files.push((name, r));
         }
     }
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let input: Box<Read + Send> = match follow_reader {
+            Some(r) => Box::new(r),
+            None => {
+                let mut input: Box<Read + Send> = Box::new(io::empty());
+                for (name, r) in raw_files {
+                    match decompress::open(r) {
+                        Ok(r) => input = Box::new(input.chain(r)),
+                        Err(e) => {
+                            let e = io::Error::new(e.kind(), format!("{}: {}", name, e));
+                            let _ = tx.send(Err(e));
+                            return;
+                        }
+                    }
+                }
+                input
+            }
+        };
+        for line in BufReader::new(input).lines() {
+            match line {
+                Ok(s) => {
+                    if tx.send(Ok(s)).is_err() {
+                        return; // The viewer has quit.
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => continue,
+                Err(e) => {
+                    let _ = tx.send(Err(e));
+                    return;
+                }
+            }
+        }
+    });
 
-    // Detect the terminal
-    let term = Term::detect();
-    debug!("Detected terminal: {:?}", &term);
+    let mut backend = TermBackend::new()?;
+    let mut viewer = Viewer::new(filter);
+    tui::run(&mut viewer, &mut backend, &rx, || reloader.new_rules())
+}
+
+/// Whether the input will end at some point, which is when paging makes sense.
+fn is_input_finite(files: &Vec<String>) -> bool {
+    if files.len() == 0 || files.iter().any(|f| f == "-") {
+        tty::is_regular_file(tty::STDIN_FD)
+    } else {
+        true
+    }
+}
 
+fn load_rules(matches: &ArgMatches, term: Term, width: usize) -> Result<Vec<Rule>, String> {
     // Parse rules.
     let mut parser = RuleParser::new(term, width);
+    parser.set_options(Options {
+        ignore_case: matches.is_present(FLAG_IGNORE_CASE),
+        whole_word: matches.is_present(FLAG_WHOLE_WORD),
+        literal: matches.is_present(FLAG_LITERAL),
+        unicode: None,
+    });
 
     let mut rules: Vec<Rule> = vec![];
 
+    // Read all the files, and report all their errors together.
+    let mut files: Vec<(&str, Format)> = vec![];
     if let Some(args) = matches.values_of(FLAG_RULEFILE) {
-        for arg in args {
-            debug!("Loading TOML rule file {}", arg);
-            parser.parse_toml(arg, &mut rules).map_err(|e| e.description().to_string())?;
-        }
+        files.extend(args.map(|arg| (arg, rule_file_format(matches, arg, Format::Toml))));
     }
     if let Some(args) = matches.values_of(FLAG_LEGACY_RULEFILE) {
-        for arg in args {
-            debug!("Loading legacy rule file {}", arg);
-            parser.parse_legacy(arg, &mut rules).map_err(|e| e.description().to_string())?;
+        files.extend(args.map(|arg| (arg, Format::Legacy)));
+    }
+    let mut errors = 0;
+    for (file, format) in files {
+        debug!("Loading {} rule file {}", format.name(), file);
+        let diagnostics = match parser.read_entries(file, format) {
+            Ok(entries) => {
+                let diagnostics = lint::errors(file, &entries);
+                rules.extend(entries.into_iter().filter_map(|e| e.rule));
+                diagnostics
+            }
+            Err(e) => lint::file_errors(file, &e),
+        };
+        for d in &diagnostics {
+            writeln!(&mut std::io::stderr(), "{}", d.render());
         }
+        errors += diagnostics.len();
+    }
+    if errors > 0 {
+        return Err(format!("{} error(s) in rule files.", errors));
     }
     if let Some(args) = matches.values_of(FLAG_SIMPLE_RULE) {
         for arg in args {
             debug!("Adding simple rule {}", arg);
-            rules.push(parser.parse_simple_rule(arg).map_err(|e| e.description().to_string())?);
+            rules.push(parser.parse_simple_rule(arg).map_err(|e| error_chain(&e))?);
         }
     }
 
@@ -152,27 +1166,346 @@ fn real_main() -> Result<(), String> {
         return Err("No rules specified.".to_string());
     }
 
+    Ok(rules)
+}
+
+/// Run `hl check`.  Returns 1 if there's any error, but not for warnings.
+fn run_check(matches: &ArgMatches) -> i32 {
+    let parser = RuleParser::new(Term::Dumb, DEFAULT_WIDTH);
+    let mut errors = 0;
+    let mut warnings = 0;
+    for file in matches.values_of(FLAG_FILES).unwrap() {
+        let format = rule_file_format(matches, file, Format::Legacy);
+        for d in lint::check_file(&parser, file, format) {
+            match d.severity {
+                lint::Severity::Error => errors += 1,
+                lint::Severity::Warning => warnings += 1,
+            }
+            println!("{}", d.render());
+        }
+    }
+    if errors + warnings > 0 {
+        error(&format!("{} error(s), {} warning(s)", errors, warnings));
+    }
+    if errors > 0 { 1 } else { 0 }
+}
+
+/// Run `hl test`.  Each file's tests run against the rules in the same file.
+/// Returns 1 if any test fails.
+fn run_tests(matches: &ArgMatches) -> i32 {
+    let parser = RuleParser::new(Term::Xterm, DEFAULT_WIDTH);
+    let mut passed = 0;
+    let mut failed = 0;
+    for file in matches.values_of(FLAG_FILES).unwrap() {
+        let mut rules = vec![];
+        let format = rule_file_format(matches, file, Format::Toml);
+        let tests = match parser.parse_file_with_tests(file, format, &mut rules) {
+            Ok(tests) => tests,
+            Err(e) => {
+                error(&error_chain(&e));
+                failed += 1;
+                continue;
+            }
+        };
+        for test in &tests {
+            let failures = test.run(&rules);
+            if failures.len() == 0 {
+                passed += 1;
+                continue;
+            }
+            failed += 1;
+            println!("FAIL {}:{}: test {}", file, test.line, test);
+            for f in &failures {
+                println!("{}", f);
+            }
+        }
+    }
+    println!("{} passed, {} failed", passed, failed);
+    if failed > 0 { 1 } else { 0 }
+}
+
+/// Run `hl convert`, writing the converted rules to stdout.  Returns 1 on
+/// errors.
+fn run_convert(matches: &ArgMatches) -> i32 {
+    let parser = RuleParser::new(Term::Dumb, DEFAULT_WIDTH);
+    let file = matches.value_of(FLAG_FILES).unwrap();
+    let format = rule_file_format(matches, file, Format::Legacy);
+    let res = convert::read_file(&parser, file, format).and_then(|(rules, tests)| {
+        // --to only takes "toml" and "legacy".
+        if matches.value_of(FLAG_TO) == Some("toml") {
+            return Ok(convert::to_toml(&rules, &tests));
+        }
+        if tests.len() > 0 {
+            error(&format!("{}: {} [[test]](s) dropped; legacy rule files have no tests.",
+                           file,
+                           tests.len()));
+        }
+        convert::to_legacy(&rules).map_err(|e| RuleError::in_file(file, e))
+    });
+    match res {
+        Ok(text) => {
+            print!("{}", text);
+            0
+        }
+        Err(e) => {
+            error(&error_chain(&e));
+            1
+        }
+    }
+}
+
+/// Returns the exit code.
+fn real_main() -> Result<i32, String> {
+    env_logger::init().unwrap();
+
+    let matches = get_app().get_matches();
+    if matches.is_present(FLAG_BASHCOMP) {
+        get_app().gen_completions_to("hl", Shell::Bash, &mut io::stdout());
+        return Ok(0);
+    }
+    if let Some(m) = matches.subcommand_matches(SUBCOMMAND_CHECK) {
+        return Ok(run_check(m));
+    }
+    if let Some(m) = matches.subcommand_matches(SUBCOMMAND_TEST) {
+        return Ok(run_tests(m));
+    }
+    if let Some(m) = matches.subcommand_matches(SUBCOMMAND_CONVERT) {
+        return Ok(run_convert(m));
+    }
+
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let auto_width = !matches.is_present(FLAG_WIDTH);
+    let width = if auto_width {
+        tty::terminal_width().unwrap_or(DEFAULT_WIDTH)
+    } else {
+        value_t!(matches, FLAG_WIDTH, usize).map_err(|e| e.to_string())?
+    };
+    let follow = matches.is_present(FLAG_FOLLOW);
+    let tail = if matches.is_present(FLAG_TAIL) {
+        value_t!(matches, FLAG_TAIL, usize).map_err(|e| e.to_string())?
+    } else {
+        DEFAULT_TAIL
+    };
+    let explain = matches.is_present(FLAG_EXPLAIN);
+    let auto_flush = matches.is_present("autoflush") || follow || explain;
+    let output_format = matches.value_of(FLAG_OUTPUT_FORMAT).unwrap();
+    let paging = matches.value_of(FLAG_PAGING).unwrap();
+    let mut files: Vec<String> = vec![];
+    if let Some(args) = matches.values_of(FLAG_FILES) {
+        for arg in args {
+            files.push(arg.to_string());
+        }
+    }
+    if follow && files.len() != 1 {
+        return Err("--follow requires exactly one file.".to_string());
+    }
+    // The input never ends, so the document would never be finished.
+    if follow && output_format != "ansi" && !matches.is_present(FLAG_TUI) {
+        return Err(format!("--follow can't be used with --output-format {}.", output_format));
+    }
+    let merge = matches.is_present(FLAG_MERGE);
+    let command: Option<Vec<String>> = matches.values_of(FLAG_COMMAND)
+        .map(|args| args.map(|a| a.to_string()).collect());
+    if merge && files.len() == 0 {
+        files.push("-".to_string());
+    }
+
+    // Detect the terminal.  Recordings are always in full color.
+    let term = if output_format == "ansi" {
+        Term::detect()
+    } else {
+        Term::Rgb
+    };
+    debug!("Detected terminal: {:?}", &term);
+
+    let rules = load_rules(&matches, term, width)?;
+    let mut decorations = FileDecorations::new(&matches, term, width, &files)?;
+    let time_formats = if merge {
+        Some(load_time_formats(&matches, &files)?)
+    } else {
+        None
+    };
+
     // Create the filter
     let mut filter = Filter::new(term, rules);
+    filter.set_explain(explain);
+    if let Some(colors) = matches.value_of(FLAG_STDERR_COLOR) {
+        filter.set_stderr_colors(RuleParser::new(term, width).parse_colors(colors)
+            .map_err(|e| format!("--{}: {}", FLAG_STDERR_COLOR, e))?);
+    }
+    let mut reloader = Reloader::new(&matches, term, width, auto_width);
+
+    let follow_reader = if follow {
+        Some(FollowReader::open(&files[0], tail)
+            .map_err(|e| format!("Unable to open file '{}': {}", files[0], e))?)
+    } else {
+        None
+    };
+
+    if matches.is_present(FLAG_TUI) {
+        return run_tui(filter, files, follow_reader, &mut reloader)
+            .map(|_| 0)
+            .map_err(|e| format!("{}", e));
+    }
+
+    // Start the pager, if needed.
+    let use_pager = match paging {
+        "always" => true,
+        "never" => false,
+        _ => {
+            output_format == "ansi" && !follow && command.is_none() && !explain &&
+            tty::is_tty(tty::STDOUT_FD) && is_input_finite(&files)
+        }
+    };
+    let mut pager = None;
+    if use_pager {
+        match Pager::spawn() {
+            Ok(p) => pager = Some(p),
+            Err(e) => {
+                if paging == "always" {
+                    return Err(format!("Unable to start pager: {}", e));
+                }
+                error(&format!("Unable to start pager: {}", e));
+            }
+        }
+    }
+    let writer: Box<Write> = match pager.as_mut().and_then(|p| p.take_input()) {
+        Some(input) => Box::new(io::BufWriter::new(input)),
+        None => Box::new(io::stdout()),
+    };
+    let mut output = Output::new(writer, auto_flush);
 
-    // This works.
-    let fileinput = FileInput::new(&files);
-    let reader = BufReader::new(fileinput);
+    let input = InputSpec {
+        time_formats: time_formats,
+        files: files,
+        follow_reader: follow_reader,
+        reset_state: matches.is_present(FLAG_RESET_STATE),
+        command: command,
+        pty: matches.is_present(FLAG_PTY),
+    };
 
-    run_single_threaded(reader,
-                        &mut filter,
-                        &move |out| {
-                            println!("{}", out);
-                            if auto_flush {
-                                io::stdout().flush();
+    let result = match output_format {
+        "svg" => {
+            let mut svg = SvgRenderer::new(width);
+            run_single_threaded(input, &mut filter, &mut reloader, |filter, input| {
+                    match input {
+                        Input::FileStart(name, width) => {
+                            if let Some((dl, (plain, _))) = decorations.header(name, width) {
+                                svg.add_line(&StyledLine::Decorative(dl, plain));
                             }
-                        });
-    Ok(())
+                        }
+                        Input::Line { source, name, stream, line_no, line } => {
+                            let prefix = decorations.prefix_spans(source, stream, name, line_no);
+                            let width = decorations.prefix_width(source, stream, name, line_no);
+                            let blank = [StyledSpan {
+                                             text: " ".repeat(width),
+                                             fg: Color::None,
+                                             bg: Color::None,
+                                             attrs: ATTR_NONE,
+                                         }];
+                            filter.process_styled(line, |out| {
+                                let prefix: &[StyledSpan] = match out {
+                                    StyledLine::Text(_) => &prefix,
+                                    StyledLine::Decorative(..) if width > 0 => &blank,
+                                    StyledLine::Decorative(..) => &[],
+                                };
+                                svg.add_prefixed_line(prefix, &out)
+                            });
+                            print_explanation(filter);
+                        }
+                        Input::Partial { .. } => {}
+                    }
+                    Ok(())
+                })
+                .and_then(|code| {
+                    output.write_line(&svg.finish());
+                    output.check().map(|_| code)
+                })
+        }
+        "asciicast" => {
+            let cast = AsciicastWriter::new(width, ASCIICAST_HEIGHT);
+            output.write_line(&cast.header());
+            run_single_threaded(input, &mut filter, &mut reloader, |filter, input| {
+                match input {
+                    Input::FileStart(name, width) => {
+                        if let Some((_, (_, computed))) = decorations.header(name, width) {
+                            output.write_line(&cast.event(&computed));
+                        }
+                    }
+                    Input::Line { source, name, stream, line_no, line } => {
+                        let prefix = decorations.prefix(source, stream, name, line_no);
+                        let width = decorations.prefix_width(source, stream, name, line_no);
+                        for out in prefixed_lines(filter, line, &prefix, width) {
+                            output.write_line(&cast.event(&out));
+                        }
+                        print_explanation(filter);
+                    }
+                    Input::Partial { .. } => {}
+                }
+                output.check()
+            })
+        }
+        _ => {
+            // Whether an incomplete line is on the screen, to be overwritten.
+            let mut partial = false;
+            run_single_threaded(input, &mut filter, &mut reloader, |filter, input| {
+                if partial {
+                    output.write_partial("\r\x1b[K");
+                    partial = false;
+                }
+                match input {
+                    Input::FileStart(name, width) => {
+                        if let Some((_, (_, computed))) = decorations.header(name, width) {
+                            output.write_line(&computed);
+                        }
+                    }
+                    Input::Line { source, name, stream, line_no, line } => {
+                        let prefix = decorations.prefix(source, stream, name, line_no);
+                        let width = decorations.prefix_width(source, stream, name, line_no);
+                        for out in prefixed_lines(filter, line, &prefix, width) {
+                            output.write_line(&out);
+                        }
+                        print_explanation(filter);
+                    }
+                    Input::Partial { name, line_no, line, width } => {
+                        // Only a line within a row can be redrawn, as "\r" doesn't go up.
+                        let prefix_width =
+                            decorations.prefix_width(0, Stream::Stdout, name, line_no);
+                        if prefix_width + line.width() < width {
+                            let prefix = decorations.prefix(0, Stream::Stdout, name, line_no);
+                            output.write_partial(&format!("{}{}",
+                                                          prefix,
+                                                          filter.process_partial(line)));
+                            partial = true;
+                        }
+                    }
+                }
+                output.check()
+            })
+        }
+    };
+    let result = result.and_then(|code| output.finish().map(|_| code));
+
+    // Close the pipe and let the user read the rest.
+    drop(output);
+    if let Some(p) = pager {
+        p.wait().map_err(|e| format!("Pager failed: {}", e))?;
+    }
+
+    match result {
+        // The pager or the downstream command has quit; not an error.
+        Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(0),
+        Err(e) => Err(format!("{}", e)),
+        Ok(code) => Ok(code),
+    }
 }
 
 fn main() {
     match real_main() {
-        Ok(_) => return, // okay
+        Ok(0) => return, // okay
+        Ok(code) => std::process::exit(code),
         Err(err) => {
             error(&err);
             std::process::exit(1);
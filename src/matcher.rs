@@ -0,0 +1,450 @@
+use std::fmt;
+use regex::{self, Regex, RegexBuilder};
+#[cfg(feature = "pcre")]
+use pcre::Pcre;
+#[cfg(feature = "fancy-regex")]
+use fancy_regex;
+
+use super::*;
+
+/// The byte ranges of the whole match and the groups, or `None` for the groups
+/// that didn't take part in the match.
+pub type Captures = Vec<Option<(usize, usize)>>;
+
+/// A compiled pattern of any regex engine.
+pub trait Matcher: fmt::Debug + Send + Sync {
+    /// The number of the groups, besides the whole match.
+    fn capture_count(&self) -> usize;
+
+    fn is_match(&self, line: &str) -> bool;
+
+    /// The first match.
+    fn captures(&self, line: &str) -> Option<Captures>;
+
+    /// All the matches, which don't overlap.
+    fn captures_all(&self, line: &str) -> Vec<Captures>;
+}
+
+/// How to compile a pattern, besides the engine.
+#[derive(Debug,Copy,Clone,Default,PartialEq,Eq)]
+pub struct Options {
+    pub ignore_case: bool,
+    /// Only match whole words.
+    pub whole_word: bool,
+    /// Match the pattern as it is, not as a regex.  A leading `!` still
+    /// negates it, so a text starting with `!` needs a regex instead.
+    pub literal: bool,
+    /// Whether `\w`, `\b`, case folding and so on know Unicode, or only ASCII.
+    /// `None` leaves it to the engine; regex has it on, and pcre off.
+    pub unicode: Option<bool>,
+}
+
+/// The regex engines.  `Regex` is always there; the others are cargo
+/// features, as they have more syntax, e.g. look-around and backreferences.
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum Engine {
+    Regex,
+    Pcre,
+    FancyRegex,
+}
+
+/// The engine of the rules without `engine`.
+impl Default for Engine {
+    fn default() -> Engine {
+        Engine::Regex
+    }
+}
+
+/// The names of the engines, for rule files.
+pub const ENGINE_NAMES: [&'static str; 3] = ["regex", "pcre", "fancy-regex"];
+
+impl Engine {
+    pub fn parse(value: &str) -> Result<Engine, RuleError> {
+        match value {
+            "regex" => Ok(Engine::Regex),
+            "pcre" => Ok(Engine::Pcre),
+            "fancy-regex" => Ok(Engine::FancyRegex),
+            _ => {
+                Err(RuleError::new(&format!("Invalid engine '{}'; must be one of '{}'.",
+                                            value,
+                                            ENGINE_NAMES.join("', '"))))
+            }
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Engine::Regex => "regex",
+            Engine::Pcre => "pcre",
+            Engine::FancyRegex => "fancy-regex",
+        }
+    }
+
+    /// Whether the engine is built in.
+    pub fn is_available(&self) -> bool {
+        match *self {
+            Engine::Regex => true,
+            Engine::Pcre => cfg!(feature = "pcre"),
+            Engine::FancyRegex => cfg!(feature = "fancy-regex"),
+        }
+    }
+
+    /// Compile a pattern.
+    pub fn compile(&self, pattern: &str) -> Result<Box<Matcher>, RuleError> {
+        self.compile_with(pattern, Options::default())
+    }
+
+    /// A pattern that matches `text` as it is.
+    pub fn escape(&self, text: &str) -> String {
+        match *self {
+            Engine::Regex | Engine::FancyRegex => regex::escape(text),
+            // A backslash makes any other ASCII character literal.  "\Q...\E"
+            // would end at a "\E" in the text.
+            Engine::Pcre => {
+                let mut ret = String::with_capacity(text.len());
+                for c in text.chars() {
+                    if c.is_ascii() && !c.is_ascii_alphanumeric() && c != '_' {
+                        ret.push('\\');
+                    }
+                    ret.push(c);
+                }
+                ret
+            }
+        }
+    }
+
+    /// Compile a pattern, with the case and the Unicode options.  `literal`
+    /// and `whole_word` are up to the caller, as they change the pattern.
+    pub fn compile_with(&self,
+                        pattern: &str,
+                        options: Options)
+                        -> Result<Box<Matcher>, RuleError> {
+        if !self.is_available() {
+            return Err(RuleError::new(&format!("The '{}' engine isn't built in; build with \
+                                                '--features {}'.",
+                                               self.name(),
+                                               self.name())));
+        }
+        let ret = match *self {
+            Engine::Regex => RegexMatcher::compile(pattern, options),
+            Engine::Pcre => PcreMatcher::compile(pattern, options),
+            Engine::FancyRegex => FancyMatcher::compile(pattern, options),
+        };
+        // Suggest one that's built in, if any.
+        let other = if Engine::Pcre.is_available() && !Engine::FancyRegex.is_available() {
+            Engine::Pcre
+        } else {
+            Engine::FancyRegex
+        };
+        ret.map_err(|e| match e {
+            RuleError::Regex { pattern, offset, message } => {
+                let message = match unsupported_syntax(*self, &pattern) {
+                    Some(syntax) => {
+                        format!("{} isn't supported by the '{}' engine; use engine = '{}'",
+                                syntax,
+                                self.name(),
+                                other.name())
+                    }
+                    None if options.unicode == Some(false) && message.contains("UTF-8") => {
+                        format!("With unicode = false, the '{}' engine doesn't allow what may \
+                                 match a part of a character, e.g. '.' and '[^a]'",
+                                self.name())
+                    }
+                    None => message,
+                };
+                RuleError::Regex {
+                    pattern: pattern,
+                    offset: offset,
+                    message: message,
+                }
+            }
+            e => e,
+        })
+    }
+}
+
+/// The syntax in a pattern that the engine doesn't have, if any.  Only looks
+/// for the common cases; it's a hint for an error.
+fn unsupported_syntax(engine: Engine, pattern: &str) -> Option<&'static str> {
+    if engine != Engine::Regex {
+        return None;
+    }
+    let mut chars = pattern.chars().peekable();
+    let mut in_class = false;
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => {
+                match chars.next() {
+                    Some(c) if !in_class && c >= '1' && c <= '9' => return Some("Backreference"),
+                    Some('K') => return Some("'\\K'"),
+                    _ => (),
+                }
+            }
+            '[' => in_class = true,
+            ']' => in_class = false,
+            '(' if !in_class && chars.peek() == Some(&'?') => {
+                chars.next();
+                match chars.next() {
+                    Some('=') | Some('!') => return Some("Look-ahead"),
+                    Some('<') if chars.peek() == Some(&'=') || chars.peek() == Some(&'!') => {
+                        return Some("Look-behind")
+                    }
+                    Some('>') => return Some("Atomic group"),
+                    _ => (),
+                }
+            }
+            '*' | '+' | '?' | '}' if !in_class && chars.peek() == Some(&'+') => {
+                return Some("Possessive quantifier")
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+#[test]
+fn test_unsupported_syntax() {
+    assert_eq!(Some("Look-ahead"), unsupported_syntax(Engine::Regex, "a(?=b)"));
+    assert_eq!(Some("Look-behind"), unsupported_syntax(Engine::Regex, "(?<!a)b"));
+    assert_eq!(Some("Backreference"), unsupported_syntax(Engine::Regex, r"(a)\1"));
+    assert_eq!(Some("Atomic group"), unsupported_syntax(Engine::Regex, "(?>a)"));
+    assert_eq!(Some("Possessive quantifier"), unsupported_syntax(Engine::Regex, "a++"));
+    assert_eq!(None, unsupported_syntax(Engine::Regex, r"(?P<x>a)[\1+]\d+"));
+    assert_eq!(None, unsupported_syntax(Engine::Pcre, "a(?=b)"));
+}
+
+#[test]
+fn test_escape() {
+    assert_eq!(r"a\.b\\E", Engine::Pcre.escape(r"a.b\E"));
+    assert_eq!("é_1", Engine::Pcre.escape("é_1"));
+    let text = r"(a.b)\Q[c]\E {1} $x ~#";
+    for engine in &[Engine::Regex, Engine::Pcre, Engine::FancyRegex] {
+        if engine.is_available() {
+            let m = engine.compile(&engine.escape(text)).unwrap();
+            assert!(m.is_match(&format!("x{}x", text)), "{:?}", engine);
+            assert!(!m.is_match("(aXb)"), "{:?}", engine);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RegexMatcher {
+    re: Regex,
+}
+
+impl RegexMatcher {
+    fn compile(pattern: &str, options: Options) -> Result<Box<Matcher>, RuleError> {
+        let mut builder = RegexBuilder::new(pattern);
+        builder.case_insensitive(options.ignore_case);
+        if let Some(unicode) = options.unicode {
+            builder.unicode(unicode);
+        }
+        match builder.build() {
+            Ok(re) => Ok(Box::new(RegexMatcher { re: re })),
+            Err(e) => Err(regex_error(pattern, &e.to_string())),
+        }
+    }
+}
+
+/// An error of the regex crate, which shows the pattern with a caret under
+/// the error, and the message in the last line.
+fn regex_error(pattern: &str, error: &str) -> RuleError {
+    let lines: Vec<&str> = error.lines().collect();
+    let message = lines.last().map_or(error, |l| l.trim_left_matches("error: "));
+    // The pattern is indented by 4 spaces, and the caret is under it.
+    let offset = lines.iter()
+        .find(|l| l.trim_left().starts_with('^'))
+        .map_or(0, |l| l.find('^').unwrap().saturating_sub(4));
+    RuleError::Regex {
+        pattern: pattern.to_string(),
+        offset: offset,
+        message: message.to_string(),
+    }
+}
+
+impl Matcher for RegexMatcher {
+    fn capture_count(&self) -> usize {
+        self.re.captures_len() - 1
+    }
+
+    fn is_match(&self, line: &str) -> bool {
+        self.re.is_match(line)
+    }
+
+    fn captures(&self, line: &str) -> Option<Captures> {
+        self.re.captures(line).map(|c| c.iter().map(|m| m.map(|m| (m.start(), m.end()))).collect())
+    }
+
+    fn captures_all(&self, line: &str) -> Vec<Captures> {
+        self.re
+            .captures_iter(line)
+            .map(|c| c.iter().map(|m| m.map(|m| (m.start(), m.end()))).collect())
+            .collect()
+    }
+}
+
+#[test]
+fn test_regex_matcher() {
+    let m = Engine::Regex.compile("(a)(x)?b").unwrap();
+    assert_eq!(2, m.capture_count());
+    assert!(m.is_match("1ab"));
+    assert_eq!(Some(vec![Some((1, 3)), Some((1, 2)), None]), m.captures("1ab"));
+    assert_eq!(2, m.captures_all("ab axb").len());
+
+    match Engine::Regex.compile("a(?=b)").unwrap_err() {
+        RuleError::Regex { offset, message, .. } => {
+            assert_eq!(1, offset);
+            assert!(message.starts_with("Look-ahead isn't supported by the 'regex' engine; use \
+                                         engine = "));
+        }
+        e => panic!("{:?}", e),
+    }
+}
+
+#[cfg(feature = "pcre")]
+#[derive(Debug)]
+struct PcreMatcher {
+    re: Pcre,
+}
+
+// SAFETY: `Pcre` only holds raw pointers to the pattern compiled by
+// pcre_compile(), which this matcher owns alone.  That's plain heap memory,
+// and pcre_free() can release it from any thread.
+#[cfg(feature = "pcre")]
+unsafe impl Send for PcreMatcher {}
+// SAFETY: Only the `&self` methods `exec()`, `matches()` and `capture_count()`
+// are used, and each match allocates its own ovector.  libpcre doesn't alter a
+// compiled pattern while matching, so it "can safely be used by several
+// threads at once" (pcreapi(3), MULTITHREADING).  The `&mut self` methods that
+// change the pattern or make pcre_exec() write to it, `study()` and
+// `enable_mark()`, are never called.
+#[cfg(feature = "pcre")]
+unsafe impl Sync for PcreMatcher {}
+
+#[cfg(feature = "pcre")]
+impl PcreMatcher {
+    fn compile(pattern: &str, options: Options) -> Result<Box<Matcher>, RuleError> {
+        // The options go at the start of the pattern.
+        let mut prefix = String::new();
+        if options.unicode == Some(true) {
+            prefix.push_str("(*UTF8)(*UCP)");
+        }
+        if options.ignore_case {
+            prefix.push_str("(?i)");
+        }
+        let re = Pcre::compile(&format!("{}{}", prefix, pattern)).map_err(|e| {
+                RuleError::Regex {
+                    pattern: pattern.to_string(),
+                    offset: e.offset().saturating_sub(prefix.len()),
+                    message: e.message().unwrap_or("compilation failed".to_string()),
+                }
+            })?;
+        Ok(Box::new(PcreMatcher { re: re }))
+    }
+
+    fn to_captures(&self, m: &::pcre::Match) -> Captures {
+        (0..(self.re.capture_count() + 1))
+            .map(|i| if m.group_len(i) > 0 {
+                Some((m.group_start(i), m.group_end(i)))
+            } else {
+                None
+            })
+            .collect()
+    }
+}
+
+#[cfg(feature = "pcre")]
+impl Matcher for PcreMatcher {
+    fn capture_count(&self) -> usize {
+        self.re.capture_count()
+    }
+
+    fn is_match(&self, line: &str) -> bool {
+        self.re.exec(line).is_some()
+    }
+
+    fn captures(&self, line: &str) -> Option<Captures> {
+        self.re.exec(line).map(|m| self.to_captures(&m))
+    }
+
+    fn captures_all(&self, line: &str) -> Vec<Captures> {
+        self.re.matches(line).map(|m| self.to_captures(&m)).collect()
+    }
+}
+
+#[cfg(not(feature = "pcre"))]
+struct PcreMatcher;
+
+#[cfg(not(feature = "pcre"))]
+impl PcreMatcher {
+    fn compile(_: &str, _: Options) -> Result<Box<Matcher>, RuleError> {
+        unreachable!()
+    }
+}
+
+#[cfg(feature = "fancy-regex")]
+#[derive(Debug)]
+struct FancyMatcher {
+    re: fancy_regex::Regex,
+}
+
+#[cfg(feature = "fancy-regex")]
+impl FancyMatcher {
+    fn compile(pattern: &str, options: Options) -> Result<Box<Matcher>, RuleError> {
+        if options.unicode == Some(false) {
+            return Err(RuleError::new("The 'fancy-regex' engine can't turn off unicode."));
+        }
+        let re = fancy_regex::RegexBuilder::new(pattern)
+            .case_insensitive(options.ignore_case)
+            .build()
+            .map_err(|e| {
+                RuleError::Regex {
+                    pattern: pattern.to_string(),
+                    offset: 0,
+                    message: e.to_string(),
+                }
+            })?;
+        Ok(Box::new(FancyMatcher { re: re }))
+    }
+
+    fn to_captures(c: &fancy_regex::Captures) -> Captures {
+        c.iter().map(|m| m.map(|m| (m.start(), m.end()))).collect()
+    }
+}
+
+/// Errors while matching, e.g. too much backtracking, are taken as no match.
+#[cfg(feature = "fancy-regex")]
+impl Matcher for FancyMatcher {
+    fn capture_count(&self) -> usize {
+        self.re.captures_len() - 1
+    }
+
+    fn is_match(&self, line: &str) -> bool {
+        self.re.is_match(line).unwrap_or(false)
+    }
+
+    fn captures(&self, line: &str) -> Option<Captures> {
+        match self.re.captures(line) {
+            Ok(Some(c)) => Some(FancyMatcher::to_captures(&c)),
+            _ => None,
+        }
+    }
+
+    fn captures_all(&self, line: &str) -> Vec<Captures> {
+        self.re
+            .captures_iter(line)
+            .take_while(|c| c.is_ok())
+            .map(|c| FancyMatcher::to_captures(&c.unwrap()))
+            .collect()
+    }
+}
+
+#[cfg(not(feature = "fancy-regex"))]
+struct FancyMatcher;
+
+#[cfg(not(feature = "fancy-regex"))]
+impl FancyMatcher {
+    fn compile(_: &str, _: Options) -> Result<Box<Matcher>, RuleError> {
+        unreachable!()
+    }
+}
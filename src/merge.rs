@@ -0,0 +1,538 @@
+use std::f64;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Read};
+use std::mem;
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use libc;
+
+use super::*;
+use matcher::*;
+
+/// How many lines each reader thread may read ahead.
+const READ_AHEAD_LINES: usize = 1024;
+
+const MONTH_NAMES: [&'static str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug",
+                                         "sep", "oct", "nov", "dec"];
+
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+enum Field {
+    Year,
+    Month,
+    MonthName,
+    Day,
+    Hour,
+    Minute,
+    Second,
+    Fraction,
+    Epoch,
+    Uptime,
+    Offset,
+}
+
+/// A regex to find a timestamp in a line, and what each group means.
+#[derive(Debug)]
+struct TimePattern {
+    re: Box<Matcher>,
+    fields: Vec<Field>,
+}
+
+fn preset(name: &str) -> Option<(&'static str, Vec<Field>)> {
+    use self::Field::*;
+    Some(match name {
+        "iso" => {
+            (r"(\d{4})-(\d\d)-(\d\d)[T ](\d\d):(\d\d):(\d\d)(?:[.,](\d+))?(Z|[+-]\d\d:?\d\d)?",
+             vec![Year, Month, Day, Hour, Minute, Second, Fraction, Offset])
+        }
+        "logcat" => {
+            (r"(\d\d)-(\d\d)\s+(\d\d):(\d\d):(\d\d)\.(\d+)",
+             vec![Month, Day, Hour, Minute, Second, Fraction])
+        }
+        "syslog" => {
+            (r"([A-Za-z]{3})\s+(\d{1,2})\s+(\d\d):(\d\d):(\d\d)",
+             vec![MonthName, Day, Hour, Minute, Second])
+        }
+        "dmesg" => (r"^\[\s*(\d+)\.(\d+)\]", vec![Uptime, Fraction]),
+        "epoch" => (r"\b(\d{10})(?:\.(\d+))?\b", vec![Epoch, Fraction]),
+        _ => return None,
+    })
+}
+
+/// The presets "auto" tries, in this order.
+const AUTO_PRESETS: [&'static str; 5] = ["iso", "logcat", "syslog", "dmesg", "epoch"];
+
+fn escape_regex(ch: char, out: &mut String) {
+    if !ch.is_alphanumeric() && !ch.is_whitespace() {
+        out.push('\\');
+    }
+    out.push(ch);
+}
+
+/// Convert a strftime-like format, such as "%Y/%m/%d %H:%M:%S.%f", into a regex.
+fn compile_format(format: &str) -> Result<(String, Vec<Field>), RuleError> {
+    let mut pattern = String::new();
+    let mut fields = vec![];
+    let mut chars = format.chars();
+    while let Some(ch) = chars.next() {
+        if ch.is_whitespace() {
+            if !pattern.ends_with(r"\s+") {
+                pattern.push_str(r"\s+");
+            }
+            continue;
+        }
+        if ch != '%' {
+            escape_regex(ch, &mut pattern);
+            continue;
+        }
+        let (re, field) = match chars.next() {
+            Some('Y') => (r"(\d{4})", Field::Year),
+            Some('m') => (r"(\d{1,2})", Field::Month),
+            Some('b') => (r"([A-Za-z]{3})", Field::MonthName),
+            Some('d') => (r"(\d{1,2})", Field::Day),
+            Some('H') => (r"(\d{1,2})", Field::Hour),
+            Some('M') => (r"(\d\d)", Field::Minute),
+            Some('S') => (r"(\d\d)", Field::Second),
+            Some('f') => (r"(\d+)", Field::Fraction),
+            Some('s') => (r"(\d+)", Field::Epoch),
+            Some('z') => (r"(Z|[+-]\d\d:?\d\d)", Field::Offset),
+            Some('%') => {
+                pattern.push('%');
+                continue;
+            }
+            Some(c) => {
+                return Err(RuleError::new(&format!("Unknown time format directive: %{}", c)))
+            }
+            None => return Err(RuleError::new("Time format can't end with '%'")),
+        };
+        pattern.push_str(re);
+        fields.push(field);
+    }
+    if fields.len() == 0 {
+        return Err(RuleError::new(&format!("Time format has no fields: {}", format)));
+    }
+    Ok((pattern, fields))
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Seconds since the epoch of a time in the local time zone, which is given
+/// as if it were UTC.
+fn from_local(time: i64) -> i64 {
+    unsafe {
+        let t = time as libc::time_t;
+        let mut tm: libc::tm = mem::zeroed();
+        libc::gmtime_r(&t, &mut tm);
+        // Let mktime() find whether it's summer time.
+        tm.tm_isdst = -1;
+        libc::mktime(&mut tm) as i64
+    }
+}
+
+#[test]
+fn test_from_local() {
+    let time = days_from_civil(2020, 7, 1) * 86400 + 12 * 3600 + 34 * 60 + 56;
+    let t = from_local(time) as libc::time_t;
+    let tm = unsafe {
+        let mut tm: libc::tm = mem::zeroed();
+        libc::localtime_r(&t, &mut tm);
+        tm
+    };
+    assert_eq!((120, 6, 1, 12, 34, 56),
+               (tm.tm_year, tm.tm_mon, tm.tm_mday, tm.tm_hour, tm.tm_min, tm.tm_sec));
+}
+
+fn current_year() -> i64 {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64 / 86400)
+        .unwrap_or(0);
+    // Good enough to find the year; off by a day at most around new year.
+    let mut year = 1970 + days / 365;
+    while days_from_civil(year, 1, 1) > days {
+        year -= 1;
+    }
+    year
+}
+
+#[test]
+fn test_days_from_civil() {
+    assert_eq!(0, days_from_civil(1970, 1, 1));
+    assert_eq!(11016, days_from_civil(2000, 2, 29));
+    assert_eq!(-1, days_from_civil(1969, 12, 31));
+}
+
+impl TimePattern {
+    fn new(pattern: &str, fields: Vec<Field>) -> Result<TimePattern, RuleError> {
+        let re = Engine::default()
+            .compile(pattern)
+            .map_err(|_| RuleError::new(&format!("Invalid time format pattern: {}", pattern)))?;
+        Ok(TimePattern {
+            re: re,
+            fields: fields,
+        })
+    }
+
+    /// Whether the times are since the boot, as with dmesg.
+    fn is_uptime(&self) -> bool {
+        self.fields.contains(&Field::Uptime)
+    }
+
+    /// Seconds since the epoch.  A missing year is taken as `year`, times
+    /// without an offset are at `utc_offset`, or else in the local time zone,
+    /// and times since the boot are added to `boot_time`.
+    fn parse(&self,
+             line: &str,
+             year: i64,
+             utc_offset: Option<i64>,
+             boot_time: f64)
+             -> Option<f64> {
+        let m = match self.re.captures(line) {
+            Some(m) => m,
+            None => return None,
+        };
+        let (mut y, mut mo, mut d, mut h, mut mi, mut s) = (year, 1, 1, 0, 0, 0);
+        let mut frac = 0.0;
+        let mut epoch = None;
+        let mut since_boot = false;
+        let mut offset = None;
+        for (i, field) in self.fields.iter().enumerate() {
+            let text = group(line, &m, i + 1);
+            if text.len() == 0 {
+                continue;
+            }
+            let num = || text.parse::<i64>().unwrap_or(0);
+            match *field {
+                Field::Year => y = num(),
+                Field::Month => mo = num(),
+                Field::MonthName => {
+                    let lower = text.to_lowercase();
+                    mo = match MONTH_NAMES.iter().position(|&n| n == lower) {
+                        Some(p) => p as i64 + 1,
+                        None => return None,
+                    };
+                }
+                Field::Day => d = num(),
+                Field::Hour => h = num(),
+                Field::Minute => mi = num(),
+                Field::Second => s = num(),
+                Field::Fraction => frac = format!("0.{}", text).parse().unwrap_or(0.0),
+                Field::Epoch => epoch = Some(num()),
+                Field::Uptime => {
+                    epoch = Some(num());
+                    since_boot = true;
+                }
+                Field::Offset => {
+                    let mut o = 0;
+                    if text != "Z" {
+                        let digits: String = text.chars().filter(|c| c.is_digit(10)).collect();
+                        let hh: i64 = digits[0..2].parse().unwrap_or(0);
+                        let mm: i64 = digits[2..].parse().unwrap_or(0);
+                        o = (hh * 60 + mm) * 60;
+                        if text.starts_with('-') {
+                            o = -o;
+                        }
+                    }
+                    offset = Some(o);
+                }
+            }
+        }
+        let secs = match epoch {
+            Some(e) => e,
+            None => {
+                let utc = days_from_civil(y, mo, d) * 86400 + h * 3600 + mi * 60 + s;
+                match offset.or(utc_offset) {
+                    Some(o) => utc - o,
+                    None => from_local(utc),
+                }
+            }
+        };
+        let base = if since_boot { boot_time } else { 0.0 };
+        Some(base + secs as f64 + frac)
+    }
+}
+
+fn group(line: &str, m: &Captures, i: usize) -> String {
+    match m.get(i) {
+        Some(&Some((start, end))) => line[start..end].to_string(),
+        _ => String::new(),
+    }
+}
+
+/// The boot time of this machine in seconds since the epoch, from
+/// /proc/stat, for the timestamps of dmesg.
+pub fn boot_time() -> Option<f64> {
+    let file = match File::open("/proc/stat") {
+        Ok(f) => f,
+        Err(_) => return None,
+    };
+    for line in BufReader::new(file).lines() {
+        if let Ok(line) = line {
+            if line.starts_with("btime ") {
+                return line[6..].trim().parse().ok();
+            }
+        }
+    }
+    None
+}
+
+/// Where to find the timestamp in the lines of a merged input.  Either a
+/// preset (iso, logcat, syslog, dmesg, epoch), "auto" to detect one of them,
+/// or a strftime-like format, e.g. "%Y/%m/%d %H:%M:%S.%f".
+#[derive(Debug)]
+pub struct TimeFormat {
+    candidates: Vec<TimePattern>,
+    detected: Option<usize>,
+    year: i64,
+    /// The offset from UTC of the times without one in seconds, or `None` for
+    /// the local time zone.
+    utc_offset: Option<i64>,
+    boot_time: f64,
+}
+
+impl TimeFormat {
+    /// The format for `spec`.  The times of dmesg are since the boot, and
+    /// `boot_time` puts them on the same scale as the others.  Without it,
+    /// dmesg can't be used, and "auto" doesn't try it.
+    pub fn parse(spec: &str, boot_time: Option<f64>) -> Result<TimeFormat, RuleError> {
+        let mut candidates = vec![];
+        if spec == "auto" {
+            for name in AUTO_PRESETS.iter() {
+                let (pattern, fields) = preset(name).unwrap();
+                let pattern = TimePattern::new(pattern, fields)?;
+                if boot_time.is_some() || !pattern.is_uptime() {
+                    candidates.push(pattern);
+                }
+            }
+        } else if let Some((pattern, fields)) = preset(spec) {
+            let pattern = TimePattern::new(pattern, fields)?;
+            if boot_time.is_none() && pattern.is_uptime() {
+                return Err(RuleError::new(&format!("The times of {} are since the boot, \
+                                                     and the boot time is unknown",
+                                                    spec)));
+            }
+            candidates.push(pattern);
+        } else if spec.contains('%') {
+            let (pattern, fields) = compile_format(spec)?;
+            candidates.push(TimePattern::new(&pattern, fields)?);
+        } else {
+            return Err(RuleError::new(&format!("Invalid time format: {} (must be auto, iso, \
+                                                 logcat, syslog, dmesg, epoch or a format \
+                                                 with %-directives)",
+                                                spec)));
+        }
+        Ok(TimeFormat {
+            detected: if candidates.len() == 1 { Some(0) } else { None },
+            candidates: candidates,
+            year: current_year(),
+            utc_offset: None,
+            boot_time: boot_time.unwrap_or(0.0),
+        })
+    }
+
+    /// The time of a line in seconds, if it has a timestamp.  With "auto", the
+    /// first format that matches is used from then on.
+    pub fn timestamp(&mut self, line: &str) -> Option<f64> {
+        if let Some(i) = self.detected {
+            return self.candidates[i].parse(line, self.year, self.utc_offset, self.boot_time);
+        }
+        for i in 0..self.candidates.len() {
+            let t = self.candidates[i].parse(line, self.year, self.utc_offset, self.boot_time);
+            if let Some(t) = t {
+                debug!("Detected time format #{}", i);
+                self.detected = Some(i);
+                return Some(t);
+            }
+        }
+        None
+    }
+}
+
+#[test]
+fn test_time_format() {
+    let mut f = TimeFormat::parse("iso", None).unwrap();
+    f.utc_offset = Some(0);
+    assert_eq!(Some(1.5), f.timestamp("x 1970-01-01T00:00:01.5 y"));
+    assert_eq!(Some(3600.0), f.timestamp("1970-01-01 02:00:00+01:00"));
+    assert_eq!(None, f.timestamp("no time"));
+
+    let mut f = TimeFormat::parse("logcat", None).unwrap();
+    f.year = 1970;
+    f.utc_offset = Some(0);
+    assert_eq!(Some(86400.0 + 61.25), f.timestamp("01-02 00:01:01.250  123  456 I tag: x"));
+
+    let mut f = TimeFormat::parse("dmesg", Some(0.0)).unwrap();
+    assert_eq!(Some(12.000345), f.timestamp("[   12.000345] usb 1-1: new device"));
+    assert_eq!(None, f.timestamp("x [   12.000345]"));
+
+    let mut f = TimeFormat::parse("syslog", None).unwrap();
+    f.year = 1970;
+    f.utc_offset = Some(3600);
+    assert_eq!(Some(31.0 * 86400.0 + 3.0), f.timestamp("Feb  1 01:00:03 host kernel: x"));
+
+    let mut f = TimeFormat::parse("%d/%m/%Y %H:%M:%S", None).unwrap();
+    f.utc_offset = Some(0);
+    assert_eq!(Some(86400.0 + 7.0), f.timestamp("[02/01/1970 00:00:07] x"));
+
+    let mut f = TimeFormat::parse("auto", Some(0.0)).unwrap();
+    assert_eq!(None, f.timestamp("no time"));
+    assert_eq!(Some(5.0), f.timestamp("[    5.000000] x"));
+    // Now stuck with dmesg.
+    assert_eq!(None, f.timestamp("1970-01-01T00:00:01"));
+
+    let mut f = TimeFormat::parse("dmesg", Some(1000.5)).unwrap();
+    assert_eq!(Some(1012.5), f.timestamp("[   12.000000] x"));
+    assert!(TimeFormat::parse("dmesg", None).is_err());
+    let mut f = TimeFormat::parse("auto", None).unwrap();
+    assert_eq!(None, f.timestamp("[    5.000000] x"));
+
+    assert!(TimeFormat::parse("%Y %q", None).is_err());
+    assert!(TimeFormat::parse("nonsense", None).is_err());
+}
+
+#[derive(Debug)]
+struct MergeSource {
+    rx: Receiver<io::Result<String>>,
+    format: TimeFormat,
+    head: Option<(f64, io::Result<String>)>,
+    last_time: f64,
+    done: bool,
+}
+
+/// Reads several inputs concurrently, and interleaves their lines by
+/// timestamp.  Lines without a timestamp, such as stack traces, stay after
+/// the line before them.  Gives the index of the input and the line.
+#[derive(Debug)]
+pub struct Merger {
+    sources: Vec<MergeSource>,
+}
+
+impl Merger {
+    pub fn new() -> Merger {
+        Merger { sources: vec![] }
+    }
+
+    pub fn add<R: Read + Send + 'static>(&mut self, reader: R, format: TimeFormat) {
+        let (tx, rx) = sync_channel(READ_AHEAD_LINES);
+        thread::spawn(move || {
+            for line in BufReader::new(reader).lines() {
+                let stop = match line {
+                    Err(ref e) => e.kind() != io::ErrorKind::InvalidData,
+                    _ => false,
+                };
+                if tx.send(line).is_err() || stop {
+                    return;
+                }
+            }
+        });
+        self.sources.push(MergeSource {
+            rx: rx,
+            format: format,
+            head: None,
+            last_time: f64::NEG_INFINITY,
+            done: false,
+        });
+    }
+
+    fn fill(source: &mut MergeSource) {
+        if source.head.is_some() || source.done {
+            return;
+        }
+        match source.rx.recv() {
+            Ok(Ok(line)) => {
+                if let Some(t) = source.format.timestamp(&line) {
+                    source.last_time = t;
+                }
+                source.head = Some((source.last_time, Ok(line)));
+            }
+            Ok(Err(e)) => source.head = Some((source.last_time, Err(e))),
+            Err(_) => source.done = true,
+        }
+    }
+}
+
+impl Iterator for Merger {
+    type Item = (usize, io::Result<String>);
+
+    fn next(&mut self) -> Option<(usize, io::Result<String>)> {
+        let mut next: Option<(usize, f64)> = None;
+        for (i, source) in self.sources.iter_mut().enumerate() {
+            Merger::fill(source);
+            if let Some((t, _)) = source.head {
+                // On a tie, the earlier input goes first.
+                if next.map(|(_, nt)| t < nt).unwrap_or(true) {
+                    next = Some((i, t));
+                }
+            }
+        }
+        next.map(|(i, _)| (i, self.sources[i].head.take().unwrap().1))
+    }
+}
+
+#[test]
+fn test_merger() {
+    use std::io::Cursor;
+
+    let mut m = Merger::new();
+    m.add(Cursor::new("[    1.000000] a1\n[    3.000000] a3\n  a3 cont\n".as_bytes().to_vec()),
+          TimeFormat::parse("dmesg", Some(0.0)).unwrap());
+    m.add(Cursor::new("x\n[    2.000000] b2\n[    3.000000] b3\n[    4.000000] b4\n"
+                  .as_bytes()
+                  .to_vec()),
+          TimeFormat::parse("auto", Some(0.0)).unwrap());
+    let lines: Vec<(usize, String)> = m.map(|(i, l)| (i, l.unwrap())).collect();
+    assert_eq!(vec![(1, "x".to_string()),
+                    (0, "[    1.000000] a1".to_string()),
+                    (1, "[    2.000000] b2".to_string()),
+                    (0, "[    3.000000] a3".to_string()),
+                    (0, "  a3 cont".to_string()),
+                    (1, "[    3.000000] b3".to_string()),
+                    (1, "[    4.000000] b4".to_string())],
+               lines);
+}
+
+#[test]
+fn test_merger_dmesg_iso() {
+    use std::io::Cursor;
+
+    // Booted at 1970-01-01T01:00:00.
+    let mut m = Merger::new();
+    m.add(Cursor::new("[    1.500000] a\n[    3.000000] b\n".as_bytes().to_vec()),
+          TimeFormat::parse("dmesg", Some(3600.0)).unwrap());
+    m.add(Cursor::new("1970-01-01T01:00:02Z c\n1970-01-01T00:59:00Z d\n".as_bytes().to_vec()),
+          TimeFormat::parse("iso", Some(3600.0)).unwrap());
+    let lines: Vec<(usize, String)> = m.map(|(i, l)| (i, l.unwrap())).collect();
+    assert_eq!(vec![(0, "[    1.500000] a".to_string()),
+                    (1, "1970-01-01T01:00:02Z c".to_string()),
+                    (1, "1970-01-01T00:59:00Z d".to_string()),
+                    (0, "[    3.000000] b".to_string())],
+               lines);
+}
+
+#[test]
+fn test_merger_local_epoch() {
+    use std::io::Cursor;
+
+    // In UTC+1, 01:00:05 is 1577836805 (2020-01-01T00:00:05Z).
+    let mut logcat = TimeFormat::parse("logcat", None).unwrap();
+    logcat.year = 2020;
+    logcat.utc_offset = Some(3600);
+    let mut m = Merger::new();
+    m.add(Cursor::new("01-01 01:00:05.000 a\n".as_bytes().to_vec()), logcat);
+    m.add(Cursor::new("1577836804 b\n1577836806 c\n".as_bytes().to_vec()),
+          TimeFormat::parse("epoch", None).unwrap());
+    let lines: Vec<(usize, String)> = m.map(|(i, l)| (i, l.unwrap())).collect();
+    assert_eq!(vec![(1, "1577836804 b".to_string()),
+                    (0, "01-01 01:00:05.000 a".to_string()),
+                    (1, "1577836806 c".to_string())],
+               lines);
+}
@@ -0,0 +1,85 @@
+use std::env;
+use std::io;
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+const DEFAULT_PAGER: &'static str = "less -R";
+
+/// Default options for less, same as git: quit if one screen, raw colors, no init.
+const DEFAULT_LESS: &'static str = "FRX";
+
+/// A pager process, which we write the output to.
+#[derive(Debug)]
+pub struct Pager {
+    child: Child,
+}
+
+/// Returns the LESS environment variable to use, making sure the escape
+/// sequences we generate are passed through.
+fn less_env(current: Option<String>) -> String {
+    match current {
+        None => DEFAULT_LESS.to_string(),
+        Some(ref v) if v.trim().len() == 0 => DEFAULT_LESS.to_string(),
+        Some(v) => {
+            if v.split_whitespace().any(is_raw_option) {
+                v
+            } else {
+                format!("{} -R", v)
+            }
+        }
+    }
+}
+
+/// Whether an option of less passes escape sequences through: -R or -r, in a
+/// cluster of short options with or without the dash, or their long names.
+fn is_raw_option(option: &str) -> bool {
+    if option.starts_with("--") {
+        option == "--RAW-CONTROL-CHARS" || option == "--raw-control-chars"
+    } else {
+        option.contains('R') || option.contains('r')
+    }
+}
+
+#[test]
+fn test_less_env() {
+    assert_eq!("FRX", less_env(None));
+    assert_eq!("FRX", less_env(Some("".to_string())));
+    assert_eq!("-iR", less_env(Some("-iR".to_string())));
+    assert_eq!("-i -R", less_env(Some("-i".to_string())));
+    assert_eq!("--quit-if-one-screen -R",
+               less_env(Some("--quit-if-one-screen".to_string())));
+    assert_eq!("-F --RAW-CONTROL-CHARS", less_env(Some("-F --RAW-CONTROL-CHARS".to_string())));
+    assert_eq!("--raw-control-chars", less_env(Some("--raw-control-chars".to_string())));
+}
+
+impl Pager {
+    /// Start $PAGER, or "less -R" if it's not set.
+    pub fn spawn() -> io::Result<Pager> {
+        let command = env::var("PAGER").unwrap_or(String::new());
+        let mut args: Vec<&str> = command.split_whitespace().collect();
+        if args.len() == 0 {
+            args = DEFAULT_PAGER.split_whitespace().collect();
+        }
+        debug!("Starting pager: {:?}", args);
+
+        let child = Command::new(args[0])
+            .args(&args[1..])
+            .env("LESS", less_env(env::var("LESS").ok()))
+            .env("LV", env::var("LV").unwrap_or("-c".to_string()))
+            .stdin(Stdio::piped())
+            .spawn()?;
+        Ok(Pager { child: child })
+    }
+
+    /// Take the pipe to the pager.  The pager will finish once it's dropped.
+    pub fn take_input(&mut self) -> Option<ChildStdin> {
+        self.child.stdin.take()
+    }
+
+    /// Wait for the user to quit the pager.
+    pub fn wait(mut self) -> io::Result<()> {
+        drop(self.child.stdin.take());
+        let status = self.child.wait()?;
+        debug!("Pager finished: {}", status);
+        Ok(())
+    }
+}
@@ -0,0 +1,342 @@
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::mem;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, ExitStatus, Stdio};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender};
+use std::thread;
+use std::time::Duration;
+use libc;
+
+use tty;
+
+/// How long to wait for the rest of a line before showing what we have.
+const PARTIAL_DELAY_MS: u64 = 50;
+
+/// How often to check the window size while the command is quiet.
+const POLL_INTERVAL_MS: u64 = 100;
+
+/// How many reads may wait for the output.  Past that, the command blocks on
+/// the full terminal.
+const READ_AHEAD_CHUNKS: usize = 16;
+
+/// What the command wrote.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum PtyOutput {
+    /// A complete line, without the line terminator.
+    Line(String),
+    /// The start of a line that's been pending for a while, e.g. a prompt.
+    /// It grows until the line is complete, and then it's given as a `Line`.
+    Partial(String),
+}
+
+/// Runs a command under a pseudo-terminal, like script(1), so it keeps its
+/// line buffering.  The input and the window size of our terminal are passed
+/// through.  The escape sequences of the command, e.g. its colors, are
+/// removed from the output, so that they don't get in the way of the rules.
+pub struct Pty {
+    master: File,
+    child: Child,
+    rx: Receiver<io::Result<Vec<u8>>>,
+    closed: bool,
+    buffer: Vec<u8>,
+    shown: usize,
+    window_size: Option<(usize, usize)>,
+    _raw: Option<tty::RawMode>,
+}
+
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Open a new pseudo-terminal, and returns the master and the slave.
+fn open_pty() -> io::Result<(File, File)> {
+    unsafe {
+        let fd = check(libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY))?;
+        let master = File::from_raw_fd(fd);
+        check(libc::grantpt(fd))?;
+        check(libc::unlockpt(fd))?;
+        let name = libc::ptsname(fd);
+        if name.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let name = CStr::from_ptr(name).to_string_lossy().into_owned();
+        let slave = OpenOptions::new().read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&name)?;
+        Ok((master, slave))
+    }
+}
+
+fn set_window_size(fd: RawFd, size: (usize, usize)) {
+    unsafe {
+        let mut ws: libc::winsize = mem::zeroed();
+        ws.ws_col = size.0 as libc::c_ushort;
+        ws.ws_row = size.1 as libc::c_ushort;
+        libc::ioctl(fd, libc::TIOCSWINSZ, &ws);
+    }
+}
+
+/// Remove the escape sequences, i.e. CSI ("\x1b[...m"), OSC ("\x1b]...\x07")
+/// and the shorter ones.  An unfinished one at the end is removed too.
+fn strip_escapes(data: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] != 0x1b {
+            ret.push(data[i]);
+            i += 1;
+            continue;
+        }
+        i += 1;
+        match data.get(i) {
+            Some(&b'[') => {
+                // Parameters and intermediate bytes, then the final byte.
+                i += 1;
+                while i < data.len() && !(data[i] >= 0x40 && data[i] <= 0x7e) {
+                    i += 1;
+                }
+                i += 1;
+            }
+            Some(&b']') => {
+                // Ended by BEL or ST ("\x1b\\").
+                i += 1;
+                while i < data.len() && data[i] != 0x07 && data[i] != 0x1b {
+                    i += 1;
+                }
+                i += if data.get(i) == Some(&0x1b) { 2 } else { 1 };
+            }
+            Some(_) => {
+                // Intermediate bytes, e.g. "(" in "\x1b(B", then the final byte.
+                while i < data.len() && data[i] >= 0x20 && data[i] <= 0x2f {
+                    i += 1;
+                }
+                i += 1;
+            }
+            None => (),
+        }
+    }
+    ret
+}
+
+#[test]
+fn test_strip_escapes() {
+    assert_eq!(b"red x".to_vec(), strip_escapes(b"\x1b[1;31mred\x1b[0m x"));
+    assert_eq!(b"ab".to_vec(), strip_escapes(b"a\x1b]0;title\x07b"));
+    assert_eq!(b"ab".to_vec(), strip_escapes(b"a\x1b]0;title\x1b\\b"));
+    assert_eq!(b"ab".to_vec(), strip_escapes(b"a\x1b(Bb"));
+    assert_eq!(b"a".to_vec(), strip_escapes(b"a\x1b[1;3"));
+    assert_eq!(b"a".to_vec(), strip_escapes(b"a\x1b"));
+}
+
+fn stdio(file: &File) -> io::Result<Stdio> {
+    Ok(unsafe { Stdio::from_raw_fd(file.try_clone()?.into_raw_fd()) })
+}
+
+fn read_output(mut master: File, tx: SyncSender<io::Result<Vec<u8>>>) {
+    thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        loop {
+            let res = match master.read(&mut buf) {
+                Ok(0) => return,
+                // Linux gives EIO once the slave side is closed.
+                Err(ref e) if e.raw_os_error() == Some(libc::EIO) => return,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Ok(n) => Ok(buf[..n].to_vec()),
+                Err(e) => Err(e),
+            };
+            let stop = res.is_err();
+            if tx.send(res).is_err() || stop {
+                return;
+            }
+        }
+    });
+}
+
+/// Copy our stdin to the command.  On the end of the input, send ^D, like
+/// the user typing it.
+fn write_input(mut master: File) {
+    thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        let stdin = io::stdin();
+        let mut stdin = stdin.lock();
+        loop {
+            let n = match stdin.read(&mut buf) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => 0,
+            };
+            let data: &[u8] = if n == 0 { b"\x04" } else { &buf[..n] };
+            if master.write_all(data).is_err() || n == 0 {
+                return;
+            }
+        }
+    });
+}
+
+impl Pty {
+    /// Start `args[0]` with the rest of the arguments on a new pseudo-terminal.
+    /// If our stdin is a terminal, it's put into the raw mode until the `Pty`
+    /// is dropped, so that keys like ^C go to the command.
+    pub fn spawn(args: &[String]) -> io::Result<Pty> {
+        let (master, slave) = open_pty()?;
+        let window_size = tty::window_size(tty::STDIN_FD)
+            .or_else(|| tty::window_size(tty::STDOUT_FD));
+        if let Some(size) = window_size {
+            set_window_size(master.as_raw_fd(), size);
+        }
+
+        let mut command = ::std::process::Command::new(&args[0]);
+        command.args(&args[1..])
+            .stdin(stdio(&slave)?)
+            .stdout(stdio(&slave)?)
+            .stderr(stdio(&slave)?);
+        #[allow(deprecated)]
+        command.before_exec(|| {
+            // Become a session leader, and make the slave the controlling
+            // terminal, so that job control and ^C work.
+            unsafe {
+                check(libc::setsid())?;
+                check(libc::ioctl(0, libc::TIOCSCTTY, 0))?;
+            }
+            Ok(())
+        });
+        let child = command.spawn()?;
+        // Only the command holds the slave now, so we see the end of it.
+        drop(slave);
+
+        let raw = if tty::is_tty(tty::STDIN_FD) {
+            Some(tty::RawMode::enable_input(tty::STDIN_FD)?)
+        } else {
+            None
+        };
+
+        let (tx, rx) = sync_channel(READ_AHEAD_CHUNKS);
+        read_output(master.try_clone()?, tx);
+        write_input(master.try_clone()?);
+        Ok(Pty {
+            master: master,
+            child: child,
+            rx: rx,
+            closed: false,
+            buffer: vec![],
+            shown: 0,
+            window_size: window_size,
+            _raw: raw,
+        })
+    }
+
+    /// Pass a change of our window size to the command.  It's polled, rather
+    /// than taken from SIGWINCH, which is left for the width detection.
+    fn update_window_size(&mut self) {
+        let size = tty::window_size(tty::STDIN_FD).or_else(|| tty::window_size(tty::STDOUT_FD));
+        if size.is_some() && size != self.window_size {
+            debug!("Window size changed to {:?}", size);
+            set_window_size(self.master.as_raw_fd(), size.unwrap());
+            self.window_size = size;
+        }
+    }
+
+    /// Split off the first line in the buffer, if any.
+    fn take_line(&mut self, force: bool) -> Option<String> {
+        let end = match self.buffer.iter().position(|&b| b == b'\n') {
+            Some(pos) => pos + 1,
+            None if force && self.buffer.len() > 0 => self.buffer.len(),
+            None => return None,
+        };
+        let mut line: Vec<u8> = self.buffer.drain(..end).collect();
+        while line.last().map(|&b| b == b'\n' || b == b'\r').unwrap_or(false) {
+            line.pop();
+        }
+        self.shown = 0;
+        Some(String::from_utf8_lossy(&strip_escapes(&line)).into_owned())
+    }
+
+    /// The next line, or the pending part of it if nothing has come for a
+    /// while.  `None` when the command has closed the terminal.
+    pub fn next_output(&mut self) -> Option<io::Result<PtyOutput>> {
+        self.next_output_or_idle(|| ())
+    }
+
+    /// Same as `next_output()`, but calls `idle` every so often while the
+    /// command is quiet, e.g. to reload the rules.
+    pub fn next_output_or_idle<F: FnMut()>(&mut self,
+                                           mut idle: F)
+                                           -> Option<io::Result<PtyOutput>> {
+        loop {
+            let closed = self.closed;
+            if let Some(line) = self.take_line(closed) {
+                return Some(Ok(PtyOutput::Line(line)));
+            }
+            if self.closed {
+                return None;
+            }
+            let pending = self.buffer.len() > self.shown;
+            let timeout = if pending { PARTIAL_DELAY_MS } else { POLL_INTERVAL_MS };
+            match self.rx.recv_timeout(Duration::from_millis(timeout)) {
+                Ok(Ok(data)) => self.buffer.extend(data),
+                Ok(Err(e)) => return Some(Err(e)),
+                Err(RecvTimeoutError::Timeout) if pending => {
+                    self.shown = self.buffer.len();
+                    let partial = String::from_utf8_lossy(&strip_escapes(&self.buffer))
+                        .into_owned();
+                    return Some(Ok(PtyOutput::Partial(partial)));
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    self.update_window_size();
+                    idle();
+                }
+                Err(RecvTimeoutError::Disconnected) => self.closed = true,
+            }
+        }
+    }
+
+    /// Wait for the command to finish.
+    pub fn wait(mut self) -> io::Result<ExitStatus> {
+        self.child.wait()
+    }
+
+    /// Stop the command, e.g. when the output is closed.
+    pub fn terminate(mut self) -> io::Result<ExitStatus> {
+        unsafe {
+            libc::kill(self.child.id() as libc::pid_t, libc::SIGTERM);
+        }
+        self.child.wait()
+    }
+}
+
+#[test]
+fn test_pty() {
+    // The command waits for the file, which is made once the partial line is
+    // seen.
+    let go = ::std::env::temp_dir().join(format!("hl-test-pty-{}", ::std::process::id()));
+    let _ = ::std::fs::remove_file(&go);
+    let script = format!("printf 'a\\nb'; while [ ! -e '{}' ]; do sleep 0.01; done; \
+                          printf '\\033[31mc\\033[0m\\n'; test -t 1 && echo tty; exit 2",
+                         go.display());
+    let mut pty = Pty::spawn(&["sh".to_string(), "-c".to_string(), script]).unwrap();
+    let mut output = vec![];
+    while let Some(o) = pty.next_output() {
+        let o = o.unwrap();
+        if let PtyOutput::Partial(_) = o {
+            File::create(&go).unwrap();
+        }
+        output.push(o);
+    }
+    ::std::fs::remove_file(&go).unwrap();
+    assert_eq!(vec![PtyOutput::Line("a".to_string()),
+                    PtyOutput::Partial("b".to_string()),
+                    PtyOutput::Line("bc".to_string()),
+                    PtyOutput::Line("tty".to_string())],
+               output);
+    assert_eq!(Some(2), pty.wait().unwrap().code());
+}
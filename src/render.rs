@@ -0,0 +1,268 @@
+use std::io;
+use std::io::prelude::*;
+
+use super::*;
+use term_color::*;
+use filter::*;
+use svg::{escape_xml, SvgRenderer};
+
+/// Turns highlighted lines into output, e.g. ANSI escape sequences.  Outputs
+/// that aren't in this crate can be written by implementing it.
+pub trait Renderer {
+    /// Write a line, with its pre and post lines.
+    fn render(&mut self, line: &HighlightedLine, out: &mut Write) -> io::Result<()>;
+
+    /// Write what comes after all the lines, e.g. the end of a document.
+    fn finish(&mut self, _out: &mut Write) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Write runs of text, each after the escape sequences of its foreground and
+/// background, e.g. from `Colors::fg_code()`.  Colors are reset between runs,
+/// and at the end.  A first run without colors is written as it is.
+pub fn write_ansi_runs<I, S, T>(term: Term, runs: I, out: &mut Write) -> io::Result<()>
+    where I: IntoIterator<Item = (S, S, T)>,
+          S: AsRef<str>,
+          T: AsRef<[u8]>
+{
+    let mut in_color = false;
+    for (i, (fg_code, bg_code, text)) in runs.into_iter().enumerate() {
+        let (fg_code, bg_code) = (fg_code.as_ref(), bg_code.as_ref());
+        if i > 0 || fg_code.len() > 0 || bg_code.len() > 0 {
+            if in_color {
+                try!(out.write_all(term.csi_reset().as_bytes()));
+            }
+            try!(out.write_all(fg_code.as_bytes()));
+            try!(out.write_all(bg_code.as_bytes()));
+            in_color = true;
+        }
+        try!(out.write_all(text.as_ref()));
+    }
+    if in_color {
+        try!(out.write_all(term.csi_reset().as_bytes()));
+    }
+    Ok(())
+}
+
+/// Write the spans with ANSI escape sequences.
+fn write_ansi(term: Term, spans: &[StyledSpan], out: &mut Write) -> io::Result<()> {
+    let colors: Vec<Colors> = spans.iter()
+        .map(|s| Colors::with_colors(s.fg, s.bg, s.attrs, term))
+        .collect();
+    let runs = colors.iter()
+        .zip(spans)
+        .map(|(c, s)| (c.fg_code().as_str(), c.bg_code().as_str(), s.text.as_bytes()));
+    write_ansi_runs(term, runs, out)
+}
+
+fn write_ansi_decoration(term: Term, d: &Decoration, out: &mut Write) -> io::Result<()> {
+    match d.colors {
+        Some(ref c) => {
+            write!(out,
+                   "{}{}{}{}\n",
+                   c.fg_code(),
+                   c.bg_code(),
+                   d.text,
+                   term.csi_reset())
+        }
+        None => write!(out, "{}\n", d.text),
+    }
+}
+
+/// Renders for terminals, with ANSI escape sequences.
+#[derive(Debug)]
+pub struct AnsiRenderer {
+    term: Term,
+}
+
+impl AnsiRenderer {
+    pub fn new(term: Term) -> AnsiRenderer {
+        AnsiRenderer { term: term }
+    }
+}
+
+impl Renderer for AnsiRenderer {
+    fn render(&mut self, line: &HighlightedLine, out: &mut Write) -> io::Result<()> {
+        for d in &line.pre_lines {
+            try!(write_ansi_decoration(self.term, d, out));
+        }
+        try!(write_ansi(self.term, &line.spans, out));
+        try!(out.write_all(b"\n"));
+        for d in &line.post_lines {
+            try!(write_ansi_decoration(self.term, d, out));
+        }
+        Ok(())
+    }
+}
+
+/// Renders without colors, only with the pre and post lines.
+#[derive(Debug,Default)]
+pub struct PlainRenderer;
+
+impl Renderer for PlainRenderer {
+    fn render(&mut self, line: &HighlightedLine, out: &mut Write) -> io::Result<()> {
+        for d in &line.pre_lines {
+            try!(write!(out, "{}\n", d.text));
+        }
+        try!(write!(out, "{}\n", line.text));
+        for d in &line.post_lines {
+            try!(write!(out, "{}\n", d.text));
+        }
+        Ok(())
+    }
+}
+
+/// The CSS of a span, e.g. "color:#cd0000;font-weight:bold".
+fn css(span: &StyledSpan) -> String {
+    let mut ret = vec![];
+    if let Some((r, g, b)) = span.fg.rgb() {
+        ret.push(format!("color:#{:02x}{:02x}{:02x}", r, g, b));
+    }
+    if let Some((r, g, b)) = span.bg.rgb() {
+        ret.push(format!("background-color:#{:02x}{:02x}{:02x}", r, g, b));
+    }
+    if span.attrs.contains(ATTR_INTENSE) {
+        ret.push("font-weight:bold".to_string());
+    }
+    if span.attrs.contains(ATTR_ITALIC) {
+        ret.push("font-style:italic".to_string());
+    }
+    if span.attrs.contains(ATTR_FAINT) {
+        ret.push("opacity:0.6".to_string());
+    }
+    if span.attrs.contains(ATTR_UNDERLINE) {
+        ret.push("text-decoration:underline".to_string());
+    } else if span.attrs.contains(ATTR_STRIKE) {
+        ret.push("text-decoration:line-through".to_string());
+    }
+    ret.join(";")
+}
+
+/// Renders HTML, as lines of `<span>`s to put in a `<pre>`.
+#[derive(Debug,Default)]
+pub struct HtmlRenderer;
+
+impl HtmlRenderer {
+    fn write_spans(spans: &[StyledSpan], out: &mut Write) -> io::Result<()> {
+        for span in spans {
+            let style = css(span);
+            if style.len() > 0 {
+                try!(write!(out, "<span style=\"{}\">{}</span>", style, escape_xml(&span.text)));
+            } else {
+                try!(write!(out, "{}", escape_xml(&span.text)));
+            }
+        }
+        out.write_all(b"\n")
+    }
+}
+
+impl Renderer for HtmlRenderer {
+    fn render(&mut self, line: &HighlightedLine, out: &mut Write) -> io::Result<()> {
+        for d in &line.pre_lines {
+            try!(HtmlRenderer::write_spans(&[d.span()], out));
+        }
+        try!(HtmlRenderer::write_spans(&line.spans, out));
+        for d in &line.post_lines {
+            try!(HtmlRenderer::write_spans(&[d.span()], out));
+        }
+        Ok(())
+    }
+}
+
+/// Adds the lines as rows, and writes the document when finished.
+impl Renderer for SvgRenderer {
+    fn render(&mut self, line: &HighlightedLine, _out: &mut Write) -> io::Result<()> {
+        for d in &line.pre_lines {
+            self.add_line(&StyledLine::Text(vec![d.span()]));
+        }
+        self.add_line(&StyledLine::Text(line.spans.clone()));
+        for d in &line.post_lines {
+            self.add_line(&StyledLine::Text(vec![d.span()]));
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut Write) -> io::Result<()> {
+        out.write_all(SvgRenderer::finish(self).as_bytes())
+    }
+}
+
+#[cfg(test)]
+fn test_filter() -> Filter {
+    use rule::*;
+
+    let mut error = Rule::new("E (\\w+)").unwrap();
+    error.set_match_colors(Colors::with_colors(Color::Console(1), Color::None, ATTR_INTENSE, Term::Xterm))
+        .set_line_colors(Colors::with_colors(Color::None, Color::Console(4), ATTR_NONE, Term::Xterm))
+        .set_pre_line(DecorativeLine::new("*",
+                                          Some(Colors::with_colors(Color::Console(3),
+                                                                   Color::None,
+                                                                   ATTR_NONE,
+                                                                   Term::Xterm)),
+                                          Term::Xterm,
+                                          4))
+        .set_post_line(DecorativeLine::new("- $1 -", None, Term::Xterm, 10));
+    let mut tag = Rule::new("<a>").unwrap();
+    tag.set_match_colors(Colors::with_colors(Color::Rgb(0, 0xcd, 0), Color::None, ATTR_UNDERLINE, Term::Xterm));
+    Filter::new(Term::Xterm, vec![error, tag])
+}
+
+#[test]
+fn test_ansi_renderer() {
+    let expected = ["\x1b[33m****\x1b[0m\n\
+                     \x1b[44mx \x1b[0m\x1b[4m\x1b[38;5;40m\x1b[44m<a>\x1b[0m\
+                     \x1b[44m E \x1b[0m\x1b[1m\x1b[31m\x1b[44mmain\x1b[0m\x1b[44m y\x1b[0m\n\
+                     -- main --\n",
+                    "\x1b[4m\x1b[38;5;40m<a>\x1b[0m\n",
+                    "none\n"];
+    let lines = ["x <a> E main y", "<a>", "none"];
+    let mut renderer = AnsiRenderer::new(Term::Xterm);
+    let mut filter = test_filter();
+    for (line, expected) in lines.iter().zip(&expected) {
+        let mut out = vec![];
+        renderer.render(&filter.highlight(line), &mut out).unwrap();
+        assert_eq!(*expected, String::from_utf8(out).unwrap());
+    }
+    // The same as process_to().
+    let mut filter = test_filter();
+    for (line, expected) in lines.iter().zip(&expected) {
+        let mut out = vec![];
+        filter.process_to(line, &mut out).unwrap();
+        assert_eq!(*expected, String::from_utf8(out).unwrap());
+    }
+}
+
+#[test]
+fn test_plain_renderer() {
+    let mut out = vec![];
+    PlainRenderer.render(&test_filter().highlight("E main"), &mut out).unwrap();
+    assert_eq!("****\nE main\n-- main --\n", String::from_utf8(out).unwrap());
+}
+
+#[test]
+fn test_html_renderer() {
+    let mut out = vec![];
+    HtmlRenderer.render(&test_filter().highlight("<a> E x"), &mut out).unwrap();
+    assert_eq!("<span style=\"color:#cdcd00\">****</span>\n\
+                <span style=\"color:#00cd00;background-color:#0000ee;\
+                text-decoration:underline\">&lt;a&gt;</span>\
+                <span style=\"background-color:#0000ee\"> E </span>\
+                <span style=\"color:#cd0000;background-color:#0000ee;font-weight:bold\">x</span>\n\
+                --- x ----\n",
+               String::from_utf8(out).unwrap());
+}
+
+#[test]
+fn test_svg_renderer() {
+    let mut filter = test_filter();
+    let mut expected = SvgRenderer::new(20);
+    filter.process_styled("E main", |l| expected.add_line(&l));
+
+    let mut renderer = SvgRenderer::new(20);
+    let mut out = vec![];
+    renderer.render(&filter.highlight("E main"), &mut out).unwrap();
+    assert!(out.is_empty());
+    Renderer::finish(&mut renderer, &mut out).unwrap();
+    assert_eq!(expected.finish(), String::from_utf8(out).unwrap());
+}
@@ -1,25 +1,228 @@
 use std;
 use std::iter;
-use std::rc::Rc;
+use std::sync::Arc;
 use std::cell::*;
-use pcre::{CompileOption, Match, Pcre};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
 use super::*;
 use term_color::*;
+use matcher::*;
+
+/// Where to put the title of a decorative line.
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+impl Align {
+    pub fn parse(value: &str) -> Option<Align> {
+        match value {
+            "left" => Some(Align::Left),
+            "center" => Some(Align::Center),
+            "right" => Some(Align::Right),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Align::Left => "left",
+            Align::Center => "center",
+            Align::Right => "right",
+        }
+    }
+}
+
+/// Which output of a command a line came from.  Lines from files are taken
+/// as stdout.
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    pub fn parse(value: &str) -> Option<Stream> {
+        match value {
+            "stdout" => Some(Stream::Stdout),
+            "stderr" => Some(Stream::Stderr),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
+
+/// Number of columns a character takes on a terminal.
+pub fn char_width(ch: char) -> usize {
+    UnicodeWidthChar::width(ch).unwrap_or(0)
+}
+
+/// Repeat the mark to fill exactly `width` columns.  If a wide character
+/// doesn't fit at the end, pad with spaces.
+fn fill(mark: &str, width: usize) -> String {
+    let mut ret = String::new();
+    let mut w = 0;
+    if mark.width() > 0 {
+        for ch in mark.chars().cycle() {
+            let cw = char_width(ch);
+            if w + cw > width {
+                break;
+            }
+            ret.push(ch);
+            w += cw;
+        }
+    }
+    while w < width {
+        ret.push(' ');
+        w += 1;
+    }
+    ret
+}
+
+/// Truncate a string to fit in `width` columns.
+pub fn truncate(s: &str, width: usize) -> String {
+    let mut ret = String::new();
+    let mut w = 0;
+    for ch in s.chars() {
+        let cw = char_width(ch);
+        if w + cw > width {
+            break;
+        }
+        ret.push(ch);
+        w += cw;
+    }
+    ret
+}
+
+/// A part of a template: either text, or a reference to a capture.
+#[derive(Debug,Clone,PartialEq,Eq)]
+enum TemplatePart {
+    Text(String),
+    Capture(usize),
+}
+
+/// Split a template at $N and ${N}.  "$$" is a literal "$".
+fn parse_template(template: &str) -> Vec<TemplatePart> {
+    let mut ret = vec![];
+    let mut text = String::new();
+    let chars: Vec<char> = template.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] != '$' || i + 1 >= chars.len() {
+            text.push(chars[i]);
+            i += 1;
+            continue;
+        }
+        if chars[i + 1] == '$' {
+            text.push('$');
+            i += 2;
+            continue;
+        }
+        let braced = chars[i + 1] == '{';
+        let start = if braced { i + 2 } else { i + 1 };
+        let mut end = start;
+        while end < chars.len() && chars[end].is_digit(10) {
+            end += 1;
+        }
+        if end == start || (braced && (end >= chars.len() || chars[end] != '}')) {
+            text.push(chars[i]);
+            i += 1;
+            continue;
+        }
+        let index: usize = chars[start..end].iter().cloned().collect::<String>().parse().unwrap();
+        if text.len() > 0 {
+            ret.push(TemplatePart::Text(text));
+            text = String::new();
+        }
+        ret.push(TemplatePart::Capture(index));
+        i = if braced { end + 1 } else { end };
+    }
+    if text.len() > 0 {
+        ret.push(TemplatePart::Text(text));
+    }
+    ret
+}
+
+/// Whether a template refers to any capture.
+fn has_capture_refs(template: &str) -> bool {
+    parse_template(template).iter().any(|p| match *p {
+        TemplatePart::Capture(_) => true,
+        TemplatePart::Text(_) => false,
+    })
+}
+
+/// Replace $N and ${N} with the captures.  "$$" is a literal "$".
+fn expand_captures(template: &str, captures: &[String]) -> String {
+    let mut ret = String::new();
+    for part in parse_template(template) {
+        match part {
+            TemplatePart::Text(ref t) => ret.push_str(t),
+            TemplatePart::Capture(index) => {
+                if let Some(c) = captures.get(index) {
+                    ret.push_str(c);
+                }
+            }
+        }
+    }
+    ret
+}
+
+#[test]
+fn test_expand_captures() {
+    let caps = vec!["abc".to_string(), "a".to_string(), "b".to_string()];
+    assert_eq!("x", expand_captures("x", &caps));
+    assert_eq!("[a][b][abc][]", expand_captures("[$1][${2}][$0][$3]", &caps));
+    assert_eq!("$1 $ ${x} $", expand_captures("$$1 $ ${x} $", &caps));
+    assert!(has_capture_refs("= ${1} ="));
+    assert!(!has_capture_refs("$$1 $ ${x} $"));
+}
+
+/// Braces aren't marks, as they end "${N}".
+fn is_mark_char(ch: char) -> bool {
+    !ch.is_alphanumeric() && !ch.is_whitespace() && ch != '$' && ch != '{' && ch != '}'
+}
 
 #[derive(Debug,Clone)]
 pub struct DecorativeLine {
+    /// The mark or template as written in the rule file.
+    spec: String,
+
     /// Single mark, such as "*".
     mark: String,
 
+    /// Title put in the line, such as " FATAL in $1 ", if any.
+    title: Option<String>,
+    align: Align,
+    /// The alignment given in the rule file, if any.
+    align_spec: Option<Align>,
+
     /// Colors.
     colors: Option<Colors>,
 
+    term: Term,
+    width: usize,
+
+    /// Repeated marks to fill a given width, without colors.
+    plain_line: String,
+
     /// Repeated marks to fill a given width, with colors.
     computed_line: String,
 }
 
 impl DecorativeLine {
+    /// `mark` is either a mark to repeat, such as "*" or "- -", or a template
+    /// with a title, such as "══ FATAL in $1 ══", which is told by a capture
+    /// reference in it.  In a template, the marks before (or after) the title
+    /// are used to fill the line, and the title is put on the side where there
+    /// are no marks, or in the center if marks are on both sides.
     pub fn new(mark: &str,
                colors: Option<Colors>,
                term: Term,
@@ -30,27 +233,140 @@ impl DecorativeLine {
         } else {
             "-".to_string()
         };
-        let len = m.len();
-        let mut computed = String::new();
 
-        if let Some(ref c) = colors {
+        let mut title = None;
+        let mut align = Align::Left;
+        let mut fill_mark = m.clone();
+        if has_capture_refs(&m) {
+            let lead: String = m.chars().take_while(|&c| is_mark_char(c)).collect();
+            let trail: String = {
+                let mut t: Vec<char> = m.chars().rev().take_while(|&c| is_mark_char(c)).collect();
+                t.reverse();
+                t.into_iter().collect()
+            };
+            if lead.len() < m.len() {
+                let rest = &m[lead.len()..];
+                let middle = &rest[..(rest.len() - if trail.len() < rest.len() { trail.len() } else { 0 })];
+                align = match (lead.len() > 0, trail.len() > 0) {
+                    (true, true) => Align::Center,
+                    (true, false) => Align::Right,
+                    _ => Align::Left,
+                };
+                fill_mark = if lead.len() > 0 {
+                    lead.clone()
+                } else if trail.len() > 0 {
+                    trail.clone()
+                } else {
+                    "-".to_string()
+                };
+                title = Some(middle.to_string());
+            }
+        }
+
+        let mut ret = DecorativeLine {
+            spec: mark.to_string(),
+            mark: fill_mark,
+            title: title,
+            align: align,
+            align_spec: None,
+            colors: colors,
+            term: term,
+            width: console_width,
+            plain_line: String::new(),
+            computed_line: String::new(),
+        };
+        ret.compute();
+        ret
+    }
+
+    fn compute(&mut self) {
+        let (plain, computed) = self.render(&[]);
+        self.plain_line = plain;
+        self.computed_line = computed;
+    }
+
+    /// Build the line without and with colors, with the captures of the
+    /// triggering match put in the title.
+    pub fn render(&self, captures: &[String]) -> (String, String) {
+        let plain = match self.title {
+            None => {
+                let len = self.mark.width();
+                let count = if len > 0 { self.width / len } else { 0 };
+                std::iter::repeat(self.mark.clone()).take(count).collect::<String>()
+            }
+            Some(ref t) => {
+                let title = truncate(&expand_captures(t, captures), self.width);
+                let rest = self.width - title.width();
+                let (left, right) = match self.align {
+                    Align::Left => (0, rest),
+                    Align::Right => (rest, 0),
+                    Align::Center => (rest / 2, rest - rest / 2),
+                };
+                format!("{}{}{}", fill(&self.mark, left), title, fill(&self.mark, right))
+            }
+        };
+
+        let mut computed = String::new();
+        if let Some(ref c) = self.colors {
             computed.push_str(c.fg_code());
             computed.push_str(c.bg_code());
         };
 
-        computed.push_str(
-            &std::iter::repeat(m.clone()).take(console_width / len).collect::<String>());
-        if colors.is_some() {
-            computed.push_str(term.csi_reset());
+        computed.push_str(&plain);
+        if self.colors.is_some() {
+            computed.push_str(self.term.csi_reset());
         }
+        (plain, computed)
+    }
 
-        DecorativeLine {
-            mark: m,
-            colors: colors,
-            computed_line: computed,
+    /// Whether the title refers to captures, in which case the line needs to
+    /// be rendered for each match.
+    pub fn has_captures(&self) -> bool {
+        match self.title {
+            Some(ref t) => has_capture_refs(t),
+            None => false,
+        }
+    }
+
+    /// Override where to put the title.
+    pub fn set_align(&mut self, align: Align) {
+        self.align = align;
+        self.align_spec = Some(align);
+        self.compute();
+    }
+
+    /// Rebuild the line for a new width, e.g. when the terminal is resized.
+    pub fn set_width(&mut self, console_width: usize) {
+        if self.width != console_width {
+            self.width = console_width;
+            self.compute();
         }
     }
 
+    pub fn mark(&self) -> &String {
+        &self.mark
+    }
+
+    pub fn spec(&self) -> &String {
+        &self.spec
+    }
+
+    pub fn align_spec(&self) -> Option<Align> {
+        self.align_spec
+    }
+
+    pub fn title(&self) -> Option<&String> {
+        self.title.as_ref()
+    }
+
+    pub fn colors(&self) -> Option<&Colors> {
+        self.colors.as_ref()
+    }
+
+    pub fn plain_line(&self) -> &String {
+        &self.plain_line
+    }
+
     pub fn computed_line(&self) -> &String {
         &self.computed_line
     }
@@ -64,43 +380,133 @@ fn test_build_decorative_line() {
                *DecorativeLine::new(&"*".to_string(), None, Term::Xterm, 5).computed_line());
     assert_eq!("*-*-".to_string(),
                *DecorativeLine::new(&"*-".to_string(), None, Term::Xterm, 5).computed_line());
+
+    let mut dl = DecorativeLine::new(&"*".to_string(), None, Term::Xterm, 5);
+    dl.set_width(8);
+    assert_eq!("********".to_string(), *dl.computed_line());
+
+    // Multibyte marks.
+    assert_eq!("═════",
+               DecorativeLine::new("═", None, Term::Xterm, 5).plain_line());
+    assert_eq!("ああ",
+               DecorativeLine::new("あ", None, Term::Xterm, 5).plain_line());
 }
 
-#[derive(Debug)]
+#[test]
+fn test_decorative_line_template() {
+    let dl = DecorativeLine::new("══ FATAL in $1 ══", None, Term::Xterm, 20);
+    assert!(dl.has_captures());
+    assert_eq!(Align::Center, dl.align);
+    assert_eq!("══ FATAL in main ═══",
+               dl.render(&["x".to_string(), "main".to_string()]).0);
+    assert_eq!("══ FATAL in 処理 ═══",
+               dl.render(&["x".to_string(), "処理".to_string()]).0);
+
+    let title = ["".to_string(), "title".to_string()];
+    let dl = DecorativeLine::new("* $1", None, Term::Xterm, 10);
+    assert_eq!("**** title", dl.render(&title).0);
+
+    let mut dl = DecorativeLine::new("$1 -=", None, Term::Xterm, 10);
+    assert_eq!("title -=-=", dl.render(&title).0);
+    dl.set_align(Align::Center);
+    assert_eq!("-=title -=", dl.render(&title).0);
+
+    // Braced references at either end.
+    let dl = DecorativeLine::new("== ${1}", None, Term::Xterm, 16);
+    assert_eq!("=========== main", dl.render(&["x".to_string(), "main".to_string()]).0);
+    let dl = DecorativeLine::new("${1} ==", None, Term::Xterm, 16);
+    assert_eq!("main ===========", dl.render(&["x".to_string(), "main".to_string()]).0);
+
+    // Wide marks that don't fit are padded.
+    let dl = DecorativeLine::new("＊ $1", None, Term::Xterm, 5);
+    assert_eq!("＊  x", dl.render(&["".to_string(), "x".to_string()]).0);
+
+    // The title is truncated.
+    let dl = DecorativeLine::new("= $1 =", None, Term::Xterm, 4);
+    assert_eq!(" abc", dl.render(&["".to_string(), "abcdef".to_string()]).0);
+
+    // Without captures, marks with spaces or "$$" are repeated as before.
+    let dl = DecorativeLine::new("- -", None, Term::Xterm, 7);
+    assert!(dl.title().is_none());
+    assert_eq!("- -- -", dl.plain_line());
+    assert_eq!("$$$$", DecorativeLine::new("$$", None, Term::Xterm, 4).plain_line());
+    assert_eq!("= = ", DecorativeLine::new("= ", None, Term::Xterm, 4).plain_line());
+}
+
+/// A compiled pattern.  Clones share the compiled code.
+#[derive(Debug,Clone)]
 struct PcreEx {
     pattern: String,
-    re: Pcre,
+    re: Arc<Matcher>,
     negate: bool,
 }
 
 impl PcreEx {
-    fn compile(orig_pattern: &str) -> Result<PcreEx, RuleError> {
-        let bytes = orig_pattern.as_bytes();
-        let negate = bytes[0] == '!' as u8;
-        let pattern = if negate {
+    fn compile(orig_pattern: &str,
+               engine: Engine,
+               options: Options)
+               -> Result<PcreEx, RuleError> {
+        let negate = orig_pattern.starts_with('!');
+        let mut pattern = if negate {
             orig_pattern[1..].to_string()
         } else {
             orig_pattern.to_string()
         };
+        let written = pattern.clone();
+        if options.literal {
+            pattern = engine.escape(&pattern);
+        }
+        let mut shift = 0;
+        if options.whole_word {
+            pattern = format!(r"\b(?:{})\b", pattern);
+            shift = 5;
+        }
 
-        let re = Pcre::compile(&pattern)
-            .map_err(|e| RuleError::new(&format!("Invalid regex pattern: {}", pattern)))?;
+        // Report errors with the pattern as written.
+        let re = engine.compile_with(&pattern, options).map_err(|e| match e {
+                RuleError::Regex { offset, message, .. } => {
+                    RuleError::Regex {
+                        pattern: written.clone(),
+                        offset: offset.saturating_sub(shift),
+                        message: message,
+                    }
+                }
+                e => e,
+            })?;
 
         Ok(PcreEx {
             pattern: orig_pattern.to_string(),
-            re: re,
+            re: Arc::from(re),
             negate: negate,
         })
     }
 
     fn test(&self, line: &str) -> bool {
-        let mut ret = self.re.exec(line).is_some();
+        let mut ret = self.re.is_match(line);
         if self.negate {
             ret = !ret;
         }
         ret
     }
 
+    /// Returns the whole match and the groups of the first match.
+    fn captures(&self, line: &str) -> Vec<String> {
+        if self.negate {
+            return vec![line.to_string()];
+        }
+        match self.re.captures(line) {
+            None => vec![],
+            Some(c) => {
+                c.iter()
+                    .map(|g| match *g {
+                        Some((start, end)) => line[start..end].to_string(),
+                        None => String::new(),
+                    })
+                    .collect()
+            }
+        }
+    }
+
     fn matches(&self, line: &str) -> Vec<(usize, usize)> {
         if self.negate {
             if self.test(line) {
@@ -111,10 +517,12 @@ impl PcreEx {
         }
         let cc = self.re.capture_count();
         let mut ret = vec![];
-        for m in self.re.matches(line) {
-            let mut pusher = |i| {
-                if m.group_len(i) > 0 {
-                    ret.push((m.group_start(i), m.group_end(i)))
+        for c in self.re.captures_all(line) {
+            let mut pusher = |i: usize| {
+                if let Some((start, end)) = c[i] {
+                    if end > start {
+                        ret.push((start, end))
+                    }
                 }
             };
             if cc == 0 {
@@ -130,8 +538,8 @@ impl PcreEx {
 
     #[test]
     fn test_matches() {
-        let mut pat1 = PcreEx::compile("abc").unwrap();
-        let mut pat2 = PcreEx::compile("(a)b(c)").unwrap();
+        let mut pat1 = PcreEx::compile("abc", Engine::Regex, Options::default()).unwrap();
+        let mut pat2 = PcreEx::compile("(a)b(c)", Engine::Regex, Options::default()).unwrap();
 
         assert_eq!(pat1.matches(&""), vec![]);
         assert_eq!(pat1.matches(&"1abc2"), vec![(1, 4)]);
@@ -143,11 +551,6 @@ impl PcreEx {
                    vec![(1, 2), (3, 4), (5, 6), (7, 8)]);
     }
 }
-impl Clone for PcreEx {
-    fn clone(&self) -> PcreEx {
-        PcreEx::compile(&self.pattern).unwrap()
-    }
-}
 
 #[derive(Debug, Clone)]
 pub struct Rule {
@@ -156,13 +559,33 @@ pub struct Rule {
 
     when_re: Option<PcreEx>,
 
+    /// More conditions on the line: all of `when_all` and one of `when_any`
+    /// must match, and none of `unless`.
+    when_all: Vec<PcreEx>,
+    when_any: Vec<PcreEx>,
+    unless: Vec<PcreEx>,
+
+    /// The regex engine given in the rule file, if any.
+    engine: Option<Engine>,
+    options: Options,
+
+    /// Name to refer to the rule, e.g. in tests.
+    name: Option<String>,
+    description: Option<String>,
+
+    /// Tag to enable / disable related rules together.
+    tag: Option<String>,
+
     states: Vec<String>,
     next_state: Option<String>,
 
     stop: bool,
 
-    match_colors: Option<Rc<Colors>>,
-    line_colors: Option<Rc<Colors>>,
+    /// Only apply to lines from this stream.
+    stream: Option<Stream>,
+
+    match_colors: Option<Arc<Colors>>,
+    line_colors: Option<Arc<Colors>>,
 
     pre_line: Option<DecorativeLine>,
     post_line: Option<DecorativeLine>,
@@ -170,19 +593,31 @@ pub struct Rule {
 
 impl Rule {
     pub fn new(pattern: &str) -> Result<Rule, RuleError> {
+        Rule::with_options(pattern, Options::default())
+    }
+
+    pub fn with_options(pattern: &str, options: Options) -> Result<Rule, RuleError> {
         if pattern.len() == 0 {
             return Err(RuleError::new("No pattern found"));
         }
 
-        let re = PcreEx::compile(pattern)
-            .map_err(|e| RuleError::new(&format!("Invalid regex pattern: {}", pattern)))?;
+        let re = PcreEx::compile(pattern, Engine::default(), options)?;
 
         Ok(Rule {
             re: re,
             when_re: None,
+            when_all: vec![],
+            when_any: vec![],
+            unless: vec![],
+            engine: None,
+            options: options,
+            name: None,
+            description: None,
+            tag: None,
             states: vec![],
             next_state: None,
             stop: false,
+            stream: None,
             match_colors: None,
             line_colors: None,
             pre_line: None,
@@ -190,13 +625,90 @@ impl Rule {
         })
     }
 
+    /// Replace the pattern, e.g. one that couldn't be compiled before the
+    /// engine or the options were set.
+    pub fn set_pattern(&mut self, pattern: &str) -> Result<&mut Rule, RuleError> {
+        self.re = PcreEx::compile(pattern, self.engine.unwrap_or_default(), self.options)?;
+        Ok(self)
+    }
+
     pub fn set_when(&mut self, pattern: String) -> Result<&mut Rule, RuleError> {
-        let re = PcreEx::compile(&pattern)
-            .map_err(|e| RuleError::new(&format!("Invalid regex pattern: {}", pattern)))?;
+        let re = PcreEx::compile(&pattern, self.engine.unwrap_or_default(), self.options)?;
         self.when_re = Some(re);
         Ok(self)
     }
 
+    pub fn set_when_all(&mut self, patterns: Vec<String>) -> Result<&mut Rule, RuleError> {
+        self.when_all = self.compile_all(&patterns)?;
+        Ok(self)
+    }
+
+    pub fn set_when_any(&mut self, patterns: Vec<String>) -> Result<&mut Rule, RuleError> {
+        self.when_any = self.compile_all(&patterns)?;
+        Ok(self)
+    }
+
+    pub fn set_unless(&mut self, patterns: Vec<String>) -> Result<&mut Rule, RuleError> {
+        self.unless = self.compile_all(&patterns)?;
+        Ok(self)
+    }
+
+    fn compile_all(&self, patterns: &[String]) -> Result<Vec<PcreEx>, RuleError> {
+        let engine = self.engine.unwrap_or_default();
+        patterns.iter().map(|p| PcreEx::compile(p, engine, self.options)).collect()
+    }
+
+    /// Use another regex engine.  The patterns are compiled again.
+    pub fn set_engine(&mut self, engine: Engine) -> Result<&mut Rule, RuleError> {
+        self.recompile(engine, self.options)?;
+        self.engine = Some(engine);
+        Ok(self)
+    }
+
+    /// Change the options of the patterns, which are compiled again.
+    pub fn set_options(&mut self, options: Options) -> Result<&mut Rule, RuleError> {
+        let engine = self.engine.unwrap_or_default();
+        self.recompile(engine, options)?;
+        self.options = options;
+        Ok(self)
+    }
+
+    /// Compile the patterns again.  Nothing changes if either fails.
+    fn recompile(&mut self, engine: Engine, options: Options) -> Result<(), RuleError> {
+        let re = PcreEx::compile(&self.re.pattern, engine, options)?;
+        let when_re = match self.when_re {
+            Some(ref w) => Some(PcreEx::compile(&w.pattern, engine, options)?),
+            None => None,
+        };
+        let again = |res: &[PcreEx]| -> Result<Vec<PcreEx>, RuleError> {
+            res.iter().map(|r| PcreEx::compile(&r.pattern, engine, options)).collect()
+        };
+        let when_all = again(&self.when_all)?;
+        let when_any = again(&self.when_any)?;
+        let unless = again(&self.unless)?;
+        self.re = re;
+        self.when_re = when_re;
+        self.when_all = when_all;
+        self.when_any = when_any;
+        self.unless = unless;
+        Ok(())
+    }
+
+    pub fn set_name(&mut self, name: String) -> &mut Rule {
+        self.name = Some(name);
+        self
+    }
+
+    pub fn set_description(&mut self, description: String) -> &mut Rule {
+        self.description = Some(description);
+        self
+    }
+
+    pub fn set_tag(&mut self, tag: String) -> &mut Rule {
+        self.tag = Some(tag);
+        self
+    }
+
     pub fn set_next_state(&mut self, state: String) -> &mut Rule {
         self.next_state = Some(state.to_string());
         self
@@ -212,13 +724,18 @@ impl Rule {
         self
     }
 
+    pub fn set_stream(&mut self, stream: Stream) -> &mut Rule {
+        self.stream = Some(stream);
+        self
+    }
+
     pub fn set_match_colors(&mut self, c: Colors) -> &mut Rule {
-        self.match_colors = Some(Rc::new(c));
+        self.match_colors = Some(Arc::new(c));
         self
     }
 
     pub fn set_line_colors(&mut self, c: Colors) -> &mut Rule {
-        self.line_colors = Some(Rc::new(c));
+        self.line_colors = Some(Arc::new(c));
         self
     }
 
@@ -232,27 +749,102 @@ impl Rule {
         self
     }
 
-    pub fn matches(&self, line: &str) -> Vec<(usize, usize)> {
+    /// Update the width of the pre and post lines.
+    pub fn set_width(&mut self, console_width: usize) {
+        if let Some(ref mut l) = self.pre_line {
+            l.set_width(console_width);
+        }
+        if let Some(ref mut l) = self.post_line {
+            l.set_width(console_width);
+        }
+    }
+
+    /// Whether the line meets the conditions of the rule, i.e. `when`,
+    /// `when_all`, `when_any` and `unless`.
+    fn applies_to(&self, line: &str) -> bool {
         if let Some(ref re) = self.when_re {
             if !re.test(line) {
-                return vec![];
+                return false;
             }
         }
+        self.when_all.iter().all(|re| re.test(line)) &&
+        (self.when_any.len() == 0 || self.when_any.iter().any(|re| re.test(line))) &&
+        !self.unless.iter().any(|re| re.test(line))
+    }
+
+    pub fn matches(&self, line: &str) -> Vec<(usize, usize)> {
+        if !self.applies_to(line) {
+            return vec![];
+        }
         self.re.matches(line)
     }
 
+    /// Returns the whole match and the groups, for $N in decorative lines.
+    pub fn captures(&self, line: &str) -> Vec<String> {
+        self.re.captures(line)
+    }
+
     pub fn pattern(&self) -> &String {
         &self.re.pattern
     }
 
-    pub fn match_colors(&self) -> Option<Rc<Colors>> {
+    pub fn when(&self) -> Option<&String> {
+        self.when_re.as_ref().map(|re| &re.pattern)
+    }
+
+    pub fn when_all(&self) -> Vec<&String> {
+        self.when_all.iter().map(|re| &re.pattern).collect()
+    }
+
+    pub fn when_any(&self) -> Vec<&String> {
+        self.when_any.iter().map(|re| &re.pattern).collect()
+    }
+
+    pub fn unless(&self) -> Vec<&String> {
+        self.unless.iter().map(|re| &re.pattern).collect()
+    }
+
+    /// Whether the rule only applies to some lines, because of `when`,
+    /// `when_all`, `when_any` or `unless`.
+    pub fn conditional(&self) -> bool {
+        self.when_re.is_some() || self.when_all.len() > 0 || self.when_any.len() > 0 ||
+        self.unless.len() > 0
+    }
+
+    /// The regex engine given in the rule file, if any.
+    pub fn engine(&self) -> Option<Engine> {
+        self.engine
+    }
+
+    pub fn options(&self) -> Options {
+        self.options
+    }
+
+    pub fn match_colors(&self) -> Option<Arc<Colors>> {
         self.match_colors.as_ref().map(|x| x.clone())
     }
 
-    pub fn line_colors(&self) -> Option<Rc<Colors>> {
+    pub fn line_colors(&self) -> Option<Arc<Colors>> {
         self.line_colors.as_ref().map(|x| x.clone())
     }
 
+    pub fn name(&self) -> Option<&String> {
+        self.name.as_ref()
+    }
+
+    pub fn description(&self) -> Option<&String> {
+        self.description.as_ref()
+    }
+
+    /// The name, or the pattern if the rule has no name.
+    pub fn label(&self) -> &String {
+        self.name.as_ref().unwrap_or(&self.re.pattern)
+    }
+
+    pub fn tag(&self) -> Option<&String> {
+        self.tag.as_ref()
+    }
+
     pub fn states(&self) -> &Vec<String> {
         &self.states
     }
@@ -272,14 +864,128 @@ impl Rule {
     pub fn stop(&self) -> bool {
         self.stop
     }
+
+    pub fn stream(&self) -> Option<Stream> {
+        self.stream
+    }
+}
+
+/// Compiled rules.  They can't be changed, so an `Arc<RuleSet>` can be shared
+/// by the filters of any number of threads; see `Filter::with_rule_set()`.
+#[derive(Debug,Clone)]
+pub struct RuleSet {
+    rules: Vec<Rule>,
+}
+
+impl RuleSet {
+    pub fn new(rules: Vec<Rule>) -> RuleSet {
+        RuleSet { rules: rules }
+    }
+
+    pub fn rules(&self) -> &Vec<Rule> {
+        &self.rules
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// A copy with the decorative lines for another width.  The patterns
+    /// aren't compiled again.
+    pub fn with_width(&self, console_width: usize) -> RuleSet {
+        let mut rules = self.rules.clone();
+        for r in rules.iter_mut() {
+            r.set_width(console_width);
+        }
+        RuleSet::new(rules)
+    }
 }
 
 #[test]
 fn test_matches_zero_width() {
-    let mut pat1 = Pcre::compile("^").unwrap();
+    // Empty matches aren't highlighted.
+    assert_eq!(Rule::new("^").unwrap().matches("abc"), vec![]);
+    assert_eq!(Rule::new("x*").unwrap().matches("axxb"), vec![(1, 3)]);
+}
 
-    // TODO Send a pull request to fix it.
-    // assert_eq!(Rule::matches_inner(&mut pat1, &"abc"), vec![]);
+#[test]
+fn test_options() {
+    let options = |f: &Fn(&mut Options)| {
+        let mut o = Options::default();
+        f(&mut o);
+        o
+    };
+    let rule = Rule::with_options("error", options(&|o| o.ignore_case = true)).unwrap();
+    assert_eq!(vec![(2, 7)], rule.matches("x ERROR"));
+
+    let rule = Rule::with_options("a.c", options(&|o| o.literal = true)).unwrap();
+    assert_eq!(vec![(4, 7)], rule.matches("abc a.c"));
+    assert!(Rule::with_options("f(", options(&|o| o.literal = true)).is_ok());
+
+    let whole_word = options(&|o| o.whole_word = true);
+    let rule = Rule::with_options("log", whole_word).unwrap();
+    assert_eq!(vec![(7, 10)], rule.matches("syslog log"));
+    // The negation isn't a part of the words.
+    assert_eq!(vec![(0, 6)], Rule::with_options("!log", whole_word).unwrap().matches("syslog"));
+
+    // Errors are about the pattern as written.
+    match Rule::with_options("a[z-a]", whole_word).unwrap_err() {
+        RuleError::Regex { pattern, offset, .. } => {
+            assert_eq!(("a[z-a]", 2), (&pattern[..], offset))
+        }
+        e => panic!("{:?}", e),
+    }
+
+    let mut rule = Rule::new(r"\w+").unwrap();
+    rule.set_when("é".to_string()).unwrap();
+    assert_eq!(vec![(0, 3)], rule.matches("éa"));
+    rule.set_options(options(&|o| o.unicode = Some(false))).unwrap();
+    assert_eq!(vec![(2, 3)], rule.matches("éa"));
+    assert!(rule.set_pattern(".").is_err());
+}
+
+#[test]
+fn test_set_engine() {
+    let mut rule = Rule::new("(a)b").unwrap();
+    rule.set_when("b$".to_string()).unwrap();
+    assert!(rule.set_engine(Engine::Regex).is_ok());
+    assert_eq!(Some(Engine::Regex), rule.engine());
+    assert_eq!("(a)b", rule.pattern());
+    assert_eq!(Some(&"b$".to_string()), rule.when());
+    assert_eq!(vec![(0, 1)], rule.matches("ab"));
+
+    // Look-ahead needs another engine.
+    assert!(Rule::new("a(?=b)").is_err());
+    if !Engine::FancyRegex.is_available() {
+        assert!(rule.set_engine(Engine::FancyRegex).is_err());
+        assert_eq!(Some(Engine::Regex), rule.engine());
+    }
+}
+
+#[test]
+fn test_conditions() {
+    let strings = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+    let mut rule = Rule::new(r"pid=\d+").unwrap();
+    rule.set_when_all(strings(&["ActivityManager", "!DEBUG"]))
+        .unwrap()
+        .set_when_any(strings(&["Start", "Kill"]))
+        .unwrap()
+        .set_unless(strings(&["StrictMode"]))
+        .unwrap();
+    assert!(rule.conditional());
+    assert_eq!(vec![(23, 30)], rule.matches("ActivityManager: Start pid=123"));
+    assert_eq!(0, rule.matches("ActivityManager: Start pid=123 StrictMode").len());
+    assert_eq!(0, rule.matches("ActivityManager: Stop pid=123").len());
+    assert_eq!(0, rule.matches("ActivityManager: DEBUG Kill pid=123").len());
+    assert_eq!(0, rule.matches("Kill pid=123").len());
+    assert_eq!(vec!["StrictMode"], rule.unless());
+
+    // The conditions are compiled again with the options.
+    rule.set_options(Options { ignore_case: true, ..Default::default() }).unwrap();
+    assert_eq!(0, rule.matches("activitymanager: start pid=1 strictmode").len());
+    assert_eq!(vec![(23, 28)], rule.matches("activitymanager: start pid=1"));
+    assert!(rule.set_unless(strings(&["("])).is_err());
+    assert!(!Rule::new("x").unwrap().conditional());
 }
 
 #[test]
@@ -0,0 +1,181 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use serde_json::Value as Json;
+use toml::Value;
+use yaml_rust::{Yaml, YamlLoader};
+
+use super::*;
+
+/// The rule file formats.  TOML, YAML and JSON files share the same keys.
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum Format {
+    Toml,
+    Yaml,
+    Json,
+    Legacy,
+}
+
+impl Format {
+    pub fn parse(value: &str) -> Option<Format> {
+        match value {
+            "toml" => Some(Format::Toml),
+            "yaml" => Some(Format::Yaml),
+            "json" => Some(Format::Json),
+            "legacy" => Some(Format::Legacy),
+            _ => None,
+        }
+    }
+
+    /// The format told by the extension of a file name, if any.
+    pub fn from_extension(filename: &str) -> Option<Format> {
+        if filename.ends_with(".toml") {
+            Some(Format::Toml)
+        } else if filename.ends_with(".yaml") || filename.ends_with(".yml") {
+            Some(Format::Yaml)
+        } else if filename.ends_with(".json") {
+            Some(Format::Json)
+        } else {
+            None
+        }
+    }
+
+    /// The format of a file, by the extension.  Files without a known
+    /// extension are legacy rule files.
+    pub fn of_file(filename: &str) -> Format {
+        Format::from_extension(filename).unwrap_or(Format::Legacy)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Format::Toml => "TOML",
+            Format::Yaml => "YAML",
+            Format::Json => "JSON",
+            Format::Legacy => "legacy",
+        }
+    }
+}
+
+fn yaml_to_value(yaml: &Yaml) -> Result<Value, RuleError> {
+    Ok(match *yaml {
+        Yaml::String(ref s) => Value::String(s.clone()),
+        Yaml::Integer(i) => Value::Integer(i),
+        Yaml::Real(ref s) => {
+            Value::Float(s.parse()
+                .map_err(|_| RuleError::new(&format!("Invalid YAML: Bad number '{}'.", s)))?)
+        }
+        Yaml::Boolean(b) => Value::Boolean(b),
+        Yaml::Array(ref a) => {
+            let mut values = vec![];
+            for v in a {
+                values.push(yaml_to_value(v)?);
+            }
+            Value::Array(values)
+        }
+        Yaml::Hash(ref h) => {
+            let mut table = BTreeMap::new();
+            for (k, v) in h {
+                let key = k.as_str().ok_or(RuleError::new(&format!("Invalid YAML: Key {:?} must \
+                                                                     be a string.",
+                                                                    k)))?;
+                table.insert(key.to_string(), yaml_to_value(v)?);
+            }
+            Value::Table(table)
+        }
+        Yaml::Null => {
+            return Err(RuleError::new("Invalid YAML: Empty value; '#' starts a comment, so \
+                                       quote values such as '#'."))
+        }
+        _ => return Err(RuleError::new(&format!("Invalid YAML: Unsupported value {:?}.", yaml))),
+    })
+}
+
+/// Parse a YAML rule file into the same structure as a TOML one.
+pub fn parse_yaml(filename: &str, content: &str) -> Result<Value, RuleError> {
+    let docs = YamlLoader::load_from_str(content).map_err(|e| {
+            RuleError::Syntax {
+                file: filename.to_string(),
+                format: Format::Yaml,
+                line: e.marker().line(),
+                column: e.marker().col() + 1,
+                message: e.description().to_string(),
+            }
+        })?;
+    match docs.into_iter().next() {
+        Some(doc) => yaml_to_value(&doc),
+        None => Ok(Value::Table(BTreeMap::new())),
+    }
+}
+
+fn json_to_value(json: &Json) -> Result<Value, RuleError> {
+    Ok(match *json {
+        Json::String(ref s) => Value::String(s.clone()),
+        Json::Number(ref n) => {
+            match n.as_i64() {
+                Some(i) => Value::Integer(i),
+                None => Value::Float(n.as_f64().unwrap_or(0.0)),
+            }
+        }
+        Json::Bool(b) => Value::Boolean(b),
+        Json::Array(ref a) => {
+            let mut values = vec![];
+            for v in a {
+                values.push(json_to_value(v)?);
+            }
+            Value::Array(values)
+        }
+        Json::Object(ref o) => {
+            let mut table = BTreeMap::new();
+            for (k, v) in o {
+                table.insert(k.clone(), json_to_value(v)?);
+            }
+            Value::Table(table)
+        }
+        Json::Null => return Err(RuleError::new("Invalid JSON: null isn't allowed.")),
+    })
+}
+
+/// Parse a JSON rule file into the same structure as a TOML one.
+pub fn parse_json(filename: &str, content: &str) -> Result<Value, RuleError> {
+    let json: Json = ::serde_json::from_str(content).map_err(|e| {
+            // The location is kept apart, as for the other formats.
+            let message = e.to_string();
+            let message = match message.rfind(" at line ") {
+                Some(i) => &message[..i],
+                None => &message,
+            };
+            RuleError::Syntax {
+                file: filename.to_string(),
+                format: Format::Json,
+                line: e.line(),
+                column: e.column(),
+                message: message.to_string(),
+            }
+        })?;
+    json_to_value(&json)
+}
+
+#[test]
+fn test_parse_yaml() {
+    let yaml = "rule:\n  - pattern: 'a'\n    stop: true\n    states: ['', x]\n";
+    let value = parse_yaml("x.yaml", yaml).unwrap();
+    let rule = &value.as_table().unwrap()["rule"].as_slice().unwrap()[0];
+    assert_eq!(Some("a"), rule.lookup("pattern").and_then(|v| v.as_str()));
+    assert_eq!(Some(true), rule.lookup("stop").and_then(|v| v.as_bool()));
+    assert_eq!(2, rule.lookup("states").and_then(|v| v.as_slice()).unwrap().len());
+
+    assert!(parse_yaml("x.yaml", "rule:\n  - pre_line: #\n").is_err());
+    assert!(parse_yaml("x.yaml", "rule: [").is_err());
+}
+
+#[test]
+fn test_parse_json() {
+    let value = parse_json("x.json", r#"{"rule": [{"pattern": "a", "stop": true}]}"#).unwrap();
+    let rule = &value.as_table().unwrap()["rule"].as_slice().unwrap()[0];
+    assert_eq!(Some("a"), rule.lookup("pattern").and_then(|v| v.as_str()));
+    assert_eq!(Some(true), rule.lookup("stop").and_then(|v| v.as_bool()));
+
+    assert!(parse_json("x.json", r#"{"rule": [{"pattern": null}]}"#).is_err());
+    let e = parse_json("x.json", "{\n\"rule\": [}").unwrap_err();
+    assert_eq!("Invalid JSON: x.json:2:10: expected value", e.to_string());
+    assert!(parse_json("x.json", "[1.5, 2, -3]").is_ok());
+}
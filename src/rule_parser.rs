@@ -8,19 +8,23 @@ use std::io;
 use std::io::BufReader;
 use std::io::prelude::*;
 use std::fs::File;
+use std::cmp;
 use std::collections::BTreeMap;
-use pcre::{CompileOption, Match, Pcre};
 use toml;
 use toml::Value;
 
 use super::*;
 use term_color::*;
 use rule::*;
+use rule_test::*;
+use matcher::*;
+use rule_format;
+use rule_format::Format;
 
 #[derive(Debug)]
 struct ColorParser {
     term: Term,
-    re: Pcre,
+    re: Box<Matcher>,
 }
 
 macro_rules! COLOR_RE {
@@ -44,9 +48,9 @@ const COLORS_RE: &'static str = concat!(r"(?xi) ^",
 
 impl ColorParser {
     fn new(term: Term) -> ColorParser {
-        let re = match Pcre::compile(COLORS_RE) {
+        let re = match Engine::Regex.compile(COLORS_RE) {
             Err(err) => {
-                panic!("Pcre failed");
+                panic!("Regex failed");
             }
             Ok(re) => re,
         };
@@ -56,11 +60,10 @@ impl ColorParser {
         }
     }
 
-    fn get_group(m: &Match, i: usize) -> String {
-        if m.group_len(i) == 0 {
-            String::new()
-        } else {
-            m.group(i).to_string()
+    fn get_group(value: &str, m: &Captures, i: usize) -> String {
+        match m[i] {
+            Some((start, end)) => value[start..end].to_string(),
+            None => String::new(),
         }
     }
 
@@ -145,22 +148,22 @@ impl ColorParser {
     }
 
     fn parse(&self, value: &str) -> Result<Colors, RuleError> {
-        let m = match self.re.exec(&value) {
-            None => return Err(RuleError::new(&format!("Invalid color: {}", value))),
+        let m = match self.re.captures(value) {
+            None => return Err(RuleError::Color { spec: value.to_string() }),
             Some(m) => m,
         };
-        let prefix = ColorParser::get_group(&m, 1);
-        let fg_index = ColorParser::to_color_index(&ColorParser::get_group(&m, 2));
-        let fg_rgb666 = ColorParser::rgb666_to_u16(&ColorParser::get_group(&m, 3));
-        let fg_r = ColorParser::hex_to_u8(&ColorParser::get_group(&m, 4));
-        let fg_g = ColorParser::hex_to_u8(&ColorParser::get_group(&m, 5));
-        let fg_b = ColorParser::hex_to_u8(&ColorParser::get_group(&m, 6));
-
-        let bg_index = ColorParser::to_color_index(&ColorParser::get_group(&m, 7));
-        let bg_rgb666 = ColorParser::rgb666_to_u16(&ColorParser::get_group(&m, 8));
-        let bg_r = ColorParser::hex_to_u8(&ColorParser::get_group(&m, 9));
-        let bg_g = ColorParser::hex_to_u8(&ColorParser::get_group(&m, 10));
-        let bg_b = ColorParser::hex_to_u8(&ColorParser::get_group(&m, 11));
+        let prefix = ColorParser::get_group(value, &m, 1);
+        let fg_index = ColorParser::to_color_index(&ColorParser::get_group(value, &m, 2));
+        let fg_rgb666 = ColorParser::rgb666_to_u16(&ColorParser::get_group(value, &m, 3));
+        let fg_r = ColorParser::hex_to_u8(&ColorParser::get_group(value, &m, 4));
+        let fg_g = ColorParser::hex_to_u8(&ColorParser::get_group(value, &m, 5));
+        let fg_b = ColorParser::hex_to_u8(&ColorParser::get_group(value, &m, 6));
+
+        let bg_index = ColorParser::to_color_index(&ColorParser::get_group(value, &m, 7));
+        let bg_rgb666 = ColorParser::rgb666_to_u16(&ColorParser::get_group(value, &m, 8));
+        let bg_r = ColorParser::hex_to_u8(&ColorParser::get_group(value, &m, 9));
+        let bg_g = ColorParser::hex_to_u8(&ColorParser::get_group(value, &m, 10));
+        let bg_b = ColorParser::hex_to_u8(&ColorParser::get_group(value, &m, 11));
 
         let mut attrs = ATTR_NONE;
         if prefix.contains('b') {
@@ -181,7 +184,8 @@ impl ColorParser {
         Ok(Colors::with_colors(ColorParser::to_color(fg_index, fg_rgb666, fg_r, fg_g, fg_b),
                                ColorParser::to_color(bg_index, bg_rgb666, bg_r, bg_g, bg_b),
                                attrs,
-                               self.term))
+                               self.term)
+            .with_spec(value))
     }
 }
 
@@ -204,6 +208,8 @@ pub struct RuleParser {
     term: Term,
     output_width: usize,
     default_color_index: i32,
+    /// The options of the rules that don't set them.
+    options: Options,
 }
 
 impl RuleParser {
@@ -213,9 +219,16 @@ impl RuleParser {
             term: term,
             output_width: output_width,
             default_color_index: -1,
+            options: Options::default(),
         }
     }
 
+    /// Set the options of all the rules that don't set them, e.g. from the
+    /// command line.
+    pub fn set_options(&mut self, options: Options) {
+        self.options = options;
+    }
+
     fn str_from_table<'a>(map: &'a BTreeMap<String, Value>,
                           key: &str)
                           -> Result<&'a str, RuleError> {
@@ -291,6 +304,12 @@ impl RuleParser {
             pattern = &value;
             rest = "";
         }
+        // Split the flags after ";".
+        let mut options = self.options;
+        if let Some(p) = rest.rfind(';') {
+            options = RuleParser::parse_flags(&rest[p + 1..], options)?;
+            rest = &rest[0..p];
+        }
         if rest.len() == 0 {
             rest = self.get_default_color();
         }
@@ -314,7 +333,7 @@ impl RuleParser {
                color,
                line_color);
 
-        let mut rule = try!(Rule::new(&pattern));
+        let mut rule = try!(Rule::with_options(&pattern, options));
 
         if color.len() > 0 {
             let c = try!(self.color_parser.parse(color));
@@ -332,87 +351,175 @@ impl RuleParser {
         Ok(rule)
     }
 
-    fn new_decorative_line(&self, marker: &str, colors: &Option<Colors>) -> DecorativeLine {
-        DecorativeLine::new(marker, colors.clone(), self.term, self.output_width)
+    /// Parse a color spec, such as "b500/001", into `Colors` for this terminal.
+    pub fn parse_colors(&self, value: &str) -> Result<Colors, RuleError> {
+        self.color_parser.parse(value)
     }
 
-    pub fn parse_legacy(&self, filename: &str, mut rules: &mut Vec<Rule>) -> Result<(), RuleError> {
-        debug!("Reading legacy rule file from {}...", filename);
+    fn new_decorative_line(&self,
+                           marker: &str,
+                           colors: &Option<Colors>,
+                           align: Option<Align>)
+                           -> DecorativeLine {
+        let mut dl = DecorativeLine::new(marker, colors.clone(), self.term, self.output_width);
+        if let Some(a) = align {
+            dl.set_align(a);
+        }
+        dl
+    }
+
+    fn parse_align(value: &str) -> Result<Align, RuleError> {
+        Align::parse(value).ok_or(RuleError::new(&format!("Invalid alignment: {} \
+                                                           (must be left, center or right)",
+                                                          value)))
+    }
+
+    /// Apply the flags of a simple rule, e.g. "iw", to the options.
+    fn parse_flags(flags: &str, mut options: Options) -> Result<Options, RuleError> {
+        for flag in flags.chars() {
+            match flag {
+                'i' => options.ignore_case = true,
+                'w' => options.whole_word = true,
+                'l' => options.literal = true,
+                'u' => options.unicode = Some(true),
+                'a' => options.unicode = Some(false),
+                _ => {
+                    return Err(RuleError::new(&format!("Invalid flag '{}'; must be i (ignore \
+                                                        case), w (whole word), l (literal), u \
+                                                        (Unicode) or a (ASCII).",
+                                                       flag)))
+                }
+            }
+        }
+        Ok(options)
+    }
 
-        let file = BufReader::new(try!(File::open(&filename)
-            .map_err(|e| RuleError::new(&format!("Unable to open file '{}'", filename)))));
+    /// A boolean in a legacy rule file, where the key alone means true.
+    fn parse_legacy_bool(key: &str, value: &str) -> Result<bool, RuleError> {
+        match value {
+            "" | "true" => Ok(true),
+            "false" => Ok(false),
+            _ => Err(RuleError::new(&format!("Key '{}' must be 'true' or 'false'.", key))),
+        }
+    }
 
-        // I just wanted "add_rule()" as a closure, but couldn't because of
-        // borrow checking... So I needed "expand" the closure by myself
-        // to access the borrowed values in it.
-        struct State {
-            rule: Option<Rule>,
+    fn parse_stream(value: &str) -> Result<Stream, RuleError> {
+        Stream::parse(value).ok_or(RuleError::new(&format!("Invalid stream: {} \
+                                                            (must be stdout or stderr)",
+                                                           value)))
+    }
 
-            pre_line: Option<String>,
-            pre_line_color: Option<Colors>,
-            post_line: Option<String>,
-            post_line_color: Option<Colors>,
+    /// Read a rule file in the given format.
+    pub fn parse_file(&self,
+                      filename: &str,
+                      format: Format,
+                      rules: &mut Vec<Rule>)
+                      -> Result<(), RuleError> {
+        RuleParser::collect(filename, self.read_entries(filename, format)?, rules)
+    }
 
-            term: Term,
-            output_width: usize,
+    /// Same as `parse_file()`, but keeps going after errors, and returns all
+    /// the rules with their problems.
+    pub fn read_entries(&self,
+                        filename: &str,
+                        format: Format)
+                        -> Result<Vec<RuleEntry>, RuleError> {
+        match format {
+            Format::Legacy => self.read_legacy(filename),
+            _ => self.parse_entries(filename, &RuleParser::read_file(filename)?, format),
         }
+    }
 
-        impl State {
-            fn new_decorative_line(p: &RuleParser,
-                                   pre_line: &Option<String>,
-                                   pre_line_color: &Option<Colors>)
-                                   -> DecorativeLine {
-                let pl = pre_line.as_ref().unwrap_or(&String::new()).clone();
-                p.new_decorative_line(&pl, pre_line_color)
+    /// Same as `parse_file()`, but reads the rules from a string.
+    pub fn parse_str(&self,
+                     filename: &str,
+                     content: &str,
+                     format: Format,
+                     rules: &mut Vec<Rule>)
+                     -> Result<(), RuleError> {
+        let entries = match format {
+            Format::Legacy => self.parse_legacy_entries(filename, content.as_bytes())?,
+            _ => self.parse_entries(filename, content, format)?,
+        };
+        RuleParser::collect(filename, entries, rules)
+    }
+
+    /// Read a rule file in the given format, and return its tests, parsing it
+    /// only once.  Legacy rule files have no tests.
+    pub fn parse_file_with_tests(&self,
+                                 filename: &str,
+                                 format: Format,
+                                 rules: &mut Vec<Rule>)
+                                 -> Result<Vec<RuleTest>, RuleError> {
+        match format {
+            Format::Legacy => self.parse_file(filename, format, rules).map(|_| vec![]),
+            _ => {
+                let content = RuleParser::read_file(filename)?;
+                self.parse_str_with_tests(filename, &content, format, rules)
             }
+        }
+    }
 
-            fn add_rule(&mut self, p: &RuleParser, rules: &mut Vec<Rule>) {
-                if self.rule.is_none() {
-                    // No previous rule.
-                    return;
-                }
-                {
-                    let rule = self.rule.as_mut().unwrap();
-                    if self.pre_line.is_some() || self.pre_line_color.is_some() {
-                        let dl =
-                            State::new_decorative_line(p, &self.pre_line, &self.pre_line_color);
-                        rule.set_pre_line(dl);
-                    }
-                    if self.post_line.is_some() || self.post_line_color.is_some() {
-                        let dl =
-                            State::new_decorative_line(p, &self.post_line, &self.post_line_color);
-                        rule.set_post_line(dl);
-                    }
+    /// Same as `parse_file_with_tests()`, but reads the rules from a string.
+    pub fn parse_str_with_tests(&self,
+                                filename: &str,
+                                content: &str,
+                                format: Format,
+                                rules: &mut Vec<Rule>)
+                                -> Result<Vec<RuleTest>, RuleError> {
+        if format == Format::Legacy {
+            return self.parse_str(filename, content, format, rules).map(|_| vec![]);
+        }
+        let doc = RuleParser::parse_document(filename, content, format)?;
+        RuleParser::collect(filename, self.entries_of(filename, content, &doc, format)?, rules)?;
+        RuleParser::tests_of(filename, content, &doc, format)
+    }
 
-                    debug!("{:?}", rule);
-                    rules.push(rule.clone());
-                }
-                self.rule = None;
-                self.pre_line = None;
-                self.pre_line_color = None;
-                self.post_line = None;
-                self.post_line_color = None;
-            }
-        }
-
-        let mut state = State {
-            rule: None,
-            pre_line: None,
-            pre_line_color: None,
-            post_line: None,
-            post_line_color: None,
-            term: self.term,
-            output_width: self.output_width,
-        };
+    /// Read the tests of a rule file.  Legacy rule files have none.
+    pub fn read_tests(&self, filename: &str, format: Format) -> Result<Vec<RuleTest>, RuleError> {
+        match format {
+            Format::Legacy => Ok(vec![]),
+            _ => self.read_tests_str(filename, &RuleParser::read_file(filename)?, format),
+        }
+    }
+
+    /// Read a legacy rule file.
+    pub fn parse_legacy(&self, filename: &str, rules: &mut Vec<Rule>) -> Result<(), RuleError> {
+        RuleParser::collect(filename, self.read_legacy(filename)?, rules)
+    }
+
+    /// Same as `parse_legacy()`, but keeps going after errors, and returns
+    /// all the rules with their problems.
+    pub fn read_legacy(&self, filename: &str) -> Result<Vec<RuleEntry>, RuleError> {
+        debug!("Reading legacy rule file from {}...", filename);
+
+        let file = BufReader::new(try!(File::open(&filename).map_err(|e| {
+            RuleError::Io {
+                file: filename.to_string(),
+                source: e,
+            }
+        })));
+        self.parse_legacy_entries(filename, file)
+    }
+
+    fn parse_legacy_entries<R: BufRead>(&self,
+                                        filename: &str,
+                                        reader: R)
+                                        -> Result<Vec<RuleEntry>, RuleError> {
+        let mut entries = vec![];
+        let mut pending: Option<PendingRule> = None;
 
         let mut line_no = 0;
-        for line_res in file.lines() {
+        for line_res in reader.lines() {
             line_no += 1;
 
-            if let Err(e) = line_res {
-                return Err(RuleError::new(&format!("Error reading from '{}': {}", filename, e)));
-            }
-            let line = line_res.unwrap().trim().to_string();
+            let text = try!(line_res.map_err(|e| {
+                RuleError::Io {
+                    file: filename.to_string(),
+                    source: e,
+                }
+            }));
+            let line = text.trim();
 
             if line.len() == 0 || line.starts_with('/') || line.starts_with('#') {
                 continue;
@@ -424,85 +531,274 @@ impl RuleParser {
                 key = line[0..p].trim();
                 value = line[p + 1..line.len()].trim();
             } else {
-                key = &line;
-                value = &"";
+                key = line;
+                value = "";
             }
 
             debug!("  {}={}", key, value);
 
             if key == "pattern" {
-                state.add_rule(self, &mut rules);
-                state.rule = Some(try!(Rule::new(&value)));
+                if let Some(p) = pending.take() {
+                    entries.push(p.finish(self));
+                }
+                pending = Some(PendingRule::new(line_no,
+                                                Ok(value),
+                                                Location::of_value(line_no, &text, false),
+                                                self.options));
                 continue;
             }
 
-            if state.rule.is_none() {
-                return Err(RuleError::new(&format!("Error reading from '{}': file must start \
-                                                    with 'pattern' ",
-                                                   filename)));
-            }
-
-            match key {
-                ".when" => {
-                    try!(state.rule.as_mut().unwrap().set_when(value.to_string()));
+            match pending.as_mut() {
+                None => {
+                    let e = RuleError::new("The file must start with 'pattern'.");
+                    return Err(RuleError::at(filename, line_no, 0, "", e));
                 }
-                ".states" => {
-                    // state.rule.set_states(value.to_string());
-                    let mut vals: Vec<String> = vec![];
-                    for s in value.split(",") {
-                        let s = if s == "INIT" { "" } else { s.trim() };
-                        vals.push(s.to_string());
+                Some(p) => {
+                    let value_location = Location::of_value(line_no, &text, false);
+                    if let Err(e) = self.apply_legacy_key(p, key, value, &value_location) {
+                        p.fail(e,
+                               Format::Legacy,
+                               key,
+                               Some(value),
+                               Location::of_key(line_no, &text),
+                               value_location);
                     }
-                    state.rule.as_mut().unwrap().set_states(vals);
-                }
-                ".next_state" => {
-                    let s = if value == "INIT" { "" } else { value };
-                    state.rule.as_mut().unwrap().set_next_state(s.to_string());
                 }
-                ".color" => {
-                    let c = try!(self.color_parser.parse(value));
-                    state.rule.as_mut().unwrap().set_match_colors(c);
-                }
-                ".stop" => {
-                    state.rule.as_mut().unwrap().set_stop(true);
-                }
-                ".line_color" => {
-                    let c = try!(self.color_parser.parse(value));
-                    state.rule.as_mut().unwrap().set_line_colors(c);
+            }
+        }
+        if let Some(p) = pending {
+            entries.push(p.finish(self));
+        }
+        Ok(entries)
+    }
+
+    fn apply_legacy_key(&self,
+                        p: &mut PendingRule,
+                        key: &str,
+                        value: &str,
+                        location: &Location)
+                        -> Result<(), RuleError> {
+        match key {
+            ".engine" => {
+                let engine = try!(Engine::parse(value));
+                try!(p.rule.set_engine(engine));
+            }
+            ".ignore_case" => {
+                let v = try!(RuleParser::parse_legacy_bool(key, value));
+                try!(p.set_option(|o| o.ignore_case = v));
+            }
+            ".whole_word" => {
+                let v = try!(RuleParser::parse_legacy_bool(key, value));
+                try!(p.set_option(|o| o.whole_word = v));
+            }
+            ".literal" => {
+                let v = try!(RuleParser::parse_legacy_bool(key, value));
+                try!(p.set_option(|o| o.literal = v));
+            }
+            ".unicode" => {
+                let v = try!(RuleParser::parse_legacy_bool(key, value));
+                try!(p.set_option(|o| o.unicode = Some(v)));
+            }
+            ".when" => {
+                p.add_condition(Condition::When(value.to_string()), location);
+            }
+            // The lists have a line per pattern.
+            ".when_all" => {
+                p.add_condition(Condition::WhenAll(vec![value.to_string()]), location);
+            }
+            ".when_any" => {
+                p.add_condition(Condition::WhenAny(vec![value.to_string()]), location);
+            }
+            ".unless" => {
+                p.add_condition(Condition::Unless(vec![value.to_string()]), location);
+            }
+            ".name" => {
+                p.rule.set_name(value.to_string());
+            }
+            ".description" => {
+                p.rule.set_description(value.to_string());
+            }
+            ".tag" => {
+                p.rule.set_tag(value.to_string());
+            }
+            ".states" => {
+                let mut vals: Vec<String> = vec![];
+                for s in value.split(",") {
+                    let s = if s == "INIT" { "" } else { s.trim() };
+                    vals.push(s.to_string());
                 }
-                ".pre_line" => {
-                    state.pre_line = Some(value.to_string());
+                p.rule.set_states(vals);
+            }
+            ".next_state" => {
+                let s = if value == "INIT" { "" } else { value };
+                p.rule.set_next_state(s.to_string());
+            }
+            ".color" => {
+                p.rule.set_match_colors(try!(self.color_parser.parse(value)));
+            }
+            ".stop" => {
+                p.rule.set_stop(true);
+            }
+            ".stream" => {
+                p.rule.set_stream(try!(RuleParser::parse_stream(value)));
+            }
+            ".line_color" => {
+                p.rule.set_line_colors(try!(self.color_parser.parse(value)));
+            }
+            ".pre_line" => {
+                p.parts.pre_line = Some(value.to_string());
+            }
+            ".pre_line_color" => {
+                p.parts.pre_line_color = Some(try!(self.color_parser.parse(value)));
+            }
+            ".pre_line_align" => {
+                p.parts.pre_line_align = Some(try!(RuleParser::parse_align(value)));
+            }
+            ".post_line" => {
+                p.parts.post_line = Some(value.to_string());
+            }
+            ".post_line_align" => {
+                p.parts.post_line_align = Some(try!(RuleParser::parse_align(value)));
+            }
+            ".post_line_color" => {
+                p.parts.post_line_color = Some(try!(self.color_parser.parse(value)));
+            }
+            _ => return Err(RuleError::new(&format!("Unknown key '{}'.", key))),
+        }
+        Ok(())
+    }
+
+    /// Read a TOML rule file.
+    pub fn parse_toml(&self, filename: &str, rules: &mut Vec<Rule>) -> Result<(), RuleError> {
+        RuleParser::collect(filename, self.read_toml(filename)?, rules)
+    }
+
+    /// Same as `parse_toml()`, but keeps going after errors, and returns all
+    /// the rules with their problems.
+    pub fn read_toml(&self, filename: &str) -> Result<Vec<RuleEntry>, RuleError> {
+        debug!("Reading toml rule file from {}...", filename);
+        self.parse_entries(filename, &RuleParser::read_file(filename)?, Format::Toml)
+    }
+
+    /// Read the `[[test]]`s of a TOML rule file.
+    pub fn read_toml_tests(&self, filename: &str) -> Result<Vec<RuleTest>, RuleError> {
+        debug!("Reading tests from {}...", filename);
+        self.read_tests_str(filename, &RuleParser::read_file(filename)?, Format::Toml)
+    }
+
+    /// Same as `read_tests()`, but reads the tests from a string.
+    pub fn read_tests_str(&self,
+                          filename: &str,
+                          content: &str,
+                          format: Format)
+                          -> Result<Vec<RuleTest>, RuleError> {
+        if format == Format::Legacy {
+            return Ok(vec![]);
+        }
+        let doc = RuleParser::parse_document(filename, content, format)?;
+        RuleParser::tests_of(filename, content, &doc, format)
+    }
+
+    /// The tests in a document from `parse_document()`.
+    fn tests_of(filename: &str,
+                content: &str,
+                doc: &BTreeMap<String, Value>,
+                format: Format)
+                -> Result<Vec<RuleTest>, RuleError> {
+        let tests_array = match doc.get("test") {
+            Some(&Value::Array(ref v)) => v,
+            Some(_) => {
+                let e = RuleError::new(&format!("Invalid {}: 'test' must be {}s.",
+                                                format.name(),
+                                                tables(format, "test")));
+                return Err(RuleError::in_file(filename, e));
+            }
+            None => return Ok(vec![]),
+        };
+
+        let locations = locations(content, format, "test");
+        let mut tests = vec![];
+        for (index, raw_test) in tests_array.iter().enumerate() {
+            let line = locations.get(index).map(|l| l.0).unwrap_or(0);
+            let table = format!("test #{}", index + 1);
+            let test = raw_test.as_table()
+                .ok_or(RuleError::new("'test' key found, but it's not a table."))
+                .and_then(|t| RuleParser::parse_test(t, line));
+            tests.push(try!(test.map_err(|e| RuleError::at(filename, line, 0, &table, e))));
+        }
+        Ok(tests)
+    }
+
+    fn parse_test(table: &BTreeMap<String, Value>, line: usize) -> Result<RuleTest, RuleError> {
+        let input: Vec<String> = match table.get("input") {
+            Some(&Value::String(ref s)) => s.lines().map(|l| l.to_string()).collect(),
+            Some(_) => try!(RuleParser::slice_from_table(table, "input")),
+            None => return Err(RuleError::new("Missing key 'input'.")),
+        };
+        let mut test = RuleTest::new(input, line);
+        for key in table.keys() {
+            match key.as_ref() {
+                "input" => (), // Already parsed.
+                k @ "name" => {
+                    test.name = Some(try!(RuleParser::str_from_table(table, k)).to_string());
                 }
-                ".pre_line_color" => {
-                    state.pre_line_color = Some(try!(self.color_parser.parse(value)));
+                "expect" => {
+                    let error = "Key 'expect' must contain an array of tables.";
+                    let expects = try!(table["expect"].as_slice().ok_or(RuleError::new(error)));
+                    for e in expects {
+                        let t = try!(e.as_table().ok_or(RuleError::new(error)));
+                        test.expect.push(try!(RuleParser::parse_expectation(t)));
+                    }
                 }
-                ".post_line" => {
-                    state.post_line = Some(value.to_string());
+                _ => return Err(RuleError::new(&format!("Unknown key '{}'.", key))),
+            }
+        }
+        if test.expect.len() > test.input.len() {
+            return Err(RuleError::new("More 'expect's than input lines."));
+        }
+        Ok(test)
+    }
+
+    fn parse_expectation(table: &BTreeMap<String, Value>) -> Result<Expectation, RuleError> {
+        let mut e = Expectation::default();
+        for key in table.keys() {
+            match key.as_ref() {
+                k @ "rules" => {
+                    e.rules = Some(try!(RuleParser::slice_from_table(table, k)));
                 }
-                ".post_line_color" => {
-                    state.post_line_color = Some(try!(self.color_parser.parse(value)));
+                k @ "state" => {
+                    e.state = Some(try!(RuleParser::str_from_table(table, k)).to_string());
                 }
-                _ => {
-                    return Err(RuleError::new(&format!("Error reading from '{}': Invalid key \
-                                                        '{}'",
-                                                       filename,
-                                                       key)));
+                k @ "markup" => {
+                    e.markup = Some(try!(RuleParser::str_from_table(table, k)).to_string());
                 }
+                _ => return Err(RuleError::new(&format!("Unknown key '{}'.", key))),
             }
         }
-        state.add_rule(self, &mut rules);
-        Ok(())
+        Ok(e)
     }
 
-    pub fn parse_toml(&self, filename: &str, rules: &mut Vec<Rule>) -> Result<(), RuleError> {
-        debug!("Reading toml rule file from {}...", filename);
-
-        // Load file content.
+    fn read_file(filename: &str) -> Result<String, RuleError> {
         let mut rule = String::new();
         try!(File::open(&filename)
             .and_then(|mut f| f.read_to_string(&mut rule))
-            .map_err(|e| RuleError::new(&format!("Unable to open file '{}'", filename))));
+            .map_err(|e| {
+                RuleError::Io {
+                    file: filename.to_string(),
+                    source: e,
+                }
+            }));
+        Ok(rule)
+    }
 
+    /// Parse a TOML, YAML or JSON rule file into a table, and check it only
+    /// contains `rule`s and `test`s.  From here on, all the formats are read
+    /// the same way.
+    fn parse_document(filename: &str,
+                      content: &str,
+                      format: Format)
+                      -> Result<BTreeMap<String, Value>, RuleError> {
+        let mut rule = content.to_string();
         // If the first line starts with '/', then ignore it.
         if rule.starts_with("/") {
             if let Some(first_nl) = rule.find('\n') {
@@ -510,126 +806,993 @@ impl RuleParser {
                 rule.drain(..(first_nl));
             }
         }
-        // debug!("TOML: {}", rule);
-
-        // Parse TOML.
-        let mut parser = toml::Parser::new(&rule);
-        let toml = match parser.parse() {
-            Some(toml) => toml,
-            None => {
-                for err in &parser.errors {
-                    let (loline, locol) = parser.to_linecol(err.lo);
-                    let (hiline, hicol) = parser.to_linecol(err.hi);
-                    return Err(RuleError::new(&format!("Invalid TOML: {}:{}:{}-{}:{} error: {}",
-                                                       filename,
-                                                       loline + 1,
-                                                       locol + 1,
-                                                       hiline + 1,
-                                                       hicol + 1,
-                                                       err.desc)));
+        let invalid = |message: String| RuleError::in_file(filename, RuleError::new(&message));
+
+        let doc = match format {
+            Format::Toml => RuleParser::parse_toml_text(filename, &rule)?,
+            Format::Yaml | Format::Json => {
+                let value = if format == Format::Yaml {
+                    rule_format::parse_yaml(filename, &rule)?
+                } else {
+                    rule_format::parse_json(filename, &rule)?
+                };
+                match value {
+                    Value::Table(t) => t,
+                    _ => {
+                        return Err(invalid(format!("Invalid {}: Must be a table of 'rule's and \
+                                                    'test's.",
+                                                   format.name())))
+                    }
                 }
-                return Err(RuleError::new("Invalid TOML: Unknown error."));
             }
+            Format::Legacy => return Err(RuleError::new("Legacy rule files aren't structured.")),
         };
 
         // Parse the structure.
-        if toml.len() == 0 {
-            return Err(RuleError::new("Invalid TOML: No [[rule]] found."));
+        if doc.len() == 0 {
+            return Err(invalid(format!("Invalid {}: No {} found.",
+                                       format.name(),
+                                       tables(format, "rule"))));
         }
-        if toml.len() > 1 {
-            return Err(RuleError::new("Invalid TOML: Invalid TOML: Must only contain [[rule]]s."));
+        if doc.keys().any(|k| k != "rule" && k != "test") {
+            return Err(invalid(format!("Invalid {}: Must only contain {}s and {}s.",
+                                       format.name(),
+                                       tables(format, "rule"),
+                                       tables(format, "test"))));
         }
-        let rules_array = match toml.get("rule") {
+        Ok(doc)
+    }
+
+    fn parse_toml_text(filename: &str, rule: &str) -> Result<BTreeMap<String, Value>, RuleError> {
+        // debug!("TOML: {}", rule);
+
+        // Parse TOML.
+        let mut parser = toml::Parser::new(rule);
+        match parser.parse() {
+            Some(toml) => Ok(toml),
+            None => {
+                let mut errors: Vec<RuleError> = parser.errors
+                    .iter()
+                    .map(|err| {
+                        let (line, column) = parser.to_linecol(err.lo);
+                        RuleError::Syntax {
+                            file: filename.to_string(),
+                            format: Format::Toml,
+                            line: line + 1,
+                            column: column + 1,
+                            message: err.desc.clone(),
+                        }
+                    })
+                    .collect();
+                match errors.len() {
+                    0 => {
+                        Err(RuleError::Syntax {
+                            file: filename.to_string(),
+                            format: Format::Toml,
+                            line: 0,
+                            column: 0,
+                            message: "Unknown error.".to_string(),
+                        })
+                    }
+                    1 => Err(errors.pop().unwrap()),
+                    _ => Err(RuleError::Several(errors)),
+                }
+            }
+        }
+    }
+
+    fn parse_entries(&self,
+                     filename: &str,
+                     content: &str,
+                     format: Format)
+                     -> Result<Vec<RuleEntry>, RuleError> {
+        let doc = RuleParser::parse_document(filename, content, format)?;
+        self.entries_of(filename, content, &doc, format)
+    }
+
+    /// The rules in a document from `parse_document()`.
+    fn entries_of(&self,
+                  filename: &str,
+                  content: &str,
+                  doc: &BTreeMap<String, Value>,
+                  format: Format)
+                  -> Result<Vec<RuleEntry>, RuleError> {
+        let rules_array = match doc.get("rule") {
             Some(&Value::Array(ref v)) => v,
-            _ => return Err(RuleError::new("Invalid TOML: No [[rule]]s found.")),
+            _ => {
+                let e = RuleError::new(&format!("Invalid {}: No {}s found.",
+                                                format.name(),
+                                                tables(format, "rule")));
+                return Err(RuleError::in_file(filename, e));
+            }
         };
 
-        debug!("# rules={}", rules_array.len());
-
         debug!("Rules count={}", rules_array.len());
-        for raw_rule in rules_array {
+        let locations = locations(content, format, "rule");
+        let lines: Vec<&str> = content.lines().collect();
+        let mut entries = vec![];
+        for (index, raw_rule) in rules_array.iter().enumerate() {
             debug!("value={:?}", raw_rule);
-            let rule_table = try!(raw_rule.as_table()
-                .ok_or(RuleError::new("\'rule\' key fond, but it's not a table.")));
-
-            let pattern = try!(RuleParser::str_from_table(rule_table, "pattern"));
-            let mut rule = try!(Rule::new(&pattern));
-
-            let mut pre_line: Option<String> = None;
-            let mut pre_line_color: Option<Colors> = None;
-            let mut post_line: Option<String> = None;
-            let mut post_line_color: Option<Colors> = None;
+            let (line, key_lines) = match locations.get(index) {
+                Some(&(line, ref key_lines)) => (line, Some(key_lines)),
+                None => (0, None),
+            };
+            let rule_table = try!(raw_rule.as_table().ok_or_else(|| {
+                let e = RuleError::new("'rule' key found, but it's not a table.");
+                RuleError::at(filename, line, 0, &format!("rule #{}", index + 1), e)
+            }));
+            // The key and the value, or else the start of the table.
+            let spans = |key: &str| match key_lines.and_then(|k| k.get(key)) {
+                Some(&l) => {
+                    (Location::of_key(l, lines[l - 1]), Location::of_value(l, lines[l - 1], true))
+                }
+                None if line > 0 => {
+                    let header = Location::of_line(line, lines[line - 1]);
+                    (header.clone(), header)
+                }
+                None => (Location::default(), Location::default()),
+            };
 
+            let mut p = PendingRule::new(line,
+                                         RuleParser::str_from_table(rule_table, "pattern"),
+                                         spans("pattern").1,
+                                         self.options);
             for key in rule_table.keys() {
-                match key.as_ref() {
-                    "pattern" => (), // Already parsed.
-                    k @ "when" => {
-                        let p = try!(RuleParser::str_from_table(rule_table, k));
-                        try!(rule.set_when(p.to_string()));
-                        ()
-                    }
-                    k @ "color" => {
-                        let c = try!(self.color_parser
-                            .parse(try!(RuleParser::str_from_table(rule_table, k))));
-                        rule.set_match_colors(c);
-                        ()
-                    }
-                    k @ "line_color" => {
-                        rule.set_line_colors(try!(self.color_parser
-                            .parse(try!(RuleParser::str_from_table(rule_table, k)))));
-                        ()
-                    }
-                    k @ "states" => {
-                        rule.set_states(try!(RuleParser::slice_from_table(rule_table, k)));
-                    }
-                    k @ "next_state" => {
-                        rule.set_next_state(try!(RuleParser::str_from_table(rule_table, k))
-                            .to_string());
-                    }
-                    k @ "stop" => {
-                        rule.set_stop(try!(RuleParser::bool_from_table(rule_table, k)));
-                    }
-                    k @ "pre_line" => {
-                        pre_line = Some(try!(RuleParser::str_from_table(rule_table, k))
-                            .to_string());
-                        ()
-                    }
-                    k @ "pre_line_color" => {
-                        pre_line_color = Some(try!(self.color_parser
-                            .parse(try!(RuleParser::str_from_table(rule_table, k)))));
-                        ()
-                    }
-                    k @ "post_line" => {
-                        post_line = Some(try!(RuleParser::str_from_table(rule_table, k))
-                            .to_string());
-                        ()
-                    }
-                    k @ "post_line_color" => {
-                        post_line_color = Some(try!(self.color_parser
-                            .parse(try!(RuleParser::str_from_table(rule_table, k)))));
-                        ()
-                    }
-                    _ => return Err(RuleError::new(&format!("Unknown key '{}'.", key))),
+                let (key_location, value_location) = spans(key);
+                if let Err(e) = self.apply_toml_key(&mut p, rule_table, key, &value_location) {
+                    let value = rule_table[key].as_str();
+                    p.fail(e, format, key, value, key_location, value_location);
                 }
             }
-            if pre_line.is_some() || pre_line_color.is_some() {
-                rule.set_pre_line(self.new_decorative_line(&pre_line.unwrap_or("".to_string()),
-                                                      &pre_line_color));
+            entries.push(p.finish(self));
+        }
+
+        Ok(entries)
+    }
+
+    fn apply_toml_key(&self,
+                      p: &mut PendingRule,
+                      rule_table: &BTreeMap<String, Value>,
+                      key: &str,
+                      location: &Location)
+                      -> Result<(), RuleError> {
+        match key {
+            "pattern" => (), // Already parsed.
+            k @ "engine" => {
+                let engine = try!(Engine::parse(try!(RuleParser::str_from_table(rule_table, k))));
+                try!(p.rule.set_engine(engine));
+            }
+            k @ "ignore_case" => {
+                let v = try!(RuleParser::bool_from_table(rule_table, k));
+                try!(p.set_option(|o| o.ignore_case = v));
+            }
+            k @ "whole_word" => {
+                let v = try!(RuleParser::bool_from_table(rule_table, k));
+                try!(p.set_option(|o| o.whole_word = v));
+            }
+            k @ "literal" => {
+                let v = try!(RuleParser::bool_from_table(rule_table, k));
+                try!(p.set_option(|o| o.literal = v));
+            }
+            k @ "unicode" => {
+                let v = try!(RuleParser::bool_from_table(rule_table, k));
+                try!(p.set_option(|o| o.unicode = Some(v)));
+            }
+            k @ "when" => {
+                let w = try!(RuleParser::str_from_table(rule_table, k));
+                p.add_condition(Condition::When(w.to_string()), location);
+            }
+            k @ "when_all" => {
+                let patterns = try!(RuleParser::slice_from_table(rule_table, k));
+                p.add_condition(Condition::WhenAll(patterns), location);
+            }
+            k @ "when_any" => {
+                let patterns = try!(RuleParser::slice_from_table(rule_table, k));
+                p.add_condition(Condition::WhenAny(patterns), location);
+            }
+            k @ "unless" => {
+                let patterns = try!(RuleParser::slice_from_table(rule_table, k));
+                p.add_condition(Condition::Unless(patterns), location);
+            }
+            k @ "name" => {
+                p.rule.set_name(try!(RuleParser::str_from_table(rule_table, k)).to_string());
+            }
+            k @ "description" => {
+                p.rule.set_description(try!(RuleParser::str_from_table(rule_table, k))
+                    .to_string());
+            }
+            k @ "tag" => {
+                p.rule.set_tag(try!(RuleParser::str_from_table(rule_table, k)).to_string());
+            }
+            k @ "color" => {
+                let c = try!(self.color_parser
+                    .parse(try!(RuleParser::str_from_table(rule_table, k))));
+                p.rule.set_match_colors(c);
+            }
+            k @ "line_color" => {
+                p.rule.set_line_colors(try!(self.color_parser
+                    .parse(try!(RuleParser::str_from_table(rule_table, k)))));
+            }
+            k @ "states" => {
+                p.rule.set_states(try!(RuleParser::slice_from_table(rule_table, k)));
+            }
+            k @ "next_state" => {
+                p.rule.set_next_state(try!(RuleParser::str_from_table(rule_table, k))
+                    .to_string());
+            }
+            k @ "stop" => {
+                p.rule.set_stop(try!(RuleParser::bool_from_table(rule_table, k)));
+            }
+            k @ "stream" => {
+                p.rule.set_stream(try!(RuleParser::parse_stream(
+                    try!(RuleParser::str_from_table(rule_table, k)))));
             }
-            if post_line.is_some() || post_line_color.is_some() {
-                rule.set_post_line(self.new_decorative_line(&post_line.unwrap_or("".to_string()),
-                                                            &post_line_color));
+            k @ "pre_line" => {
+                p.parts.pre_line = Some(try!(RuleParser::str_from_table(rule_table, k))
+                    .to_string());
             }
+            k @ "pre_line_color" => {
+                p.parts.pre_line_color = Some(try!(self.color_parser
+                    .parse(try!(RuleParser::str_from_table(rule_table, k)))));
+            }
+            k @ "pre_line_align" => {
+                p.parts.pre_line_align = Some(try!(RuleParser::parse_align(
+                    try!(RuleParser::str_from_table(rule_table, k)))));
+            }
+            k @ "post_line_align" => {
+                p.parts.post_line_align = Some(try!(RuleParser::parse_align(
+                    try!(RuleParser::str_from_table(rule_table, k)))));
+            }
+            k @ "post_line" => {
+                p.parts.post_line = Some(try!(RuleParser::str_from_table(rule_table, k))
+                    .to_string());
+            }
+            k @ "post_line_color" => {
+                p.parts.post_line_color = Some(try!(self.color_parser
+                    .parse(try!(RuleParser::str_from_table(rule_table, k)))));
+            }
+            _ => return Err(RuleError::new(&format!("Unknown key '{}'.", key))),
+        }
+        Ok(())
+    }
 
+    /// Take the rules out of the entries, or the first error, with where it
+    /// is.
+    fn collect(filename: &str,
+               entries: Vec<RuleEntry>,
+               rules: &mut Vec<Rule>)
+               -> Result<(), RuleError> {
+        for (index, entry) in entries.into_iter().enumerate() {
+            if let Some(problem) = entry.errors.into_iter().next() {
+                return Err(RuleError::at(filename,
+                                         problem.location.line,
+                                         problem.location.column,
+                                         &format!("rule #{}", index + 1),
+                                         problem.error));
+            }
+            let rule = entry.rule.unwrap();
             debug!("rule={:?}", rule);
-
             rules.push(rule);
         }
-
         Ok(())
     }
 }
 
+/// A rule read from a rule file, with the problems found in it, so that all of
+/// them can be reported at once.
+#[derive(Debug)]
+pub struct RuleEntry {
+    /// The rule, or `None` if the pattern is missing or invalid.
+    pub rule: Option<Rule>,
+    /// The line where the rule starts, from 1, or 0 if unknown.
+    pub line: usize,
+    /// The errors in the rule.
+    pub errors: Vec<Problem>,
+}
+
+/// An error in a rule, with where it is, and a hint to fix it if there's one.
+#[derive(Debug)]
+pub struct Problem {
+    pub error: RuleError,
+    pub location: Location,
+    /// A suggestion, e.g. "did you mean 'color'?".
+    pub help: Option<String>,
+}
+
+/// A place in a rule file, for reporting.
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+pub struct Location {
+    /// The line, from 1, or 0 if unknown.
+    pub line: usize,
+    /// The column in characters, from 1, or 0 if unknown.
+    pub column: usize,
+    /// The number of characters to underline.
+    pub len: usize,
+    /// The text of the line.
+    pub text: String,
+}
+
+impl Location {
+    fn new(line: usize, text: &str, start: usize, end: usize) -> Location {
+        Location {
+            line: line,
+            column: text[..start].chars().count() + 1,
+            len: text[start..end].chars().count(),
+            text: text.to_string(),
+        }
+    }
+
+    /// A whole line, without the indentation.
+    pub fn of_line(line: usize, text: &str) -> Location {
+        let start = text.len() - text.trim_left().len();
+        Location::new(line, text, start, text.trim_right().len())
+    }
+
+    /// The key of a `key = value` line.
+    pub fn of_key(line: usize, text: &str) -> Location {
+        let start = text.len() - text.trim_left().len();
+        let end = text.find('=').unwrap_or(text.len());
+        Location::new(line, text, start, text[..end].trim_right().len())
+    }
+
+    /// The value of a `key = value` line.  A comment after a TOML value isn't
+    /// part of it.
+    pub fn of_value(line: usize, text: &str, toml: bool) -> Location {
+        let eq = match text.find('=') {
+            Some(eq) => eq + 1,
+            None => return Location::of_key(line, text),
+        };
+        let value = text[eq..].trim_left();
+        let start = text.len() - value.len();
+        let len = if toml { toml_value_len(value) } else { value.trim_right().len() };
+        Location::new(line, text, start, start + len)
+    }
+}
+
+/// The length of a TOML value at the start of `value`, up to a comment.
+fn toml_value_len(value: &str) -> usize {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, ch) in value.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some('"') if ch == '\\' => escaped = true,
+            Some(q) if ch == q => quote = None,
+            Some(_) => (),
+            None if ch == '\'' || ch == '"' => quote = Some(ch),
+            None if ch == '#' => return value[..i].trim_right().len(),
+            None => (),
+        }
+    }
+    value.trim_right().len()
+}
+
+/// The keys of a rule.  In legacy rule files, they start with '.', except for
+/// `pattern`.
+const RULE_KEYS: [&'static str; 25] = ["pattern",
+                                       "engine",
+                                       "ignore_case",
+                                       "whole_word",
+                                       "literal",
+                                       "unicode",
+                                       "when",
+                                       "when_all",
+                                       "when_any",
+                                       "unless",
+                                       "name",
+                                       "description",
+                                       "tag",
+                                       "color",
+                                       "line_color",
+                                       "states",
+                                       "next_state",
+                                       "stop",
+                                       "stream",
+                                       "pre_line",
+                                       "pre_line_color",
+                                       "pre_line_align",
+                                       "post_line",
+                                       "post_line_color",
+                                       "post_line_align"];
+
+/// The number of single character edits between two words.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..b.len() + 1).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let cost = if ca == cb { diagonal } else { diagonal + 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = cmp::min(cost, cmp::min(row[j], row[j + 1]) + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// The candidate closest to a misspelled word, if it's close enough.
+fn suggest<'a>(word: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    candidates.iter()
+        .map(|&c| (edit_distance(word, c), c))
+        .filter(|&(d, _)| d <= (word.len() + 2) / 3)
+        .min_by_key(|&(d, _)| d)
+        .map(|(_, c)| c)
+}
+
+/// The color spec with its misspelled color names fixed, if there's any, e.g.
+/// "bgreen/blue" for "bgren/bleu".
+fn suggest_colors(spec: &str) -> Option<String> {
+    let mut changed = false;
+    let parts: Vec<String> = spec.split('/')
+        .map(|part| {
+            let part = part.trim().to_lowercase();
+            if part.len() == 0 || !part.chars().all(|c| c >= 'a' && c <= 'z') {
+                return part;
+            }
+            // The attributes may look like the start of a name, as in "blue".
+            let attrs = part.chars().take_while(|&c| "bifus".contains(c)).count();
+            let best = (0..attrs + 1)
+                .filter_map(|i| {
+                    let (prefix, name) = part.split_at(i);
+                    suggest(name, &CONSOLE_NAMES)
+                        .map(|c| (edit_distance(name, c), format!("{}{}", prefix, c)))
+                })
+                .min_by_key(|&(d, _)| d);
+            match best {
+                Some((d, fixed)) if d > 0 => {
+                    changed = true;
+                    fixed
+                }
+                _ => part,
+            }
+        })
+        .collect();
+    if changed { Some(parts.join("/")) } else { None }
+}
+
+#[test]
+fn test_suggest() {
+    assert_eq!(3, edit_distance("kitten", "sitting"));
+    assert_eq!(Some("color"), suggest("colour", &RULE_KEYS));
+    assert_eq!(Some("line_color"), suggest("line_colour", &RULE_KEYS));
+    assert_eq!(None, suggest("bogus", &RULE_KEYS));
+    assert_eq!(Some("bgreen/blue".to_string()), suggest_colors("bgren/bleu"));
+    assert_eq!(Some("magenta".to_string()), suggest_colors("megenta"));
+    assert_eq!(None, suggest_colors("bred/blue"));
+    assert_eq!(None, suggest_colors("nocolor"));
+}
+
+/// The keys of the decorative lines, which are put together once all the keys
+/// of a rule are read.
+#[derive(Default)]
+struct LineParts {
+    pre_line: Option<String>,
+    pre_line_color: Option<Colors>,
+    pre_line_align: Option<Align>,
+    post_line: Option<String>,
+    post_line_color: Option<Colors>,
+    post_line_align: Option<Align>,
+}
+
+/// The patterns of a condition key, which are added to those of the rule.
+enum Condition {
+    When(String),
+    WhenAll(Vec<String>),
+    WhenAny(Vec<String>),
+    Unless(Vec<String>),
+}
+
+impl Condition {
+    fn add_to(&self, rule: &mut Rule) -> Result<(), RuleError> {
+        fn join(old: Vec<&String>, new: &[String]) -> Vec<String> {
+            old.into_iter().chain(new).cloned().collect()
+        }
+        match *self {
+            Condition::When(ref pattern) => rule.set_when(pattern.clone()).map(|_| ()),
+            Condition::WhenAll(ref patterns) => {
+                let all = join(rule.when_all(), patterns);
+                rule.set_when_all(all).map(|_| ())
+            }
+            Condition::WhenAny(ref patterns) => {
+                let all = join(rule.when_any(), patterns);
+                rule.set_when_any(all).map(|_| ())
+            }
+            Condition::Unless(ref patterns) => {
+                let all = join(rule.unless(), patterns);
+                rule.set_unless(all).map(|_| ())
+            }
+        }
+    }
+}
+
+/// A rule being read.  The keys go to a stand-in rule, and the pattern and
+/// the conditions are compiled by `finish()`, once the engine and the options
+/// are known.  If the pattern is invalid, the rest is still checked.
+struct PendingRule {
+    rule: Rule,
+    /// The pattern, or why it can't be read, and where it is.
+    pattern: Result<String, RuleError>,
+    pattern_location: Location,
+    /// The conditions, and where they are.
+    conditions: Vec<(Condition, Location)>,
+    parts: LineParts,
+    line: usize,
+    errors: Vec<Problem>,
+}
+
+impl PendingRule {
+    fn new(line: usize,
+           pattern: Result<&str, RuleError>,
+           pattern_location: Location,
+           options: Options)
+           -> PendingRule {
+        PendingRule {
+            rule: Rule::with_options("$^", options).unwrap(),
+            pattern: pattern.map(|p| p.to_string()),
+            pattern_location: pattern_location,
+            conditions: vec![],
+            parts: LineParts::default(),
+            line: line,
+            errors: vec![],
+        }
+    }
+
+    fn add_condition(&mut self, condition: Condition, location: &Location) {
+        self.conditions.push((condition, location.clone()));
+    }
+
+    fn set_option<F: FnOnce(&mut Options)>(&mut self, f: F) -> Result<(), RuleError> {
+        let mut options = self.rule.options();
+        f(&mut options);
+        self.rule.set_options(options).map(|_| ())
+    }
+
+    /// Record an error in a key.  It's reported at the key if the key is
+    /// unknown, or else at the value.
+    fn fail(&mut self,
+            error: RuleError,
+            format: Format,
+            key: &str,
+            value: Option<&str>,
+            key_location: Location,
+            value_location: Location) {
+        let prefix = if format == Format::Legacy { "." } else { "" };
+        let name = &key[cmp::min(prefix.len(), key.len())..];
+        let known = key.starts_with(prefix) && RULE_KEYS.contains(&name);
+        let (location, help) = if !known {
+            (key_location,
+             suggest(key.trim_left_matches('.'), &RULE_KEYS)
+                 .map(|k| format!("did you mean '{}{}'?", prefix, k)))
+        } else if name.ends_with("color") {
+            (value_location,
+             value.and_then(suggest_colors).map(|c| format!("did you mean '{}'?", c)))
+        } else if name == "engine" {
+            (value_location,
+             value.and_then(|v| suggest(v, &ENGINE_NAMES))
+                 .map(|e| format!("did you mean '{}'?", e)))
+        } else {
+            (value_location, None)
+        };
+        self.errors.push(Problem {
+            error: error,
+            location: location,
+            help: help,
+        });
+    }
+
+    fn finish(self, p: &RuleParser) -> RuleEntry {
+        let PendingRule { mut rule, pattern, pattern_location, conditions, parts, line, errors } =
+            self;
+        let mut errors = errors;
+        let valid = match pattern.and_then(|pattern| rule.set_pattern(&pattern).map(|_| ())) {
+            Ok(()) => true,
+            Err(e) => {
+                errors.push(Problem {
+                    error: e,
+                    location: pattern_location,
+                    help: None,
+                });
+                false
+            }
+        };
+        for (condition, location) in conditions {
+            if let Err(e) = condition.add_to(&mut rule) {
+                errors.push(Problem {
+                    error: e,
+                    location: location,
+                    help: None,
+                });
+            }
+        }
+        // In the order of the file.
+        errors.sort_by_key(|e| (e.location.line, e.location.column));
+        if parts.pre_line.is_some() || parts.pre_line_color.is_some() {
+            rule.set_pre_line(p.new_decorative_line(&parts.pre_line.unwrap_or(String::new()),
+                                                    &parts.pre_line_color,
+                                                    parts.pre_line_align));
+        }
+        if parts.post_line.is_some() || parts.post_line_color.is_some() {
+            rule.set_post_line(p.new_decorative_line(&parts.post_line.unwrap_or(String::new()),
+                                                     &parts.post_line_color,
+                                                     parts.post_line_align));
+        }
+        RuleEntry {
+            rule: if valid { Some(rule) } else { None },
+            line: line,
+            errors: errors,
+        }
+    }
+}
+
+/// How the tables of a key are written in the format, for messages.
+fn tables(format: Format, key: &str) -> String {
+    if format == Format::Toml {
+        format!("[[{}]]", key)
+    } else {
+        format!("'{}'", key)
+    }
+}
+
+/// The lines of the tables of a key and their keys, if they can be told.
+fn locations(content: &str, format: Format, name: &str) -> Vec<(usize, BTreeMap<String, usize>)> {
+    if format == Format::Toml {
+        toml_locations(content, name)
+    } else {
+        vec![]
+    }
+}
+
+/// Find the lines of the `[[name]]` tables and their keys, which toml::Parser
+/// doesn't keep.  Good enough for reporting; multi-line values may confuse it.
+fn toml_locations(content: &str, name: &str) -> Vec<(usize, BTreeMap<String, usize>)> {
+    let mut ret: Vec<(usize, BTreeMap<String, usize>)> = vec![];
+    let mut in_rule = false;
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_rule = line.trim_left_matches('[').trim_right_matches(']').trim() == name;
+            if in_rule {
+                ret.push((i + 1, BTreeMap::new()));
+            }
+            continue;
+        }
+        if !in_rule {
+            continue;
+        }
+        if let Some(p) = line.find('=') {
+            let key = line[..p].trim().trim_matches('"');
+            if key.len() > 0 && !key.starts_with('#') {
+                ret.last_mut().unwrap().1.entry(key.to_string()).or_insert(i + 1);
+            }
+        }
+    }
+    ret
+}
+
+#[test]
+fn test_read_entries() {
+    let p = RuleParser::new(Term::Xterm, 80);
+    let toml = "[[rule]]\n\
+                pattern = 'a'\n\
+                color = 'red'\n\
+                \n\
+                [[rule]]\n\
+                pattern = 'b('\n\
+                color = 'bgren' # Comment\n\
+                colour = 1\n"
+        .to_string();
+    let entries = p.parse_entries("x.toml", &toml, Format::Toml).unwrap();
+    assert_eq!(2, entries.len());
+    assert_eq!((1, 0), (entries[0].line, entries[0].errors.len()));
+    assert_eq!("a", entries[0].rule.as_ref().unwrap().pattern());
+    assert_eq!(5, entries[1].line);
+    assert!(entries[1].rule.is_none());
+    let errors = &entries[1].errors;
+    let at = |p: &Problem| (p.location.line, p.location.column, p.location.len);
+    assert_eq!(3, errors.len());
+    assert_eq!((6, 11, 4), at(&errors[0]));
+    assert!(errors[0].error.to_string().starts_with("Invalid regex pattern: b(: "));
+    assert_eq!("Invalid color: bgren", errors[1].error.to_string());
+    assert_eq!((7, 9, 7), at(&errors[1]));
+    assert_eq!("color = 'bgren' # Comment", errors[1].location.text);
+    assert_eq!(Some("did you mean 'bgreen'?".to_string()), errors[1].help);
+    assert_eq!("Unknown key 'colour'.", errors[2].error.to_string());
+    assert_eq!((8, 1, 6), at(&errors[2]));
+    assert_eq!(Some("did you mean 'color'?".to_string()), errors[2].help);
+
+    let legacy = "pattern=a\n\
+                  .color=red\n\
+                  .pre_line=-\n\
+                  pattern=b\n\
+                  .colour=red\n";
+    let entries = p.parse_legacy_entries("x", legacy.as_bytes()).unwrap();
+    assert_eq!(2, entries.len());
+    assert!(entries[0].rule.as_ref().unwrap().pre_line().is_some());
+    assert_eq!(4, entries[1].line);
+    assert_eq!(Location::of_key(5, ".colour=red"), entries[1].errors[0].location);
+    assert_eq!(Some("did you mean '.color'?".to_string()), entries[1].errors[0].help);
+
+    let mut rules = vec![];
+    let e = RuleParser::collect("x", entries, &mut rules).unwrap_err();
+    assert_eq!("x:5:1: Unknown key '.colour'.", error_chain(&e));
+    assert_eq!(1, rules.len());
+}
+
+#[test]
+fn test_option_keys() {
+    let mut p = RuleParser::new(Term::Xterm, 80);
+    let toml = "[[rule]]\n\
+                pattern = 'a.b('\n\
+                literal = true\n\
+                ignore_case = true\n\
+                \n\
+                [[rule]]\n\
+                pattern = 'x'\n\
+                unicode = 'no'\n";
+    let entries = p.parse_entries("x.toml", toml, Format::Toml).unwrap();
+    let rule = entries[0].rule.as_ref().unwrap();
+    assert_eq!(vec![(1, 5)], rule.matches("xA.B(x"));
+    assert_eq!("Key 'unicode' must contain a boolean.",
+               entries[1].errors[0].error.to_string());
+
+    let legacy = "pattern=log\n\
+                  .whole_word\n\
+                  .unicode = false\n\
+                  pattern=x\n\
+                  .literal = yes\n";
+    let entries = p.parse_legacy_entries("x", legacy.as_bytes()).unwrap();
+    let options = entries[0].rule.as_ref().unwrap().options();
+    assert!(options.whole_word && !options.literal);
+    assert_eq!(Some(false), options.unicode);
+    assert_eq!("Key '.literal' must be 'true' or 'false'.",
+               entries[1].errors[0].error.to_string());
+
+    // The defaults, e.g. from the command line, are overridden by the keys.
+    p.set_options(Options { ignore_case: true, ..Options::default() });
+    let toml = "[[rule]]\npattern = 'a'\n\n[[rule]]\npattern = 'b'\nignore_case = false\n";
+    let entries = p.parse_entries("x.toml", toml, Format::Toml).unwrap();
+    assert!(entries[0].rule.as_ref().unwrap().options().ignore_case);
+    assert!(!entries[1].rule.as_ref().unwrap().options().ignore_case);
+
+    let r = p.parse_simple_rule("a.b=red;lw").unwrap();
+    assert_eq!("a.b", r.pattern());
+    assert_eq!(Options { ignore_case: true, whole_word: true, literal: true, unicode: None },
+               r.options());
+    assert!(r.match_colors().is_some());
+    assert!(p.parse_simple_rule("x=;a").unwrap().options().unicode == Some(false));
+    assert!(p.parse_simple_rule("x=;q").is_err());
+}
+
+#[test]
+fn test_condition_keys() {
+    let p = RuleParser::new(Term::Xterm, 80);
+    let toml = "[[rule]]\n\
+                pattern = 'pid=\\d+'\n\
+                when_all = ['ActivityManager']\n\
+                unless = ['StrictMode', '!I/']\n\
+                \n\
+                [[rule]]\n\
+                pattern = 'x'\n\
+                when_any = 'y'\n";
+    let entries = p.parse_entries("x.toml", toml, Format::Toml).unwrap();
+    let rule = entries[0].rule.as_ref().unwrap();
+    assert_eq!(vec![(21, 28)], rule.matches("I/ActivityManager: x pid=123"));
+    assert_eq!(0, rule.matches("I/ActivityManager: StrictMode pid=123").len());
+    assert_eq!(0, rule.matches("W/ActivityManager: pid=123").len());
+    assert_eq!("Key 'when_any' must contain a string array.",
+               entries[1].errors[0].error.to_string());
+
+    let legacy = "pattern=x\n\
+                  .when_any = a\n\
+                  .when_any = !b\n\
+                  .unless = (\n";
+    let entries = p.parse_legacy_entries("x", legacy.as_bytes()).unwrap();
+    let rule = entries[0].rule.as_ref().unwrap();
+    assert_eq!(vec!["a", "!b"], rule.when_any());
+    assert_eq!(1, entries[0].errors.len());
+}
+
+#[test]
+fn test_engine_key() {
+    let p = RuleParser::new(Term::Xterm, 80);
+    let toml = "[[rule]]\n\
+                pattern = 'a'\n\
+                engine = 'regex'\n\
+                \n\
+                [[rule]]\n\
+                pattern = 'b'\n\
+                engine = 'rgex'\n";
+    let entries = p.parse_entries("x.toml", toml, Format::Toml).unwrap();
+    assert_eq!(Some(Engine::Regex), entries[0].rule.as_ref().unwrap().engine());
+    assert_eq!((7, 10), (entries[1].errors[0].location.line, entries[1].errors[0].location.column));
+    assert_eq!(Some("did you mean 'regex'?".to_string()), entries[1].errors[0].help);
+
+    // The pattern is compiled with the engine that comes after it.
+    let legacy = "pattern=a(?=b)\n\
+                  .engine=pcre\n\
+                  .when=c\n";
+    let entries = p.parse_legacy_entries("x", legacy.as_bytes()).unwrap();
+    if Engine::Pcre.is_available() {
+        assert_eq!(0, entries[0].errors.len());
+        assert_eq!(Some(Engine::Pcre), entries[0].rule.as_ref().unwrap().engine());
+    } else {
+        assert!(entries[0].rule.is_none());
+        let errors: Vec<String> = entries[0].errors.iter().map(|e| e.error.to_string()).collect();
+        assert_eq!(2, errors.len());
+        assert!(errors[0].contains("Look-ahead isn't supported by the 'regex' engine"));
+        assert_eq!("The 'pcre' engine isn't built in; build with '--features pcre'.", errors[1]);
+    }
+}
+
+#[test]
+fn test_conditions_before_engine() {
+    let p = RuleParser::new(Term::Xterm, 80);
+    let legacy = "pattern=a\n\
+                  .when=b(?=c)\n\
+                  .when_all=d\n\
+                  .when_all=e(?=f)\n\
+                  .engine=pcre\n";
+    let entries = p.parse_legacy_entries("x", legacy.as_bytes()).unwrap();
+    let rule = entries[0].rule.as_ref().unwrap();
+    if Engine::Pcre.is_available() {
+        assert_eq!(0, entries[0].errors.len());
+        assert_eq!(vec!["d", "e(?=f)"], rule.when_all());
+    } else {
+        let lines: Vec<usize> = entries[0].errors.iter().map(|e| e.location.line).collect();
+        assert_eq!(vec![2, 4, 5], lines);
+        assert_eq!(vec!["d"], rule.when_all());
+    }
+
+    // The same with the options, and an invalid pattern first.
+    let legacy = "pattern=(\n\
+                  .when=)\n\
+                  .unless=[\n\
+                  .literal=true\n";
+    let entries = p.parse_legacy_entries("x", legacy.as_bytes()).unwrap();
+    assert_eq!(0, entries[0].errors.len());
+    assert_eq!(vec!["["], entries[0].rule.as_ref().unwrap().unless());
+}
+
+#[test]
+fn test_read_formats() {
+    let p = RuleParser::new(Term::Xterm, 80);
+    let toml = "[[rule]]\n\
+                pattern = 'F '\n\
+                states = ['']\n\
+                next_state = 'fatal'\n\
+                pre_line = '#'\n\
+                pre_line_color = 'bred'\n\
+                \n\
+                [[rule]]\n\
+                pattern = 'x'\n\
+                when = 'y'\n\
+                color = '500/blue'\n\
+                stop = true\n\
+                \n\
+                [[test]]\n\
+                input = ['F x']\n\
+                [[test.expect]]\n\
+                state = 'fatal'\n";
+    let yaml = "# Comment\n\
+                rule:\n\
+                \x20 - pattern: 'F '\n\
+                \x20   states: ['']\n\
+                \x20   next_state: fatal\n\
+                \x20   pre_line: '#'\n\
+                \x20   pre_line_color: bred\n\
+                \x20 - pattern: x\n\
+                \x20   when: y\n\
+                \x20   color: 500/blue\n\
+                \x20   stop: true\n\
+                test:\n\
+                \x20 - input: ['F x']\n\
+                \x20   expect:\n\
+                \x20     - state: fatal\n";
+    let json = r##"{
+        "rule": [
+            {"pattern": "F ", "states": [""], "next_state": "fatal",
+             "pre_line": "#", "pre_line_color": "bred"},
+            {"pattern": "x", "when": "y", "color": "500/blue", "stop": true}
+        ],
+        "test": [{"input": ["F x"], "expect": [{"state": "fatal"}]}]
+    }"##;
+
+    let read = |content: &str, format: Format| {
+        let mut rules = vec![];
+        p.parse_str("x", content, format, &mut rules).unwrap();
+        ::convert::to_toml(&rules, &p.read_tests_str("x", content, format).unwrap())
+    };
+    let expected = read(toml, Format::Toml);
+    assert_eq!(expected, read(yaml, Format::Yaml));
+    assert_eq!(expected, read(json, Format::Json));
+
+    // The keys are checked the same way.
+    let mut rules = vec![];
+    let yaml = "rule:\n  - pattern: x\n    stop: 'yes'\n";
+    assert_eq!("x: rule #1: Key 'stop' must contain a boolean.",
+               error_chain(&p.parse_str("x", yaml, Format::Yaml, &mut rules).unwrap_err()));
+    assert_eq!("x: Invalid JSON: Must only contain 'rule's and 'test's.",
+               error_chain(&p.parse_str("x", r#"{"rules": []}"#, Format::Json, &mut rules)
+                   .unwrap_err()));
+}
+
+#[test]
+fn test_parse_test() {
+    let table = |s: &str| RuleParser::parse_toml_text("x", s).unwrap();
+    let t = RuleParser::parse_test(&table("name = 't'\n\
+                                           input = \"a\\nb\"\n\
+                                           [[expect]]\n\
+                                           rules = ['r']\n\
+                                           state = 's'\n\
+                                           [[expect]]\n\
+                                           markup = '{red:b}'\n"),
+                                   7)
+        .unwrap();
+    assert_eq!(Some("t".to_string()), t.name);
+    assert_eq!(7, t.line);
+    assert_eq!(vec!["a", "b"], t.input);
+    assert_eq!(vec![Expectation {
+                        rules: Some(vec!["r".to_string()]),
+                        state: Some("s".to_string()),
+                        markup: None,
+                    },
+                    Expectation { markup: Some("{red:b}".to_string()), ..Default::default() }],
+               t.expect);
+
+    let t = RuleParser::parse_test(&table("input = ['a', 'b']"), 1).unwrap();
+    assert_eq!(vec!["a", "b"], t.input);
+    assert_eq!(None, t.name);
+    assert_eq!(0, t.expect.len());
+
+    let error = |s: &str| RuleParser::parse_test(&table(s), 1).unwrap_err().to_string();
+    assert_eq!("Missing key 'input'.", error("name = 't'"));
+    assert_eq!("Unknown key 'output'.", error("input = 'a'\noutput = 'b'"));
+    assert_eq!("Key 'expect' must contain an array of tables.",
+               error("input = 'a'\nexpect = [1]"));
+    assert_eq!("Unknown key 'color'.", error("input = 'a'\n[[expect]]\ncolor = 'red'"));
+    assert_eq!("More 'expect's than input lines.",
+               error("input = 'a'\n[[expect]]\nstate = ''\n[[expect]]\nstate = ''"));
+}
+
+#[test]
+fn test_toml_errors() {
+    let p = RuleParser::new(Term::Xterm, 80);
+    let mut rules = vec![];
+    let toml = "[[rule]]\npattern = 'a'\npattern = 'b'\n[[rule]]\ncolor = 'x'\ncolor = 'y'\n";
+    let e = p.parse_str("x", toml, Format::Toml, &mut rules).unwrap_err();
+    assert_eq!("Invalid TOML: x:3:1: duplicate key: `pattern`\n\
+                Invalid TOML: x:6:1: duplicate key: `color`",
+               error_chain(&e));
+    let e = p.parse_str("x", "a = 'x\n", Format::Toml, &mut rules).unwrap_err();
+    assert_eq!("Invalid TOML: x:1:5: literal strings cannot contain newlines",
+               error_chain(&e));
+}
+
+#[test]
+fn test_read_toml_tests() {
+    let p = RuleParser::new(Term::Xterm, 80);
+    let tests = p.read_toml_tests("samples/highlighter-logcat.toml").unwrap();
+    assert_eq!(2, tests.len());
+    assert_eq!(Some("log start".to_string()), tests[0].name);
+    assert_eq!(450, tests[0].line);
+    assert_eq!(vec!["--------- beginning of main"], tests[0].input);
+    assert_eq!(vec![Expectation {
+                        rules: Some(vec!["log-start".to_string()]),
+                        state: Some("".to_string()),
+                        markup: None,
+                    }],
+               tests[0].expect);
+    assert_eq!(3, tests[1].input.len());
+
+    let e = p.read_toml_tests("samples/no-such-file.toml").unwrap_err();
+    assert!(error_chain(&e).starts_with("Unable to read file 'samples/no-such-file.toml'"));
+
+    let content = "[[rule]]\npattern = 'x'\n\n[[test]]\ninput = 'x'\nexpect = 'x'\n";
+    assert_eq!("x:4: Key 'expect' must contain an array of tables.",
+               error_chain(&p.read_tests_str("x", content, Format::Toml).unwrap_err()));
+}
+
 #[test]
 fn test_rule_parser() {
     let p = RuleParser::new(Term::Xterm, 80);
@@ -0,0 +1,197 @@
+use std::fmt;
+
+use super::*;
+use term_color::*;
+use rule::*;
+use filter::*;
+
+/// The expected outcome for an input line of a test.  Only the given fields
+/// are checked.
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+pub struct Expectation {
+    /// The names of the matched rules, or their patterns if unnamed.
+    pub rules: Option<Vec<String>>,
+    /// The state after the line.  The initial state is "".
+    pub state: Option<String>,
+    /// The highlighted line, as given by `markup()`.
+    pub markup: Option<String>,
+}
+
+/// A `[[test]]` in a rule file: input lines, and what should happen to them.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct RuleTest {
+    pub name: Option<String>,
+    /// The line of the test in the rule file.
+    pub line: usize,
+    pub input: Vec<String>,
+    /// The expectations for the first lines of the input.
+    pub expect: Vec<Expectation>,
+}
+
+impl RuleTest {
+    pub fn new(input: Vec<String>, line: usize) -> RuleTest {
+        RuleTest {
+            name: None,
+            line: line,
+            input: input,
+            expect: vec![],
+        }
+    }
+
+    /// Run the input through a new `Filter`, and compare with the expectations.
+    pub fn run(&self, rules: &Vec<Rule>) -> Vec<Failure> {
+        let mut filter = Filter::new(Term::Xterm, rules.clone());
+        let mut ret = vec![];
+        for (i, line) in self.input.iter().enumerate() {
+            let mut text = String::new();
+            filter.process_styled(line, |l| if let StyledLine::Text(spans) = l {
+                text = markup(&spans);
+            });
+            let expect = match self.expect.get(i) {
+                Some(e) => e,
+                None => continue,
+            };
+
+            let mut failure = Failure {
+                line_no: i + 1,
+                input: line.clone(),
+                diffs: vec![],
+            };
+            if let Some(ref rules_expected) = expect.rules {
+                let actual: Vec<String> =
+                    filter.last_matches().iter().map(|&r| rules[r].label().clone()).collect();
+                if actual != *rules_expected {
+                    failure.diffs.push(("rules", rules_expected.join(", "), actual.join(", ")));
+                }
+            }
+            if let Some(ref state) = expect.state {
+                if filter.state() != state {
+                    failure.diffs.push(("state", state.clone(), filter.state().clone()));
+                }
+            }
+            if let Some(ref m) = expect.markup {
+                if *m != text {
+                    failure.diffs.push(("markup", m.clone(), text));
+                }
+            }
+            if failure.diffs.len() > 0 {
+                ret.push(failure);
+            }
+        }
+        ret
+    }
+}
+
+impl fmt::Display for RuleTest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name {
+            Some(ref name) => write!(f, "'{}'", name),
+            None => write!(f, "at line {}", self.line),
+        }
+    }
+}
+
+/// An input line that didn't turn out as expected, with the fields that
+/// differ, and their expected and actual values.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Failure {
+    pub line_no: usize,
+    pub input: String,
+    pub diffs: Vec<(&'static str, String, String)>,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "  input line {}: {}", self.line_no, self.input)?;
+        for &(field, ref expected, ref actual) in &self.diffs {
+            write!(f, "\n  - {}: {}\n  + {}: {}", field, expected, field, actual)?;
+        }
+        Ok(())
+    }
+}
+
+/// Write the highlighted spans in a readable form.  Spans with colors are
+/// written as "{spec:text}", where spec is in the rule file syntax, e.g.
+/// "{b500/blue:ERROR}".  "{", "}" and "\" in the text are escaped with "\".
+pub fn markup(spans: &[StyledSpan]) -> String {
+    let mut ret = String::new();
+    for span in spans {
+        let mut text = String::new();
+        for ch in span.text.chars() {
+            if ch == '{' || ch == '}' || ch == '\\' {
+                text.push('\\');
+            }
+            text.push(ch);
+        }
+        if span.fg == Color::None && span.bg == Color::None && span.attrs == ATTR_NONE {
+            ret.push_str(&text);
+            continue;
+        }
+        let mut spec = span.attrs.spec();
+        spec.push_str(&span.fg.spec());
+        if span.bg != Color::None {
+            spec.push('/');
+            spec.push_str(&span.bg.spec());
+        }
+        ret.push_str(&format!("{{{}:{}}}", spec, text));
+    }
+    ret
+}
+
+#[test]
+fn test_markup() {
+    let span = |text: &str, fg, bg, attrs| {
+        StyledSpan {
+            text: text.to_string(),
+            fg: fg,
+            bg: bg,
+            attrs: attrs,
+        }
+    };
+    assert_eq!("a {b500/blue:E\\{1\\}} {/cyan:x}",
+               markup(&[span("a ", Color::None, Color::None, ATTR_NONE),
+                        span("E{1}", Color::with_xterm_color(500), Color::Console(4), ATTR_INTENSE),
+                        span(" ", Color::None, Color::None, ATTR_NONE),
+                        span("x", Color::None, Color::Console(6), ATTR_NONE)]));
+}
+
+#[test]
+fn test_run() {
+    let mut error = Rule::new("ERROR").unwrap();
+    error.set_name("error".to_string())
+        .set_next_state("error".to_string())
+        .set_match_colors(Colors::with_colors(Color::Console(1), Color::None, ATTR_NONE, Term::Xterm));
+    let digits = Rule::new(r"\d+").unwrap();
+    let rules = vec![error, digits];
+
+    let mut test = RuleTest::new(vec!["ERROR 1".to_string(), "ok".to_string()], 10);
+    test.expect.push(Expectation {
+        rules: Some(vec!["error".to_string(), r"\d+".to_string()]),
+        state: Some("error".to_string()),
+        markup: Some("{red:ERROR} 1".to_string()),
+    });
+    test.expect.push(Expectation {
+        rules: Some(vec![]),
+        state: Some("".to_string()),
+        markup: None,
+    });
+    let failures = test.run(&rules);
+    assert_eq!(1, failures.len());
+    assert_eq!(2, failures[0].line_no);
+    assert_eq!(vec![("state", "".to_string(), "error".to_string())], failures[0].diffs);
+    assert_eq!("  input line 2: ok\n  - state: \n  + state: error", failures[0].to_string());
+}
+
+#[test]
+fn test_sample_tests() {
+    let parser = ::rule_parser::RuleParser::new(Term::Xterm, 80);
+    let mut rules = vec![];
+    let tests = parser.parse_file_with_tests("samples/highlighter-logcat.toml",
+                                             ::rule_format::Format::Toml,
+                                             &mut rules)
+        .unwrap();
+    assert!(tests.len() > 0);
+    for test in &tests {
+        assert_eq!(Vec::<Failure>::new(), test.run(&rules), "test {}", test);
+    }
+}
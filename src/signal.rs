@@ -0,0 +1,68 @@
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use libc;
+
+/// Bitmap of the signals that have been delivered but not taken yet.
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Bitmap of the pending signals that were sent by a process with kill(),
+/// rather than by the kernel, e.g. for ^C on the terminal.
+static SENT: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn on_signal(sig: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    let bit = 1 << sig;
+    if !info.is_null() && unsafe { (*info).si_code } <= 0 {
+        SENT.fetch_or(bit, Ordering::SeqCst);
+    } else {
+        SENT.fetch_and(!bit, Ordering::SeqCst);
+    }
+    PENDING.fetch_or(bit, Ordering::SeqCst);
+}
+
+/// Start catching a signal.  Use `take()` to check whether it's been delivered.
+pub fn catch(sig: libc::c_int) {
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = on_signal as extern "C" fn(libc::c_int,
+                                                         *mut libc::siginfo_t,
+                                                         *mut libc::c_void) as
+                              libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART | libc::SA_SIGINFO;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(sig, &action, ::std::ptr::null_mut());
+    }
+}
+
+/// Returns whether the signal has been delivered since the last call.
+pub fn take(sig: libc::c_int) -> bool {
+    take_with_origin(sig).is_some()
+}
+
+/// Same as `take()`, but also tells whether the signal was sent by a process,
+/// as opposed to the kernel.
+pub fn take_with_origin(sig: libc::c_int) -> Option<bool> {
+    let bit = 1 << sig;
+    if (PENDING.fetch_and(!bit, Ordering::SeqCst) & bit) != 0 {
+        Some((SENT.fetch_and(!bit, Ordering::SeqCst) & bit) != 0)
+    } else {
+        None
+    }
+}
+
+#[test]
+fn test_catch_and_take() {
+    catch(libc::SIGUSR1);
+    assert!(!take(libc::SIGUSR1));
+    unsafe {
+        libc::raise(libc::SIGUSR1);
+    }
+    assert!(take(libc::SIGUSR1));
+    assert!(!take(libc::SIGUSR1));
+
+    catch(libc::SIGUSR2);
+    unsafe {
+        libc::kill(libc::getpid(), libc::SIGUSR2);
+    }
+    assert_eq!(Some(true), take_with_origin(libc::SIGUSR2));
+    assert_eq!(None, take_with_origin(libc::SIGUSR2));
+}
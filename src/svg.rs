@@ -0,0 +1,237 @@
+use std::fmt::Write;
+use unicode_width::UnicodeWidthStr;
+
+use term_color::*;
+use filter::*;
+use rule::truncate;
+
+const FONT_SIZE: f32 = 14.0;
+const CELL_WIDTH: f32 = 8.4;
+const CELL_HEIGHT: f32 = 18.0;
+const PADDING: f32 = 8.0;
+
+const DEFAULT_FG: (u8, u8, u8) = (0xe5, 0xe5, 0xe5);
+const DEFAULT_BG: (u8, u8, u8) = (0x00, 0x00, 0x00);
+
+/// Renders highlighted lines into an SVG document, on a monospace grid of
+/// `width` columns.
+#[derive(Debug)]
+pub struct SvgRenderer {
+    width: usize,
+    rows: usize,
+    body: String,
+}
+
+fn to_hex((r, g, b): (u8, u8, u8)) -> String {
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+pub fn escape_xml(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    for ch in s.chars() {
+        match ch {
+            '&' => ret.push_str("&amp;"),
+            '<' => ret.push_str("&lt;"),
+            '>' => ret.push_str("&gt;"),
+            '"' => ret.push_str("&quot;"),
+            c if c < ' ' => ret.push(' '), // Control characters aren't allowed in XML.
+            c => ret.push(c),
+        }
+    }
+    ret
+}
+
+#[test]
+fn test_escape_xml() {
+    assert_eq!("a&lt;b&gt; &amp; &quot;c&quot; d",
+               escape_xml("a<b> & \"c\"\td"));
+}
+
+impl SvgRenderer {
+    pub fn new(width: usize) -> SvgRenderer {
+        SvgRenderer {
+            width: width,
+            rows: 0,
+            body: String::new(),
+        }
+    }
+
+    pub fn add_line(&mut self, line: &StyledLine) {
+        self.add_prefixed_line(&[], line);
+    }
+
+    /// Add a line, after the `prefix` spans, e.g. a file name and a line number.
+    pub fn add_prefixed_line(&mut self, prefix: &[StyledSpan], line: &StyledLine) {
+        let mut col = 0;
+        for span in prefix {
+            col = self.add_span(col, &span.text, span.fg, span.bg, span.attrs);
+        }
+        match line {
+            &StyledLine::Text(ref spans) => {
+                for span in spans {
+                    col = self.add_span(col, &span.text, span.fg, span.bg, span.attrs);
+                }
+            }
+            &StyledLine::Decorative(dl, ref text) => {
+                let (fg, bg, attrs) = match dl.colors() {
+                    Some(c) => (c.fg(), c.bg(), c.attrs()),
+                    None => (Color::None, Color::None, ATTR_NONE),
+                };
+                self.add_span(col, text, fg, bg, attrs);
+            }
+        }
+        self.rows += 1;
+    }
+
+    /// Add a span at column `col` of the current row, and return the column
+    /// next to it.  Wide characters take two columns, and characters beyond the
+    /// width are dropped.
+    fn add_span(&mut self, col: usize, text: &str, fg: Color, bg: Color, attrs: Attribute) -> usize {
+        // Control characters are drawn as spaces, so count them as such.
+        let text: String = text.chars().map(|c| if c < ' ' { ' ' } else { c }).collect();
+        let text = truncate(&text, self.width.saturating_sub(col));
+        let len = text.width();
+        if len == 0 {
+            return col;
+        }
+        let x = PADDING + (col as f32) * CELL_WIDTH;
+        let y = PADDING + (self.rows as f32) * CELL_HEIGHT;
+
+        if let Some(rgb) = bg.rgb() {
+            write!(&mut self.body,
+                   "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{}\"/>\n",
+                   x,
+                   y,
+                   (len as f32) * CELL_WIDTH,
+                   CELL_HEIGHT,
+                   to_hex(rgb))
+                .unwrap();
+        }
+        if text.trim().len() > 0 || attrs.intersects(ATTR_UNDERLINE | ATTR_STRIKE) {
+            write!(&mut self.body,
+                   "<text x=\"{}\" y=\"{}\" textLength=\"{}\" lengthAdjust=\"spacingAndGlyphs\" \
+                    fill=\"{}\"",
+                   x,
+                   y + FONT_SIZE,
+                   (len as f32) * CELL_WIDTH,
+                   to_hex(fg.rgb().unwrap_or(DEFAULT_FG)))
+                .unwrap();
+            if attrs.contains(ATTR_INTENSE) {
+                self.body.push_str(" font-weight=\"bold\"");
+            }
+            if attrs.contains(ATTR_ITALIC) {
+                self.body.push_str(" font-style=\"italic\"");
+            }
+            if attrs.contains(ATTR_FAINT) {
+                self.body.push_str(" opacity=\"0.6\"");
+            }
+            if attrs.contains(ATTR_UNDERLINE) {
+                self.body.push_str(" text-decoration=\"underline\"");
+            } else if attrs.contains(ATTR_STRIKE) {
+                self.body.push_str(" text-decoration=\"line-through\"");
+            }
+            write!(&mut self.body, ">{}</text>\n", escape_xml(&text)).unwrap();
+        }
+        col + len
+    }
+
+    /// Build the whole SVG document.
+    pub fn finish(&self) -> String {
+        let width = PADDING * 2.0 + (self.width as f32) * CELL_WIDTH;
+        let height = PADDING * 2.0 + (self.rows as f32) * CELL_HEIGHT;
+
+        let mut ret = String::new();
+        write!(&mut ret,
+               "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" \
+                viewBox=\"0 0 {} {}\" font-family=\"monospace\" font-size=\"{}\" \
+                xml:space=\"preserve\">\n",
+               width,
+               height,
+               width,
+               height,
+               FONT_SIZE)
+            .unwrap();
+        write!(&mut ret,
+               "<rect width=\"100%\" height=\"100%\" fill=\"{}\"/>\n",
+               to_hex(DEFAULT_BG))
+            .unwrap();
+        ret.push_str(&self.body);
+        ret.push_str("</svg>\n");
+        ret
+    }
+}
+
+#[test]
+fn test_svg_renderer() {
+    let mut r = SvgRenderer::new(4);
+    r.add_line(&StyledLine::Text(vec![StyledSpan {
+                                          text: "ab".to_string(),
+                                          fg: Color::None,
+                                          bg: Color::None,
+                                          attrs: ATTR_NONE,
+                                      },
+                                      StyledSpan {
+                                          text: "cdef".to_string(),
+                                          fg: Color::Console(1),
+                                          bg: Color::Rgb(0, 0, 255),
+                                          attrs: ATTR_INTENSE,
+                                      }]));
+    let svg = r.finish();
+    assert!(svg.contains(">ab</text>"));
+    // Truncated to the width.
+    assert!(svg.contains("fill=\"#cd0000\" font-weight=\"bold\">cd</text>"));
+    assert!(svg.contains("<rect x=\"24.8\" y=\"8\" width=\"16.8\" height=\"18\" fill=\"#0000ff\"/>"));
+}
+
+#[test]
+fn test_svg_renderer_prefix() {
+    let mut r = SvgRenderer::new(10);
+    let prefix = vec![StyledSpan {
+                          text: "a.log".to_string(),
+                          fg: Color::Console(5),
+                          bg: Color::None,
+                          attrs: ATTR_NONE,
+                      },
+                      StyledSpan {
+                          text: ":".to_string(),
+                          fg: Color::None,
+                          bg: Color::None,
+                          attrs: ATTR_NONE,
+                      }];
+    r.add_prefixed_line(&prefix,
+                        &StyledLine::Text(vec![StyledSpan {
+                                                   text: "text".to_string(),
+                                                   fg: Color::None,
+                                                   bg: Color::None,
+                                                   attrs: ATTR_NONE,
+                                               }]));
+    let svg = r.finish();
+    assert!(svg.contains("<text x=\"8\" y=\"22\" textLength=\"42\" \
+                          lengthAdjust=\"spacingAndGlyphs\" fill=\"#cd00cd\">a.log</text>"));
+    assert!(svg.contains("<text x=\"50\" y=\"22\" textLength=\"8.4\" \
+                          lengthAdjust=\"spacingAndGlyphs\" fill=\"#e5e5e5\">:</text>"));
+    assert!(svg.contains(">text</text>"));
+}
+
+#[test]
+fn test_svg_renderer_wide_chars() {
+    let mut r = SvgRenderer::new(5);
+    r.add_line(&StyledLine::Text(vec![StyledSpan {
+                                          text: "\u{65e5}\u{672c}\u{8a9e}".to_string(),
+                                          fg: Color::None,
+                                          bg: Color::Rgb(0, 0, 255),
+                                          attrs: ATTR_NONE,
+                                      },
+                                      StyledSpan {
+                                          text: "a".to_string(),
+                                          fg: Color::None,
+                                          bg: Color::None,
+                                          attrs: ATTR_NONE,
+                                      }]));
+    let svg = r.finish();
+    // The third character doesn't fit in the last column.
+    assert!(svg.contains("<rect x=\"8\" y=\"8\" width=\"33.6\" height=\"18\" fill=\"#0000ff\"/>"));
+    assert!(svg.contains("textLength=\"33.6\""));
+    assert!(svg.contains(">\u{65e5}\u{672c}</text>"));
+    assert!(svg.contains("<text x=\"41.6\" y=\"22\" textLength=\"8.4\""));
+}
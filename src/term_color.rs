@@ -1,11 +1,26 @@
 use std::rc::Rc;
 use std::env;
 use std::cmp::max;
+use std::fmt;
 
 use super::*;
 
 const FG_BG_PLACE_HOLDER: char = '#';
 
+/// xterm's default RGB values for the 8 index colors.
+const CONSOLE_PALETTE: [(u8, u8, u8); 8] = [(0x00, 0x00, 0x00),
+                                            (0xcd, 0x00, 0x00),
+                                            (0x00, 0xcd, 0x00),
+                                            (0xcd, 0xcd, 0x00),
+                                            (0x00, 0x00, 0xee),
+                                            (0xcd, 0x00, 0xcd),
+                                            (0x00, 0xcd, 0xcd),
+                                            (0xe5, 0xe5, 0xe5)];
+
+/// The names of the index colors in rule files.
+pub const CONSOLE_NAMES: [&'static str; 8] = ["black", "red", "green", "yellow", "blue", "magenta",
+                                              "cyan", "white"];
+
 #[derive(Debug,Copy,Clone,PartialEq,Eq)]
 pub enum Color {
     /// Color not specified; take over previous color.
@@ -42,6 +57,34 @@ impl Color {
         return ((color256 as i32) * 5 / 255) as u8;
     }
 
+    /// Resolve the color into RGB values, using the xterm default palette for
+    /// index colors.
+    pub fn rgb(&self) -> Option<(u8, u8, u8)> {
+        match self {
+            &Color::None => None,
+            &Color::Console(index) => Some(CONSOLE_PALETTE[index as usize]),
+            &Color::Rgb(r, g, b) => Some((r, g, b)),
+        }
+    }
+
+    /// The color in the rule file syntax, e.g. "red", "500" or "ff8000".
+    pub fn spec(&self) -> String {
+        match self {
+            &Color::None => String::new(),
+            &Color::Console(index) => CONSOLE_NAMES[index as usize].to_string(),
+            &Color::Rgb(r, g, b) => {
+                if [r, g, b].iter().all(|&c| Color::color_6_to_256(Color::color_256_to_6(c)) == c) {
+                    format!("{}{}{}",
+                            Color::color_256_to_6(r),
+                            Color::color_256_to_6(g),
+                            Color::color_256_to_6(b))
+                } else {
+                    format!("{:02x}{:02x}{:02x}", r, g, b)
+                }
+            }
+        }
+    }
+
     #[test]
     fn test_color_6_256() {
         assert_eq!(0, Color::color_6_to_256(0));
@@ -53,6 +96,22 @@ impl Color {
     }
 }
 
+#[test]
+fn test_color_rgb() {
+    assert_eq!(None, Color::None.rgb());
+    assert_eq!(Some((0xcd, 0, 0)), Color::Console(1).rgb());
+    assert_eq!(Some((1, 2, 3)), Color::Rgb(1, 2, 3).rgb());
+    assert_eq!(Some((255, 0, 51)), Color::with_xterm_color(501).rgb());
+}
+
+#[test]
+fn test_color_spec() {
+    assert_eq!("", Color::None.spec());
+    assert_eq!("cyan", Color::Console(6).spec());
+    assert_eq!("501", Color::with_xterm_color(501).spec());
+    assert_eq!("ff8001", Color::Rgb(255, 128, 1).spec());
+}
+
 bitflags! {
     pub flags Attribute: u32 {
         const ATTR_NONE      = 0,
@@ -92,6 +151,21 @@ impl Attribute {
         ret.push_str(term.csi_end());
         ret
     }
+
+    /// The attributes in the rule file syntax, e.g. "bu".
+    pub fn spec(&self) -> String {
+        let mut ret = String::new();
+        for &(attr, ch) in &[(ATTR_INTENSE, 'b'),
+                             (ATTR_ITALIC, 'i'),
+                             (ATTR_UNDERLINE, 'u'),
+                             (ATTR_STRIKE, 's'),
+                             (ATTR_FAINT, 'f')] {
+            if self.contains(attr) {
+                ret.push(ch);
+            }
+        }
+        ret
+    }
 }
 
 #[test]
@@ -108,7 +182,7 @@ fn test_attr_to_ansi_code() {
                (ATTR_INTENSE | ATTR_ITALIC | ATTR_STRIKE).to_ansi_code(Term::Xterm));
 }
 
-#[derive(Debug,Clone,PartialEq,Eq)]
+#[derive(Clone)]
 pub struct Colors {
     attrs: Attribute, // whether bold or not.  only meaningful when used for FG.
 
@@ -117,12 +191,37 @@ pub struct Colors {
 
     fg_code: String, // ANSI color code for foreground
     bg_code: String, // ANSI color code for background.
+
+    spec: Option<String>, // As written in the rule file, if parsed from one.
 }
 
 lazy_static! {
     pub static ref NO_COLORS: Colors = Colors::new_empty();
 }
 
+// The spec is only how the colors were written, so colors parsed from "red"
+// and "1" are the same.
+impl PartialEq for Colors {
+    fn eq(&self, other: &Colors) -> bool {
+        self.attrs == other.attrs && self.fg == other.fg && self.bg == other.bg &&
+        self.fg_code == other.fg_code && self.bg_code == other.bg_code
+    }
+}
+
+impl Eq for Colors {}
+
+impl fmt::Debug for Colors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Colors")
+            .field("attrs", &self.attrs)
+            .field("fg", &self.fg)
+            .field("bg", &self.bg)
+            .field("fg_code", &self.fg_code)
+            .field("bg_code", &self.bg_code)
+            .finish()
+    }
+}
+
 impl Colors {
     pub fn new_empty() -> Colors {
         Colors {
@@ -131,6 +230,7 @@ impl Colors {
             bg: Color::None,
             fg_code: "".to_string(),
             bg_code: "".to_string(),
+            spec: None,
         }
     }
 
@@ -154,9 +254,30 @@ impl Colors {
             bg: bg,
             fg_code: fg_code,
             bg_code: bg_code,
+            spec: None,
         }
     }
 
+    /// Keep the spec the colors were parsed from, so they can be written back.
+    pub fn with_spec(mut self, spec: &str) -> Colors {
+        self.spec = Some(spec.to_string());
+        self
+    }
+
+    /// The colors in the rule file syntax, as they were written if known.
+    pub fn spec(&self) -> String {
+        if let Some(ref spec) = self.spec {
+            return spec.clone();
+        }
+        let mut ret = self.attrs.spec();
+        ret.push_str(&self.fg.spec());
+        if self.bg != Color::None {
+            ret.push('/');
+            ret.push_str(&self.bg.spec());
+        }
+        ret
+    }
+
     pub fn fg_code(&self) -> &String {
         &self.fg_code
     }
@@ -173,6 +294,10 @@ impl Colors {
         self.bg
     }
 
+    pub fn attrs(&self) -> Attribute {
+        self.attrs
+    }
+
     pub fn get_color(&self, fg: bool) -> Color {
         if fg { self.fg } else { self.bg }
     }
@@ -186,6 +311,21 @@ impl Colors {
     }
 }
 
+#[test]
+fn test_colors_spec() {
+    let c = Colors::with_colors(Color::with_xterm_color(500),
+                                Color::Console(4),
+                                ATTR_INTENSE | ATTR_UNDERLINE,
+                                Term::Xterm);
+    assert_eq!("bu500/blue", c.spec());
+    assert_eq!("/cyan",
+               Colors::with_colors(Color::None, Color::Console(6), ATTR_NONE, Term::Xterm).spec());
+    assert_eq!("b 500 / 004", c.clone().with_spec("b 500 / 004").spec());
+    // The spec doesn't count.
+    assert_eq!(c, c.clone().with_spec("b 500 / 004"));
+    assert_eq!(format!("{:?}", c), format!("{:?}", c.clone().with_spec("b 500 / 004")));
+}
+
 fn color_rgb_to_xterm(r: u8, g: u8, b: u8) -> u8 {
     let r6 = Color::color_256_to_6(r);
     let g6 = Color::color_256_to_6(g);
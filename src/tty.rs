@@ -0,0 +1,96 @@
+use std::env;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use libc;
+
+pub const STDIN_FD: RawFd = 0;
+pub const STDOUT_FD: RawFd = 1;
+pub const STDERR_FD: RawFd = 2;
+
+/// Whether a file descriptor refers to a terminal.
+pub fn is_tty(fd: RawFd) -> bool {
+    unsafe { libc::isatty(fd) == 1 }
+}
+
+/// Whether a file descriptor refers to a regular file, i.e. it has an end.
+pub fn is_regular_file(fd: RawFd) -> bool {
+    unsafe {
+        let mut st: libc::stat = mem::zeroed();
+        if libc::fstat(fd, &mut st) != 0 {
+            return false;
+        }
+        (st.st_mode & libc::S_IFMT) == libc::S_IFREG
+    }
+}
+
+/// Returns the (columns, rows) of the terminal.
+pub fn window_size(fd: RawFd) -> Option<(usize, usize)> {
+    unsafe {
+        let mut ws: libc::winsize = mem::zeroed();
+        if libc::ioctl(fd, libc::TIOCGWINSZ, &mut ws) != 0 || ws.ws_col == 0 {
+            return None;
+        }
+        Some((ws.ws_col as usize, ws.ws_row as usize))
+    }
+}
+
+/// Puts a terminal into the raw mode, and restores the original mode when dropped.
+pub struct RawMode {
+    fd: RawFd,
+    original: libc::termios,
+}
+
+impl RawMode {
+    pub fn enable(fd: RawFd) -> io::Result<RawMode> {
+        RawMode::enable_inner(fd, false)
+    }
+
+    /// Same as `enable()`, but keeps the output processing, so that "\n" still
+    /// goes to the start of the next line.
+    pub fn enable_input(fd: RawFd) -> io::Result<RawMode> {
+        RawMode::enable_inner(fd, true)
+    }
+
+    fn enable_inner(fd: RawFd, keep_output: bool) -> io::Result<RawMode> {
+        unsafe {
+            let mut original: libc::termios = mem::zeroed();
+            if libc::tcgetattr(fd, &mut original) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut raw = original;
+            libc::cfmakeraw(&mut raw);
+            if keep_output {
+                raw.c_oflag = original.c_oflag;
+            }
+            if libc::tcsetattr(fd, libc::TCSANOW, &raw) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(RawMode {
+                fd: fd,
+                original: original,
+            })
+        }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(self.fd, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+/// Detect the width of the terminal we're on, from any of the standard fds
+/// (stdout may be a pipe to a pager), or from $COLUMNS.
+pub fn terminal_width() -> Option<usize> {
+    for fd in &[STDOUT_FD, STDERR_FD, STDIN_FD] {
+        if let Some((cols, _)) = window_size(*fd) {
+            return Some(cols);
+        }
+    }
+    env::var("COLUMNS").ok().and_then(|v| v.trim().parse::<usize>().ok()).and_then(|w| {
+        if w > 0 { Some(w) } else { None }
+    })
+}
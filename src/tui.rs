@@ -0,0 +1,911 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::time::Duration;
+use libc;
+
+use super::*;
+use term_color::*;
+use rule::*;
+use filter::*;
+use tty;
+
+/// Max number of input lines to keep.
+const MAX_SCROLLBACK: usize = 100000;
+
+/// Max number of input lines to take before redrawing the screen.
+const MAX_LINES_PER_FRAME: usize = 10000;
+
+const POLL_INTERVAL_MS: u64 = 100;
+
+const HELP: &'static str = "q:quit /:search n/N:next/prev match p:freeze \
+                            t:toggle rule T:toggle tag r:choose rule [/]:prev/next rule match";
+
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum Key {
+    Char(char),
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+    Enter,
+    Esc,
+    Backspace,
+    Interrupt,
+}
+
+/// Abstraction of the terminal, so that the viewer can be tested without one.
+pub trait Backend {
+    /// Returns (columns, rows) of the screen.
+    fn size(&self) -> (usize, usize);
+
+    /// Replace the screen content.  Each row is an ANSI string that fits in the width.
+    fn draw(&mut self, rows: &[String]) -> io::Result<()>;
+
+    /// Wait for a key press for up to `timeout`.
+    fn poll_key(&mut self, timeout: Duration) -> io::Result<Option<Key>>;
+}
+
+#[derive(Debug)]
+struct ViewLine {
+    raw: String,
+    /// Output rows, including pre and post lines.
+    rows: Vec<Vec<StyledSpan>>,
+    /// Indexes of the matched rules.
+    rules: Vec<usize>,
+}
+
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+enum Prompt {
+    Search,
+    ToggleRule,
+    ToggleTag,
+    ChooseRule,
+}
+
+impl Prompt {
+    fn label(&self) -> &'static str {
+        match *self {
+            Prompt::Search => "/",
+            Prompt::ToggleRule => "Toggle rule #: ",
+            Prompt::ToggleTag => "Toggle tag: ",
+            Prompt::ChooseRule => "Choose rule #: ",
+        }
+    }
+}
+
+/// Full screen viewer that keeps the highlighted lines.
+pub struct Viewer {
+    filter: Filter,
+    lines: VecDeque<ViewLine>,
+
+    /// Index of the line at the top of the screen.
+    top: usize,
+    /// Number of rows to show the lines in.
+    height: usize,
+    /// Whether to keep showing the last lines as new lines come in.
+    tail: bool,
+
+    /// When frozen, new lines are kept here until unfrozen.
+    frozen: bool,
+    pending: Vec<String>,
+
+    prompt: Option<(Prompt, String)>,
+    /// Where to go back to when the search is cancelled.
+    saved_top: usize,
+    search: String,
+
+    /// Rule to jump between the matches of.
+    chosen_rule: Option<usize>,
+
+    message: String,
+    quit: bool,
+}
+
+fn sgr(term: Term, code: &str) -> String {
+    if term == Term::Dumb {
+        String::new()
+    } else {
+        format!("{}{}{}", term.csi_start(), code, term.csi_end())
+    }
+}
+
+/// Find the characters matching a search string.  Smart-case: the search is
+/// case insensitive unless it contains an upper case letter.
+fn search_marks(text: &str, search: &str) -> Vec<bool> {
+    let ignore_case = !search.chars().any(|c| c.is_uppercase());
+    let normalize = |c: char| if ignore_case {
+        c.to_lowercase().next().unwrap_or(c)
+    } else {
+        c
+    };
+    let text: Vec<char> = text.chars().map(&normalize).collect();
+    let search: Vec<char> = search.chars().map(&normalize).collect();
+
+    let mut ret = vec![false; text.len()];
+    if search.len() == 0 || search.len() > text.len() {
+        return ret;
+    }
+    for i in 0..(text.len() - search.len() + 1) {
+        if text[i..(i + search.len())] == search[..] {
+            for j in i..(i + search.len()) {
+                ret[j] = true;
+            }
+        }
+    }
+    ret
+}
+
+#[test]
+fn test_search_marks() {
+    assert_eq!(vec![false, false, false], search_marks("abc", ""));
+    assert_eq!(vec![false, true, true, false, true, true],
+               search_marks("aBcabc", "bc"));
+    assert_eq!(vec![false, true, true, false, false, false],
+               search_marks("aBcabc", "Bc"));
+}
+
+/// Build an ANSI string of a row, truncated to `cols`, with the search matches
+/// in reverse video.  Wide characters take two columns.
+fn render_row(term: Term, spans: &Vec<StyledSpan>, cols: usize, search: &str) -> String {
+    let text: String = spans.iter().map(|s| s.text.as_str()).collect();
+    let marks = search_marks(&text, search);
+
+    let mut ret = String::new();
+    let mut col = 0;
+    let mut i = 0;
+    for span in spans {
+        if col >= cols {
+            break;
+        }
+        let colors = Colors::with_colors(span.fg, span.bg, span.attrs, term);
+        ret.push_str(colors.fg_code());
+        ret.push_str(colors.bg_code());
+        let mut in_mark = false;
+        for ch in span.text.chars() {
+            let ch = if ch < ' ' { ' ' } else { ch };
+            let width = char_width(ch);
+            if col + width > cols {
+                col = cols;
+                break;
+            }
+            if marks[i] != in_mark {
+                in_mark = marks[i];
+                ret.push_str(&sgr(term, if in_mark { "7" } else { "27" }));
+            }
+            ret.push(ch);
+            col += width;
+            i += 1;
+        }
+        ret.push_str(term.csi_reset());
+    }
+    ret
+}
+
+#[test]
+fn test_render_row_wide_chars() {
+    let spans = vec![StyledSpan {
+                         text: "\u{65e5}\u{672c}".to_string(),
+                         fg: Color::None,
+                         bg: Color::None,
+                         attrs: ATTR_NONE,
+                     },
+                     StyledSpan {
+                         text: "ab".to_string(),
+                         fg: Color::None,
+                         bg: Color::None,
+                         attrs: ATTR_NONE,
+                     }];
+    assert_eq!("\u{65e5}\u{672c}a", render_row(Term::Dumb, &spans, 5, ""));
+    // A wide character that doesn't fit in the last column is dropped.
+    assert_eq!("\u{65e5}", render_row(Term::Dumb, &spans, 3, ""));
+    // The matches are found by characters, not by columns.
+    assert!(render_row(Term::Console, &spans, 6, "b").contains("a\x1b[7mb"));
+}
+
+fn decorative_span(dl: &DecorativeLine, text: String) -> StyledSpan {
+    let (fg, bg, attrs) = match dl.colors() {
+        Some(c) => (c.fg(), c.bg(), c.attrs()),
+        None => (Color::None, Color::None, ATTR_NONE),
+    };
+    StyledSpan {
+        text: text,
+        fg: fg,
+        bg: bg,
+        attrs: attrs,
+    }
+}
+
+fn highlight(filter: &mut Filter, raw: String) -> ViewLine {
+    let mut rows = vec![];
+    filter.process_styled(&raw, |l| {
+        rows.push(match l {
+            StyledLine::Text(spans) => spans,
+            StyledLine::Decorative(dl, text) => vec![decorative_span(dl, text)],
+        })
+    });
+    ViewLine {
+        raw: raw,
+        rows: rows,
+        rules: filter.last_matches().clone(),
+    }
+}
+
+impl Viewer {
+    pub fn new(filter: Filter) -> Viewer {
+        Viewer {
+            filter: filter,
+            lines: VecDeque::new(),
+            top: 0,
+            height: 1,
+            tail: true,
+            frozen: false,
+            pending: vec![],
+            prompt: None,
+            saved_top: 0,
+            search: String::new(),
+            chosen_rule: None,
+            message: String::new(),
+            quit: false,
+        }
+    }
+
+    pub fn is_quit(&self) -> bool {
+        self.quit
+    }
+
+    pub fn add_line(&mut self, line: String) {
+        if self.frozen {
+            self.pending.push(line);
+            return;
+        }
+        let vl = highlight(&mut self.filter, line);
+        self.lines.push_back(vl);
+        if self.lines.len() > MAX_SCROLLBACK {
+            self.lines.pop_front();
+            self.top = self.top.saturating_sub(1);
+        }
+    }
+
+    /// Switch to new rules, e.g. reloaded ones, and highlight the lines again.
+    pub fn set_rules(&mut self, rules: Vec<Rule>) {
+        self.filter.set_rules(rules);
+        if self.chosen_rule.map_or(false, |i| i >= self.filter.rules().len()) {
+            self.chosen_rule = None;
+        }
+        self.rehighlight();
+        self.message = "Rules reloaded".to_string();
+    }
+
+    /// Highlight all the lines again from the beginning, after the rules change.
+    fn rehighlight(&mut self) {
+        self.filter.reset_state();
+        for vl in self.lines.iter_mut() {
+            let raw = vl.raw.clone();
+            *vl = highlight(&mut self.filter, raw);
+        }
+    }
+
+    /// Index of the top line when showing the last lines.
+    fn bottom_top(&self) -> usize {
+        let mut rows = 0;
+        let mut i = self.lines.len();
+        while i > 0 && rows + self.lines[i - 1].rows.len() <= self.height {
+            rows += self.lines[i - 1].rows.len();
+            i -= 1;
+        }
+        // Show the top of the last line even if it doesn't fit.
+        if i == self.lines.len() && i > 0 { i - 1 } else { i }
+    }
+
+    fn scroll_to(&mut self, top: usize) {
+        let max = self.bottom_top();
+        self.top = if top > max { max } else { top };
+        self.tail = self.top == max;
+    }
+
+    fn scroll_by(&mut self, delta: isize) {
+        let top = self.top as isize + delta;
+        self.scroll_to(if top < 0 { 0 } else { top as usize });
+    }
+
+    fn find_line<P>(&self, from: usize, forward: bool, pred: P) -> Option<usize>
+        where P: Fn(&ViewLine) -> bool
+    {
+        if forward {
+            (from..self.lines.len()).find(|&i| pred(&self.lines[i]))
+        } else {
+            (0..from).rev().find(|&i| pred(&self.lines[i]))
+        }
+    }
+
+    fn find_search(&mut self, from: usize, forward: bool) {
+        if self.search.len() == 0 {
+            return;
+        }
+        let search = self.search.clone();
+        match self.find_line(from,
+                             forward,
+                             |l| search_marks(&l.raw, &search).iter().any(|&m| m)) {
+            Some(i) => self.scroll_to(i),
+            None => self.message = format!("Pattern not found: {}", search),
+        }
+    }
+
+    fn find_rule(&mut self, forward: bool) {
+        let rule = match self.chosen_rule {
+            Some(r) => r,
+            None => {
+                self.message = "No rule chosen; use 'r' first.".to_string();
+                return;
+            }
+        };
+        let from = if forward { self.top + 1 } else { self.top };
+        match self.find_line(from, forward, |l| l.rules.contains(&rule)) {
+            Some(i) => self.scroll_to(i),
+            None => self.message = format!("No more matches of rule #{}", rule),
+        }
+    }
+
+    fn set_frozen(&mut self, frozen: bool) {
+        self.frozen = frozen;
+        if !frozen {
+            let pending: Vec<String> = self.pending.drain(..).collect();
+            for line in pending {
+                self.add_line(line);
+            }
+        }
+    }
+
+    fn rule_label(&self, index: usize) -> String {
+        format!("#{} ({})", index, self.filter.rules()[index].pattern())
+    }
+
+    fn parse_rule_index(&mut self, input: &str) -> Option<usize> {
+        match input.trim().parse::<usize>() {
+            Ok(i) if i < self.filter.rules().len() => Some(i),
+            _ => {
+                self.message = format!("Invalid rule number: {}", input);
+                None
+            }
+        }
+    }
+
+    fn commit_prompt(&mut self, prompt: Prompt, input: String) {
+        match prompt {
+            Prompt::Search => (), // Already done as typed.
+            Prompt::ToggleRule => {
+                if let Some(i) = self.parse_rule_index(&input) {
+                    let enabled = !self.filter.is_rule_enabled(i);
+                    self.filter.set_rule_enabled(i, enabled);
+                    self.rehighlight();
+                    self.message = format!("Rule {} {}",
+                                           self.rule_label(i),
+                                           if enabled { "enabled" } else { "disabled" });
+                }
+            }
+            Prompt::ToggleTag => {
+                let tag = input.trim();
+                let any_enabled = (0..self.filter.rules().len()).any(|i| {
+                    self.filter.is_rule_enabled(i) &&
+                    self.filter.rules()[i].tag().map_or(false, |t| t == tag)
+                });
+                let count = self.filter.set_tag_enabled(tag, !any_enabled);
+                if count == 0 {
+                    self.message = format!("No rules with tag: {}", tag);
+                } else {
+                    self.rehighlight();
+                    self.message = format!("{} rule(s) with tag '{}' {}",
+                                           count,
+                                           tag,
+                                           if any_enabled { "disabled" } else { "enabled" });
+                }
+            }
+            Prompt::ChooseRule => {
+                if let Some(i) = self.parse_rule_index(&input) {
+                    self.chosen_rule = Some(i);
+                    self.message = format!("Chose rule {}; use [ and ] to jump",
+                                           self.rule_label(i));
+                }
+            }
+        }
+    }
+
+    fn handle_prompt_key(&mut self, key: Key) {
+        let (prompt, mut input) = self.prompt.take().unwrap();
+        match key {
+            Key::Enter => {
+                self.commit_prompt(prompt, input);
+                return;
+            }
+            Key::Esc | Key::Interrupt => {
+                if prompt == Prompt::Search {
+                    self.search.clear();
+                    let top = self.saved_top;
+                    self.scroll_to(top);
+                }
+                return;
+            }
+            Key::Backspace => {
+                input.pop();
+            }
+            Key::Char(c) => input.push(c),
+            _ => (),
+        }
+        if prompt == Prompt::Search {
+            // Incremental search.
+            self.search = input.clone();
+            let top = self.saved_top;
+            self.scroll_to(top);
+            self.find_search(top, true);
+        }
+        self.prompt = Some((prompt, input));
+    }
+
+    pub fn handle_key(&mut self, key: Key) {
+        if self.prompt.is_some() {
+            self.handle_prompt_key(key);
+            return;
+        }
+        self.message.clear();
+
+        let page = self.height as isize;
+        match key {
+            Key::Char('q') | Key::Interrupt => self.quit = true,
+            Key::Char('j') | Key::Down | Key::Enter => self.scroll_by(1),
+            Key::Char('k') | Key::Up => self.scroll_by(-1),
+            Key::Char(' ') | Key::PageDown => self.scroll_by(page),
+            Key::Char('b') | Key::PageUp => self.scroll_by(-page),
+            Key::Char('g') | Key::Home => self.scroll_to(0),
+            Key::Char('G') | Key::End => self.tail = true,
+            Key::Char('/') => {
+                self.saved_top = self.top;
+                self.prompt = Some((Prompt::Search, String::new()));
+            }
+            Key::Char('n') => {
+                let from = self.top + 1;
+                self.find_search(from, true);
+            }
+            Key::Char('N') => {
+                let from = self.top;
+                self.find_search(from, false);
+            }
+            Key::Char('p') => {
+                let frozen = !self.frozen;
+                self.set_frozen(frozen);
+            }
+            Key::Char('t') => self.prompt = Some((Prompt::ToggleRule, String::new())),
+            Key::Char('T') => self.prompt = Some((Prompt::ToggleTag, String::new())),
+            Key::Char('r') => self.prompt = Some((Prompt::ChooseRule, String::new())),
+            Key::Char(']') => self.find_rule(true),
+            Key::Char('[') => self.find_rule(false),
+            Key::Char('?') => self.message = HELP.to_string(),
+            _ => (),
+        }
+    }
+
+    fn status_line(&self) -> String {
+        if let Some((prompt, ref input)) = self.prompt {
+            return format!("{}{}", prompt.label(), input);
+        }
+        if self.message.len() > 0 {
+            return self.message.clone();
+        }
+        let mut ret = format!("line {}/{}", self.top + 1, self.lines.len());
+        if self.frozen {
+            ret.push_str(&format!(" [FROZEN +{}]", self.pending.len()));
+        }
+        if let Some(r) = self.chosen_rule {
+            ret.push_str(&format!(" rule {}", self.rule_label(r)));
+        }
+        ret.push_str("  (?: help)");
+        ret
+    }
+
+    /// Build the screen content.
+    pub fn render(&mut self, cols: usize, rows: usize) -> Vec<String> {
+        let term = self.filter.term();
+        self.height = if rows > 1 { rows - 1 } else { 1 };
+        if self.tail {
+            self.top = self.bottom_top();
+        } else if self.top >= self.lines.len() {
+            self.top = self.bottom_top();
+        }
+
+        let mut screen = vec![];
+        'outer: for vl in self.lines.iter().skip(self.top) {
+            for row in &vl.rows {
+                if screen.len() >= self.height {
+                    break 'outer;
+                }
+                screen.push(render_row(term, row, cols, &self.search));
+            }
+        }
+        while screen.len() < self.height {
+            screen.push(String::new());
+        }
+
+        let status: String = self.status_line().chars().take(cols).collect();
+        screen.push(format!("{}{}{}", sgr(term, "7"), status, sgr(term, "0")));
+        screen
+    }
+}
+
+/// Run the viewer until the user quits, taking the input lines from `input`.
+/// `reload` is called between frames, and returns the rules to switch to, if
+/// they've changed.
+pub fn run<B, F>(viewer: &mut Viewer,
+                 backend: &mut B,
+                 input: &Receiver<io::Result<String>>,
+                 mut reload: F)
+                 -> io::Result<()>
+    where B: Backend,
+          F: FnMut() -> Option<Vec<Rule>>
+{
+    let mut eof = false;
+    while !viewer.is_quit() {
+        if let Some(rules) = reload() {
+            viewer.set_rules(rules);
+        }
+        let mut count = 0;
+        while !eof && count < MAX_LINES_PER_FRAME {
+            match input.try_recv() {
+                Ok(Ok(line)) => viewer.add_line(line),
+                Ok(Err(e)) => viewer.message = format!("Unable to read the input: {}", e),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => eof = true,
+            }
+            count += 1;
+        }
+        let (cols, rows) = backend.size();
+        let screen = viewer.render(cols, rows);
+        backend.draw(&screen)?;
+
+        if let Some(key) = backend.poll_key(Duration::from_millis(POLL_INTERVAL_MS))? {
+            viewer.handle_key(key);
+        }
+    }
+    Ok(())
+}
+
+/// Backend for tests, which replays given keys.  Once the keys run out, it
+/// sends 'q'.
+#[derive(Debug)]
+pub struct FakeBackend {
+    pub cols: usize,
+    pub rows: usize,
+    pub keys: VecDeque<Key>,
+    pub screen: Vec<String>,
+}
+
+impl FakeBackend {
+    pub fn new(cols: usize, rows: usize, keys: Vec<Key>) -> FakeBackend {
+        FakeBackend {
+            cols: cols,
+            rows: rows,
+            keys: keys.into_iter().collect(),
+            screen: vec![],
+        }
+    }
+}
+
+impl Backend for FakeBackend {
+    fn size(&self) -> (usize, usize) {
+        (self.cols, self.rows)
+    }
+
+    fn draw(&mut self, rows: &[String]) -> io::Result<()> {
+        self.screen = rows.to_vec();
+        Ok(())
+    }
+
+    fn poll_key(&mut self, _timeout: Duration) -> io::Result<Option<Key>> {
+        Ok(Some(self.keys.pop_front().unwrap_or(Key::Char('q'))))
+    }
+}
+
+/// Backend on the real terminal.  Keys are read from /dev/tty, so the input
+/// lines can come from stdin.
+pub struct TermBackend {
+    // Must be dropped before the tty is closed.
+    _raw: tty::RawMode,
+    tty: File,
+    out: io::Stdout,
+    input: Vec<u8>,
+}
+
+impl TermBackend {
+    pub fn new() -> io::Result<TermBackend> {
+        let tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+        let raw = tty::RawMode::enable(tty.as_raw_fd())?;
+        let mut out = io::stdout();
+
+        // Switch to the alternate screen, and hide the cursor.
+        write!(out, "\x1b[?1049h\x1b[?25l")?;
+        out.flush()?;
+
+        Ok(TermBackend {
+            _raw: raw,
+            tty: tty,
+            out: out,
+            input: vec![],
+        })
+    }
+}
+
+impl Drop for TermBackend {
+    fn drop(&mut self) {
+        let _ = write!(self.out, "\x1b[?25h\x1b[?1049l");
+        let _ = self.out.flush();
+    }
+}
+
+/// Take a key from the input bytes.
+fn parse_key(input: &mut Vec<u8>) -> Option<Key> {
+    if input.len() == 0 {
+        return None;
+    }
+    let (key, len) = match input[0] {
+        0x1b => {
+            if input.len() >= 3 && input[1] == b'[' {
+                match input[2] {
+                    b'A' => (Key::Up, 3),
+                    b'B' => (Key::Down, 3),
+                    b'H' => (Key::Home, 3),
+                    b'F' => (Key::End, 3),
+                    b'5' if input.len() >= 4 && input[3] == b'~' => (Key::PageUp, 4),
+                    b'6' if input.len() >= 4 && input[3] == b'~' => (Key::PageDown, 4),
+                    _ => (Key::Esc, 1),
+                }
+            } else {
+                (Key::Esc, 1)
+            }
+        }
+        0x03 => (Key::Interrupt, 1),
+        b'\r' | b'\n' => (Key::Enter, 1),
+        0x7f | 0x08 => (Key::Backspace, 1),
+        b => {
+            let len = if b < 0x80 {
+                1
+            } else if b < 0xe0 {
+                2
+            } else if b < 0xf0 {
+                3
+            } else {
+                4
+            };
+            if input.len() < len {
+                return None; // Wait for the rest.
+            }
+            match ::std::str::from_utf8(&input[..len]).ok().and_then(|s| s.chars().next()) {
+                Some(c) => (Key::Char(c), len),
+                None => {
+                    input.remove(0);
+                    return None;
+                }
+            }
+        }
+    };
+    input.drain(..len);
+    Some(key)
+}
+
+#[test]
+fn test_parse_key() {
+    let mut input = b"a\x1b[A\x1b[6~\r\x7f".to_vec();
+    input.extend("\u{3042}".as_bytes());
+    assert_eq!(Some(Key::Char('a')), parse_key(&mut input));
+    assert_eq!(Some(Key::Up), parse_key(&mut input));
+    assert_eq!(Some(Key::PageDown), parse_key(&mut input));
+    assert_eq!(Some(Key::Enter), parse_key(&mut input));
+    assert_eq!(Some(Key::Backspace), parse_key(&mut input));
+    assert_eq!(Some(Key::Char('\u{3042}')), parse_key(&mut input));
+    assert_eq!(None, parse_key(&mut input));
+}
+
+impl Backend for TermBackend {
+    fn size(&self) -> (usize, usize) {
+        tty::window_size(self.tty.as_raw_fd()).unwrap_or((80, 24))
+    }
+
+    fn draw(&mut self, rows: &[String]) -> io::Result<()> {
+        let mut buf = String::from("\x1b[H");
+        for (i, row) in rows.iter().enumerate() {
+            buf.push_str(row);
+            buf.push_str("\x1b[K");
+            if i + 1 < rows.len() {
+                buf.push_str("\r\n");
+            }
+        }
+        self.out.write_all(buf.as_bytes())?;
+        self.out.flush()
+    }
+
+    fn poll_key(&mut self, timeout: Duration) -> io::Result<Option<Key>> {
+        if let Some(key) = parse_key(&mut self.input) {
+            return Ok(Some(key));
+        }
+        let mut pfd = libc::pollfd {
+            fd: self.tty.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let millis = timeout.as_secs() * 1000 + (timeout.subsec_nanos() / 1000000) as u64;
+        let ready = unsafe { libc::poll(&mut pfd, 1, millis as libc::c_int) };
+        if ready <= 0 {
+            return Ok(None);
+        }
+        let mut buf = [0u8; 64];
+        let n = self.tty.read(&mut buf)?;
+        self.input.extend_from_slice(&buf[..n]);
+        Ok(parse_key(&mut self.input))
+    }
+}
+
+#[cfg(test)]
+fn new_test_viewer() -> Viewer {
+    let mut error = Rule::new("E/").unwrap();
+    error.set_match_colors(Colors::with_colors(Color::Console(1), Color::None, ATTR_NONE, Term::Dumb))
+        .set_tag("errors".to_string());
+    let warn = Rule::new("W/").unwrap();
+    let mut viewer = Viewer::new(Filter::new(Term::Dumb, vec![error, warn]));
+    for i in 0..10 {
+        viewer.add_line(format!("{}/{} line {}",
+                                if i % 3 == 0 { "E" } else { "W" },
+                                i,
+                                i));
+    }
+    viewer
+}
+
+#[test]
+fn test_viewer_scroll() {
+    let mut viewer = new_test_viewer();
+    let mut backend = FakeBackend::new(40, 5, vec![Key::Char('g'), Key::Down]);
+    let (_tx, rx) = ::std::sync::mpsc::channel();
+
+    // The last frame is drawn before the last 'q'.
+    run(&mut viewer, &mut backend, &rx, || None).unwrap();
+    assert_eq!("W/1 line 1", backend.screen[0]);
+    assert_eq!("W/4 line 4", backend.screen[3]);
+    assert!(backend.screen[4].starts_with("line 2/10"));
+}
+
+#[test]
+fn test_viewer_reload() {
+    let mut viewer = new_test_viewer();
+    let mut backend = FakeBackend::new(40, 12, vec![]);
+    let (_tx, rx) = ::std::sync::mpsc::channel();
+    let mut reloaded = false;
+    run(&mut viewer,
+        &mut backend,
+        &rx,
+        || if reloaded {
+            None
+        } else {
+            reloaded = true;
+            let mut rule = Rule::new("line").unwrap();
+            rule.set_match_colors(Colors::with_colors(Color::Console(2),
+                                                      Color::None,
+                                                      ATTR_NONE,
+                                                      Term::Dumb));
+            Some(vec![rule])
+        })
+        .unwrap();
+    assert_eq!(vec![0], viewer.lines[0].rules);
+    assert_eq!(1, viewer.filter.rules().len());
+}
+
+#[test]
+fn test_viewer_input_error() {
+    let mut viewer = new_test_viewer();
+    let mut backend = FakeBackend::new(40, 5, vec![]);
+    let (tx, rx) = ::std::sync::mpsc::channel();
+    tx.send(Ok("more".to_string())).unwrap();
+    tx.send(Err(io::Error::new(io::ErrorKind::Other, "broken"))).unwrap();
+    drop(tx);
+    run(&mut viewer, &mut backend, &rx, || None).unwrap();
+    assert_eq!(11, viewer.lines.len());
+    assert_eq!("Unable to read the input: broken", backend.screen[4]);
+}
+
+#[test]
+fn test_viewer_search() {
+    let mut viewer = new_test_viewer();
+    viewer.render(40, 5);
+    viewer.handle_key(Key::Char('g'));
+    assert_eq!(0, viewer.top);
+
+    viewer.handle_key(Key::Char('/'));
+    for c in "line 5".chars() {
+        viewer.handle_key(Key::Char(c));
+    }
+    viewer.handle_key(Key::Enter);
+    assert_eq!(5, viewer.top);
+
+    viewer.handle_key(Key::Char('g'));
+    viewer.handle_key(Key::Char('/'));
+    viewer.handle_key(Key::Char('x'));
+    assert_eq!(0, viewer.top);
+    viewer.handle_key(Key::Esc);
+    assert_eq!("", viewer.search);
+}
+
+#[test]
+fn test_viewer_rules() {
+    let mut viewer = new_test_viewer();
+    viewer.render(40, 5);
+    viewer.handle_key(Key::Char('g'));
+
+    // Jump between the matches of rule #0.
+    viewer.handle_key(Key::Char('r'));
+    viewer.handle_key(Key::Char('0'));
+    viewer.handle_key(Key::Enter);
+    viewer.handle_key(Key::Char(']'));
+    assert_eq!(3, viewer.top);
+    viewer.handle_key(Key::Char(']'));
+    assert_eq!(6, viewer.top);
+    viewer.handle_key(Key::Char('['));
+    assert_eq!(3, viewer.top);
+
+    // Disable the rule, and there'll be no more matches.
+    viewer.handle_key(Key::Char('T'));
+    for c in "errors".chars() {
+        viewer.handle_key(Key::Char(c));
+    }
+    viewer.handle_key(Key::Enter);
+    assert!(!viewer.filter.is_rule_enabled(0));
+    assert_eq!(0, viewer.lines[3].rules.len());
+
+    viewer.handle_key(Key::Char('t'));
+    viewer.handle_key(Key::Char('0'));
+    viewer.handle_key(Key::Enter);
+    assert!(viewer.filter.is_rule_enabled(0));
+    assert_eq!(vec![0], viewer.lines[3].rules);
+}
+
+#[test]
+fn test_viewer_tall_last_line() {
+    let mut rule = Rule::new("x").unwrap();
+    rule.set_pre_line(DecorativeLine::new("-", None, Term::Dumb, 10))
+        .set_post_line(DecorativeLine::new("=", None, Term::Dumb, 10));
+    let mut viewer = Viewer::new(Filter::new(Term::Dumb, vec![rule]));
+    viewer.add_line("a".to_string());
+    viewer.add_line("x".to_string());
+    // The last line takes 3 rows, more than the 2 rows for the lines.
+    let screen = viewer.render(10, 3);
+    assert_eq!(1, viewer.top);
+    assert_eq!("----------", screen[0]);
+    assert_eq!("x", screen[1]);
+}
+
+#[test]
+fn test_viewer_keeps_toggles() {
+    let mut viewer = new_test_viewer();
+    viewer.handle_key(Key::Char('t'));
+    viewer.handle_key(Key::Char('1'));
+    viewer.handle_key(Key::Enter);
+    assert!(!viewer.filter.is_rule_enabled(1));
+
+    let rules = viewer.filter.rules().clone();
+    viewer.set_rules(rules);
+    assert!(viewer.filter.is_rule_enabled(0));
+    assert!(!viewer.filter.is_rule_enabled(1));
+}
+
+#[test]
+fn test_viewer_freeze() {
+    let mut viewer = new_test_viewer();
+    viewer.render(40, 5);
+    viewer.handle_key(Key::Char('p'));
+    viewer.add_line("new".to_string());
+    assert_eq!(10, viewer.lines.len());
+    viewer.handle_key(Key::Char('p'));
+    assert_eq!(11, viewer.lines.len());
+    assert_eq!("new", viewer.render(40, 5)[3]);
+}
@@ -0,0 +1,50 @@
+use std::fs;
+use std::time::{Duration, Instant, SystemTime};
+
+const POLL_INTERVAL_MS: u64 = 500;
+
+/// Detects changes to files by polling their modification times.
+#[derive(Debug)]
+pub struct FileWatcher {
+    files: Vec<(String, Option<SystemTime>)>,
+    last_check: Instant,
+}
+
+fn modified_time(file: &str) -> Option<SystemTime> {
+    fs::metadata(file).and_then(|m| m.modified()).ok()
+}
+
+impl FileWatcher {
+    pub fn new(files: Vec<String>) -> FileWatcher {
+        FileWatcher {
+            files: files.into_iter().map(|f| {
+                    let t = modified_time(&f);
+                    (f, t)
+                })
+                .collect(),
+            last_check: Instant::now(),
+        }
+    }
+
+    /// Returns whether any of the files has been modified since the last call.
+    /// The files are actually checked only once in a while, so this is cheap
+    /// enough to call for every input line.
+    pub fn changed(&mut self) -> bool {
+        if self.files.len() == 0 ||
+           self.last_check.elapsed() < Duration::from_millis(POLL_INTERVAL_MS) {
+            return false;
+        }
+        self.last_check = Instant::now();
+
+        let mut changed = false;
+        for &mut (ref file, ref mut time) in &mut self.files {
+            let t = modified_time(file);
+            if t != *time {
+                debug!("{} has been modified", file);
+                *time = t;
+                changed = true;
+            }
+        }
+        changed
+    }
+}